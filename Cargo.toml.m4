@@ -24,22 +24,29 @@ members = [
 default = [ m4_ifdef(``CARGO_DEFAULT_FEATURES'',``CARGO_DEFAULT_FEATURES'') ]
 
 proxy = [ "r-tftpd-proxy" ]
+sandbox = [ "landlock", "seccompiler" ]
 legacy_rust_179 = []
 
 [dependencies]
-tokio = { version = "1", default-features = false, features = ["rt", "time", "net", "macros", "signal"] }
+tokio = { version = "1", default-features = false, features = ["rt", "time", "net", "macros", "signal", "process", "io-util", "sync"] }
 thiserror = "*"
 lazy_static = "*"
 regex = "*"
 url = "*"
+flate2 = "*"
+zstd = "*"
+xz2 = "*"
+async-trait = "*"
 tracing = "*"
 tracing-subscriber = { version = "*", features = ["json", "env-filter"] }
 listenfd = "*"
-nix = { version = "*", default-features = false, features = ["socket", "uio", "net", "socket"] }
+nix = { version = "*", default-features = false, features = ["socket", "uio", "net", "socket", "user", "fs", "dir"] }
 #systemd = { version = "*", default-features = false, features = [] }
 num-format = { version = "*", features = ["with-system-locale"] }
 
 r-tftpd-proxy = { version = "*", path = "mod-proxy", optional = true }
+landlock = { version = "*", optional = true }
+seccompiler = { version = "*", optional = true }
 
 [dependencies.clap]
 version = "*"
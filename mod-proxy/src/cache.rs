@@ -10,12 +10,20 @@ use super::http;
 use http::Time;
 
 use crate::{ Result, Error };
-use crate::util::{pretty_dump_wrap as pretty, AsInit, CopyInit};
+use crate::store::{ IndexWriter, Loaded, Store };
+use crate::util::pretty_dump_wrap as pretty;
 
 const READ_TIMEOUT:    std::time::Duration = std::time::Duration::from_secs(30);
 const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 const KEEPALIVE:       std::time::Duration = std::time::Duration::from_secs(300);
 
+// interrupted downloads are resumed with a delay which starts at
+// RESUME_BACKOFF and doubles every time.  Without progress, they are given
+// up when the total delay would exceed RESUME_MAX_DELAY; this must stay
+// below the timeout of tftp clients (3s by default) which wait meanwhile.
+const RESUME_BACKOFF:   std::time::Duration = std::time::Duration::from_millis(100);
+const RESUME_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
 lazy_static::lazy_static!{
     static ref CACHE: std::sync::RwLock<CacheImpl> = std::sync::RwLock::new(CacheImpl::new());
 }
@@ -42,6 +50,56 @@ impl crate::util::PrettyDump for Stats {
     }
 }
 
+/// Progress of a download; it is published by the download task
+#[derive(Clone, Debug, Default)]
+struct Progress {
+    pos:	u64,
+    // set when the download finished
+    done:	Option<Result<()>>,
+}
+
+/// Waits for the progress of a running download
+#[derive(Debug)]
+pub struct Waiter(tokio::sync::watch::Receiver<Progress>);
+
+impl Waiter {
+    /// Waits until data at `ofs` is available or the download finished
+    pub async fn wait(mut self, ofs: u64) -> Result<()> {
+	self.0.wait_for(|p| p.pos > ofs || p.done.is_some()).await
+	    .map_err(|_| Error::Internal("download aborted"))?;
+
+	Ok(())
+    }
+
+    /// Waits until the download finished and returns its size
+    pub async fn wait_done(mut self) -> Result<u64> {
+	let p = self.0.wait_for(|p| p.done.is_some()).await
+	    .map_err(|_| Error::Internal("download aborted"))?;
+
+	match &p.done {
+	    Some(Ok(()))	=> Ok(p.pos),
+	    Some(Err(e))	=> Err(e.clone()),
+	    None		=> unreachable!(),
+	}
+    }
+}
+
+/// Result of [`EntryData::get_filesize`]
+pub enum SizeResult {
+    Known(u64),
+    /// the server did not announce the size; it is known after the
+    /// download finished
+    Pending(Waiter),
+}
+
+/// Result of [`EntryData::read_some`]
+pub enum ReadResult {
+    /// number of bytes read; 0 at the end of the resource
+    Data(usize),
+    /// the requested data are not downloaded yet
+    Pending(Waiter),
+}
+
 #[derive(Debug)]
 enum State {
     None,
@@ -52,20 +110,12 @@ enum State {
 	response:	reqwest::Response,
     },
 
-    HaveMeta {
-	response:	reqwest::Response,
-	cache_info:	http::CacheInfo,
-	file_size:	Option<u64>,
-	stats:		Stats,
-    },
-
+    // the body is written into 'file' by a download task
     Downloading {
-	response:	reqwest::Response,
 	cache_info:	http::CacheInfo,
 	file_size:	Option<u64>,
-	file:		std::fs::File,
-	file_pos:	u64,
-	stats:		Stats,
+	file:		Arc<std::fs::File>,
+	progress:	tokio::sync::watch::Receiver<Progress>,
     },
 
     Complete {
@@ -91,15 +141,10 @@ impl crate::util::PrettyDump for State {
 		f.write_fmt(format_args!("error {e:?}")),
             State::Init { response } =>
 		f.write_fmt(format_args!("INIT({})", pretty(response))),
-            State::HaveMeta { response, cache_info, file_size, stats } =>
-		f.write_fmt(format_args!("META({}, {}, {}, {})",
-					 pretty(response), pretty(cache_info),
-					 pretty(file_size), pretty(stats))),
-            State::Downloading { response, cache_info, file_size, file, file_pos, stats } =>
-		f.write_fmt(format_args!("DOWNLOADING({}, {}, {}, {}@{}, {})",
-					 pretty(response), pretty(cache_info),
-					 pretty(file_size), pretty(file),
-					 file_pos, pretty(stats))),
+	    State::Downloading { cache_info, file_size, file, progress } =>
+		f.write_fmt(format_args!("DOWNLOADING({}, {}, {}@{})",
+					 pretty(cache_info), pretty(file_size),
+					 pretty(file.as_ref()), progress.borrow().pos)),
             State::Complete { cache_info, file, file_size } =>
 		f.write_fmt(format_args!("COMPLETE({}, {}/{})",
 					 pretty(cache_info), pretty(file), file_size)),
@@ -132,10 +177,6 @@ impl State {
 	matches!(self, Self::Refresh { .. })
     }
 
-    pub fn is_have_meta(&self) -> bool {
-	matches!(self, Self::HaveMeta { .. })
-    }
-
     pub fn is_downloading(&self) -> bool {
 	matches!(self, Self::Downloading { .. })
     }
@@ -149,7 +190,6 @@ impl State {
 	    Self::None |
 	    Self::Init { .. }	=> None,
 
-	    Self::HaveMeta { file_size, .. }	=> *file_size,
 	    Self::Downloading { file_size, .. }	=> *file_size,
 
 	    Self::Complete { file_size, .. }	=> Some(*file_size),
@@ -165,14 +205,13 @@ impl State {
 	    Self::Error(_) |
 	    Self::Init { .. }	=> None,
 
-	    Self::HaveMeta { cache_info, .. } |
 	    Self::Downloading { cache_info, .. } |
 	    Self::Complete { cache_info, .. } |
 	    Self::Refresh { cache_info, .. }	=> Some(cache_info),
 	}
     }
 
-    fn read_file<'a>(file: &std::fs::File, ofs: u64, buf: &'a mut [MaybeUninit<u8>], max: u64) -> Result<&'a [u8]> {
+    fn read_file(file: &std::fs::File, ofs: u64, buf: &mut [MaybeUninit<u8>], max: u64) -> Result<usize> {
 	use nix::libc;
 
 	assert!(max > ofs);
@@ -180,10 +219,6 @@ impl State {
 	let len = (buf.len() as u64).min(max - ofs) as usize;
 	let buf_ptr = buf.as_mut_ptr() as *mut libc::c_void;
 
-	// TODO: this would be nice, but does not work because we can not get
-	// a mutable reference to 'file'
-	//file.flush()?;
-
 	let rc = unsafe { libc::pread(file.as_raw_fd(), buf_ptr, len, ofs as i64) };
 
 	if rc < 0 {
@@ -192,26 +227,37 @@ impl State {
 
         assert_eq!(rc as usize, len);
 
-	Ok(unsafe { buf[..rc as usize].assume_init() })
+	Ok(len)
     }
 
-    pub fn read<'a>(&self, ofs: u64, buf: &'a mut [MaybeUninit<u8>]) -> Result<Option<&'a [u8]>> {
+    pub fn read(&self, ofs: u64, buf: &mut [MaybeUninit<u8>]) -> Result<ReadResult> {
 	match &self {
-	    State::Downloading { file, file_pos, .. } if ofs < *file_pos	=> {
-		Self::read_file(file, ofs, buf, *file_pos)
+	    State::Downloading { file, progress, .. }	=> {
+		let p = progress.borrow();
+
+		match &p.done {
+		    _ if ofs < p.pos			=> Self::read_file(file, ofs, buf, p.pos),
+		    Some(Ok(())) if ofs == p.pos	=> Ok(0),
+		    Some(Ok(()))			=> Err(Error::Internal("file out-of-bound read")),
+		    Some(Err(e))			=> Err(e.clone()),
+		    None				=>
+			return Ok(ReadResult::Pending(Waiter(progress.clone()))),
+		}
 	    },
 
 	    State::Complete { file, file_size, .. } if ofs < *file_size		=> {
 		Self::read_file(file, ofs, buf, *file_size)
 	    }
 
-	    State::Complete { file_size, .. } if ofs == *file_size	=> Ok(&[] as &[u8]),
+	    State::Complete { file_size, .. } if ofs == *file_size	=> Ok(0),
 
 	    State::Complete { file_size, .. } if ofs >= *file_size	=>
 		Err(Error::Internal("file out-of-bound read")),
 
-	    _	=> return Ok(None)
-	}.map(Some)
+	    State::Error(_)	=> Err(Error::Internal("download failed")),
+
+	    _	=> Err(Error::Internal("read in unexpected state")),
+	}.map(ReadResult::Data)
     }
 
     pub fn is_outdated(&self, reftm: Instant, max_lt: Duration) -> bool {
@@ -227,6 +273,23 @@ pub struct EntryData {
     pub key:		url::Url,
     state:		State,
     reftm:		Time,
+    // file of the body in the persistent cache
+    body:		Option<std::path::PathBuf>,
+    // object exceeds the size limits of the cache
+    uncached:		bool,
+    // used by the download task to finish the download
+    this:		std::sync::Weak<RwLock<EntryData>>,
+    // serializes persisting of the body
+    persisting:		Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for EntryData {
+    fn drop(&mut self) {
+	// remove incomplete downloads
+	if let Some(body) = self.body.take().filter(|b| Store::is_tmp(b)) {
+	    let _ = std::fs::remove_file(body);
+	}
+    }
 }
 
 impl std::fmt::Display for EntryData {
@@ -236,15 +299,294 @@ impl std::fmt::Display for EntryData {
     }
 }
 
+/// Information for resuming an interrupted download with a range request
+#[derive(Debug)]
+struct Resume {
+    url:	url::Url,
+    if_range:	reqwest::header::HeaderValue,
+    file_size:	u64,
+}
+
+impl Resume {
+    /// Returns the information when the download of `response` can be
+    /// resumed.  This requires a validator for `If-Range` and a body which
+    /// is stored as transferred; range requests for decompressed bodies
+    /// would return parts of the compressed representation.
+    pub fn new(response: &reqwest::Response, cache_info: &http::CacheInfo) -> Option<Self> {
+	use reqwest::header as H;
+
+	if response.headers().contains_key(H::CONTENT_ENCODING) {
+	    return None;
+	}
+
+	Some(Self {
+	    url:	response.url().clone(),
+	    if_range:	cache_info.if_range()?,
+	    file_size:	response.content_length()?,
+	})
+    }
+
+    /// Requests the body starting at `pos` and validates the response
+    async fn request(&self, pos: u64) -> Result<reqwest::Response> {
+	use reqwest::header as H;
+	use reqwest::StatusCode as S;
+
+	let response = Cache::get_client()
+	    .get(self.url.clone())
+	    .header(H::RANGE, format!("bytes={pos}-"))
+	    .header(H::IF_RANGE, &self.if_range)
+	    .header(H::ACCEPT_ENCODING, "identity")
+	    .send().await?;
+
+	match response.status() {
+	    S::PARTIAL_CONTENT	=> {},
+	    // 'If-Range' did not match; the resource was modified
+	    S::OK		=> return Err(Error::BadRange("resource was modified")),
+	    S::RANGE_NOT_SATISFIABLE	=> return Err(Error::BadRange("range not satisfiable")),
+	    s			=> return Err(Error::HttpStatus(s)),
+	}
+
+	let range = http::ContentRange::from_headers(response.headers())
+	    .map_err(|_| Error::BadRange("bad Content-Range"))?
+	    .ok_or(Error::BadRange("missing Content-Range"))?;
+
+	if range.start != pos || range.end + 1 != self.file_size ||
+	    range.total.is_some_and(|t| t != self.file_size) {
+	    warn!("unexpected range {:?} for {}", range, self.url);
+	    return Err(Error::BadRange("range mismatch"));
+	}
+
+	if response.headers().contains_key(H::CONTENT_ENCODING) {
+	    return Err(Error::BadRange("encoded range"));
+	}
+
+	Ok(response)
+    }
+
+    /// Retries the download at `pos` after `err` with an exponential
+    /// backoff; `delayed` is the total delay since the last progress.
+    async fn resume(&self, pos: u64, delayed: &mut Duration, mut err: Error) -> Result<reqwest::Response> {
+	loop {
+	    // 1, 2, 4, ... times RESUME_BACKOFF
+	    let delay = *delayed + RESUME_BACKOFF;
+
+	    if *delayed + delay > RESUME_MAX_DELAY {
+		return Err(err);
+	    }
+
+	    *delayed += delay;
+
+	    warn!("download of {} failed at {}/{}: {:?}; resuming in {:?}",
+		  self.url, pos, self.file_size, err, delay);
+
+	    tokio::time::sleep(delay).await;
+
+	    match self.request(pos).await {
+		Ok(response)		=> return Ok(response),
+		// not recoverable
+		Err(e @ Error::BadRange(_))	=> return Err(e),
+		Err(e)			=> err = e,
+	    }
+	}
+    }
+}
+
+/// Writes the body of `response` into `file` and publishes the progress.
+/// Interrupted downloads are resumed when `resume` is given.  Stops when
+/// the entry is gone.
+async fn download(mut response: reqwest::Response, file: Arc<std::fs::File>,
+		  resume: Option<Resume>,
+		  tx: tokio::sync::watch::Sender<Progress>,
+		  entry: std::sync::Weak<RwLock<EntryData>>)
+{
+    use std::os::unix::fs::FileExt;
+
+    let mut stats = Stats::default();
+    let mut pos = 0;
+    let mut delayed = Duration::ZERO;
+
+    let res = loop {
+	if tx.is_closed() {
+	    debug!("download aborted");
+	    return;
+	}
+
+	let err = match stats.chunk(&mut response).await {
+	    Ok(Some(data))	=> {
+		if let Err(e) = file.write_all_at(&data, pos) {
+		    break Err(e.into());
+		}
+
+		pos += data.len() as u64;
+		delayed = Duration::ZERO;
+		tx.send_modify(|p| p.pos = pos);
+		continue;
+	    },
+	    Ok(None) if resume.as_ref().map_or(true, |r| r.file_size == pos)	=> break Ok(()),
+	    Ok(None)		=> Error::Internal("incomplete body"),
+	    Err(e)		=> e.into(),
+	};
+
+	let Some(r) = &resume else {
+	    break Err(err);
+	};
+
+	match r.resume(pos, &mut delayed, err).await {
+	    Ok(resp)	=> response = resp,
+	    Err(e)	=> break Err(e),
+	}
+    };
+
+    if let Err(e) = &res {
+	warn!("download of {} failed: {:?}", response.url(), e);
+    }
+
+    // readers are notified before the state is changed; they do not need
+    // the write lock
+    tx.send_modify(|p| p.done = Some(res));
+
+    drop(file);
+
+    if let Some(entry) = entry.upgrade() {
+	entry.write().await.finish_download(stats);
+	persist(&entry).await;
+    }
+}
+
+/// Body of an entry which is registered in the persistent cache
+struct PersistJob {
+    key:	url::Url,
+    body:	std::path::PathBuf,
+    file:	std::fs::File,
+    cache_info:	http::CacheInfo,
+    file_size:	u64,
+}
+
+impl PersistJob {
+    /// Syncs the body and registers it; returns its new path
+    fn run(self) -> Result<Option<std::path::PathBuf>> {
+	self.file.sync_all()?;
+
+	Cache::commit(&self.key, &self.body, &self.cache_info, self.file_size)
+    }
+}
+
+/// Registers the complete body of `entry` in the persistent cache.  The
+/// body is synced without holding the entry lock so that transfers can
+/// read it meanwhile.
+async fn persist(entry: &Entry) {
+    // the cache waits for running jobs before it is closed
+    let _closing = Cache::persisting().read_owned().await;
+
+    let serial = entry.read().await.persisting.clone();
+    let _serial = serial.lock().await;
+
+    // the state is read after getting the lock so that the latest
+    // cache information is stored when the entry was refreshed meanwhile
+    let job = match entry.read().await.persist_job() {
+	Ok(Some(job))	=> job,
+	Ok(None)	=> return,
+	Err(e)		=> {
+	    warn!("failed to persist {}: {:?}", entry.read().await.key, e);
+	    return;
+	}
+    };
+
+    let key = job.key.clone();
+    let body = job.body.clone();
+    let file_size = job.file_size;
+
+    let res = tokio::task::spawn_blocking(move || job.run()).await
+	.unwrap_or_else(|_| Err(Error::Internal("persist task failed")));
+
+    match res {
+	Ok(Some(stored))	=> {
+	    let mut entry = entry.write().await;
+
+	    // the body has been moved; when the entry was invalidated in
+	    // the meantime, the next download replaces it in the store
+	    if entry.body.as_ref() == Some(&body) &&
+		entry.state.get_file_size() == Some(file_size) {
+		entry.body = Some(stored);
+	    }
+	},
+	Ok(None)		=> {},
+	Err(e)			=> warn!("failed to persist {}: {:?}", key, e),
+    }
+}
+
 impl EntryData {
-    pub fn new(url: &url::Url) -> Self {
+    fn new(url: &url::Url, this: std::sync::Weak<RwLock<EntryData>>) -> Self {
 	Self {
 	    key:		url.clone(),
 	    state:		State::None,
 	    reftm:		Time::now(),
+	    body:		None,
+	    uncached:		false,
+	    this:		this,
+	    persisting:		Default::default(),
+	}
+    }
+
+    fn new_loaded(l: Loaded, this: std::sync::Weak<RwLock<EntryData>>) -> Self {
+	Self {
+	    key:		l.key,
+	    state:		State::Complete {
+		cache_info:	l.cache_info,
+		file:		l.file,
+		file_size:	l.file_size,
+	    },
+	    reftm:		Time::now(),
+	    body:		Some(l.body),
+	    uncached:		false,
+	    this:		this,
+	    persisting:		Default::default(),
 	}
     }
 
+    fn new_file(&mut self) -> Result<std::fs::File> {
+	let (file, body) = Cache::new_file()?;
+
+	if let Some(old) = std::mem::replace(&mut self.body, body).filter(|b| Store::is_tmp(b)) {
+	    let _ = std::fs::remove_file(old);
+	}
+
+	Ok(file)
+    }
+
+    /// Returns the size when it is known without downloading the body
+    pub fn get_known_size(&self) -> Option<u64> {
+	self.state.get_file_size()
+    }
+
+    /// Marks the entry as not being part of the cache
+    pub fn set_uncached(&mut self) {
+	self.uncached = true;
+    }
+
+    /// Returns the body which must be registered in the persistent cache
+    fn persist_job(&self) -> Result<Option<PersistJob>> {
+	if self.uncached {
+	    return Ok(None);
+	}
+
+	let State::Complete { cache_info, file, file_size } = &self.state else {
+	    return Ok(None);
+	};
+
+	let Some(body) = &self.body else {
+	    return Ok(None);
+	};
+
+	Ok(Some(PersistJob {
+	    key:	self.key.clone(),
+	    body:	body.clone(),
+	    file:	file.try_clone()?,
+	    cache_info:	cache_info.clone(),
+	    file_size:	*file_size,
+	}))
+    }
+
     pub fn is_complete(&self) -> bool {
 	self.state.is_complete()
     }
@@ -254,7 +596,7 @@ impl EntryData {
     }
 
     pub fn is_running(&self) -> bool {
-	self.state.is_have_meta() || self.state.is_downloading()
+	self.state.is_downloading()
     }
 
     pub fn update_localtm(&mut self) {
@@ -286,6 +628,8 @@ impl EntryData {
 	self.state.get_cache_info()
     }
 
+    /// Processes the response given by `set_response()`; new bodies are
+    /// downloaded in the background.
     pub async fn fill_meta(&mut self) -> Result<()> {
 	if !self.state.is_init() && !self.state.is_none() && !self.state.is_refresh() {
 	    return Ok(());
@@ -296,12 +640,19 @@ impl EntryData {
 
 	    State::Init{ response }	=> {
 		let hdrs = response.headers();
+		let cache_info = http::CacheInfo::new(self.reftm, hdrs)?;
+		let file_size = response.content_length();
+		let file = Arc::new(self.new_file()?);
+		let resume = Resume::new(&response, &cache_info);
+		let (tx, rx) = tokio::sync::watch::channel(Progress::default());
+
+		tokio::task::spawn(download(response, file.clone(), resume, tx, self.this.clone()));
 
-		State::HaveMeta {
-		    cache_info:	http::CacheInfo::new(self.reftm, hdrs)?,
-		    file_size:	response.content_length(),
-		    response:	response,
-		    stats:	Stats::default(),
+		State::Downloading {
+		    cache_info:	cache_info,
+		    file_size:	file_size,
+		    file:	file,
+		    progress:	rx,
 		}
 	    },
 
@@ -318,66 +669,68 @@ impl EntryData {
 	    _				=> unreachable!(),
 	};
 
-	Ok(())
-    }
-
-    fn signal_complete(&self, stats: Stats) {
-	if let State::Complete { file_size, .. } = self.state {
-	    info!("downloaded {} with {} bytes in {}ms", self.key, file_size, stats.tm.as_millis());
+	// updates the cache information of revalidated entries; this must
+	// not wait for the entry lock which is held by the caller
+	if let (State::Complete { .. }, Some(entry)) = (&self.state, self.this.upgrade()) {
+	    tokio::task::spawn(async move { persist(&entry).await });
 	}
-    }
 
-    #[instrument(level = "trace")]
-    pub async fn get_filesize(&mut self) -> Result<u64> {
-	use std::io::Write;
+	Ok(())
+    }
 
-	if let Some(sz) = self.state.get_file_size() {
-	    return Ok(sz);
-	}
+    /// Called by the download task after it finished; the body must be
+    /// persisted after releasing the lock
+    fn finish_download(&mut self, stats: Stats) {
+	let State::Downloading { progress, .. } = &self.state else {
+	    // entry was invalidated in the meantime
+	    return;
+	};
 
-	match self.state.take("get_filesize") {
-	    State::HaveMeta { mut response, file_size: None, mut stats, cache_info }	=> {
-		let mut file = Cache::new_file()?;
-		let mut pos = 0;
+	let res = progress.borrow().done.clone();
 
+	self.state = match (self.state.take("finish_download"), res) {
+	    (State::Downloading { cache_info, file, progress, .. }, Some(Ok(())))	=> {
+		let file_size = progress.borrow().pos;
 
-		while let Some(chunk) = stats.chunk(&mut response).await? {
-		    pos += chunk.len() as u64;
-		    file.write_all(&chunk)?;
+		match Arc::try_unwrap(file).or_else(|f| f.try_clone()) {
+		    Ok(file)	=> State::Complete {
+			cache_info:	cache_info,
+			file:		file,
+			file_size:	file_size,
+		    },
+		    Err(e)	=> {
+			warn!("failed to finish download of {}: {:?}", self.key, e);
+			State::Error("finish_download")
+		    }
 		}
-
-		self.state = State::Complete {
-		    file:	file,
-		    file_size:	pos,
-		    cache_info:	cache_info,
-		};
-
-		self.signal_complete(stats);
-
-		Ok(pos)
 	    },
 
-	    State::Downloading { mut response, mut file, file_pos, file_size: None, mut stats, cache_info } => {
-		let mut pos = file_pos;
-
-		while let Some(chunk) = stats.chunk(&mut response).await? {
-		    pos += chunk.len() as u64;
-		    file.write_all(&chunk)?;
-		}
+	    _	=> State::Error("download failed"),
+	};
 
-		self.state = State::Complete {
-		    file:	file,
-		    file_size:	pos,
-		    cache_info:	cache_info,
-		};
+	match self.state {
+	    State::Complete { file_size, .. }	=> {
+		info!("downloaded {} with {} bytes in {}ms", self.key, file_size, stats.tm.as_millis());
+	    },
+	    _ if self.uncached			=> {},
+	    _					=> Cache::remove(&self.key),
+	}
+    }
 
-		self.signal_complete(stats);
+    /// Returns the size of the resource.  When the server did not
+    /// announce it, the returned waiter must be used after releasing the
+    /// lock so that other transfers can read the body meanwhile.
+    #[instrument(level = "trace")]
+    pub fn get_filesize(&self) -> SizeResult {
+	if let Some(sz) = self.state.get_file_size() {
+	    return SizeResult::Known(sz);
+	}
 
-		Ok(pos)
-	    }
+	let State::Downloading { progress, .. } = &self.state else {
+	    panic!("unexpected state: {:?}", self.state);
+	};
 
-	    s		=> panic!("unexpected state: {s:?}"),
-	}
+	SizeResult::Pending(Waiter(progress.clone()))
     }
 
     pub fn fill_request(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
@@ -418,114 +771,56 @@ impl EntryData {
 	}
     }
 
-    pub async fn read_some<'a>(&mut self, ofs: u64, buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8]>
+    /// Reads data at `ofs` from the part of the body which is already
+    /// downloaded; only a read lock is required so that many transfers can
+    /// read concurrently.
+    pub fn read_some(&self, ofs: u64, buf: &mut [MaybeUninit<u8>]) -> Result<ReadResult>
     {
-	use std::io::Write;
-
 	trace!("state={:?}, ofs={}, #buf={}", self.state, ofs, buf.len());
 
-	async fn fetch<'a>(response: &mut reqwest::Response, file: &mut std::fs::File,
-		       buf: &'a mut [MaybeUninit<u8>], stats: &mut Stats) -> Result<(&'a[u8], usize)> {
-	    match stats.chunk(response).await? {
-		Some(data)	=> {
-		    let len = buf.len().min(data.len());
-                    let buf = buf[0..len].write_copy_of_slice_x(&data.as_ref()[0..len]);
-
-		    file.write_all(&data)?;
-
-		    // TODO: it would be better to do this in State::read_file()
-		    file.flush()?;
-
-		    Ok((buf, data.len()))
-		},
-
-		None		=> Ok((&[], 0))
-	    }
-	}
-
-	if self.state.is_init() {
-	    self.fill_meta().await?;
-	}
-
-	if let Some(data) = self.state.read(ofs, buf)? {
-            // TODO: this workarounds a bogus E0499 error; revisit after polonius
-            let data = {
-                let len = data.len();
-                unsafe { buf[..len].assume_init() }
-            };
-
-	    return Ok(data);
-	}
-
-	match self.state.take("read_some") {
-	    State::HaveMeta { mut response, cache_info, file_size, mut stats }	=> {
-		let mut file = Cache::new_file()?;
-
-		let res = fetch(&mut response, &mut file, buf, &mut stats).await?;
-
-		self.state = match res {
-		    (_, 0)	=> State::Complete {
-			cache_info:	cache_info,
-			file:		file,
-			file_size:	0,
-		    },
-
-		    (_, sz)	=> State::Downloading {
-			response:	response,
-			cache_info:	cache_info,
-			file_size:	file_size,
-			file:		file,
-			file_pos:	sz as u64,
-			stats:		stats,
-		    }
-		};
-
-		self.signal_complete(stats);
-
-		Ok(res.0)
-	    },
-
-	    // catched by 'self.state.read()' above
-	    State::Downloading { file_pos, .. } if ofs < file_pos	=> unreachable!(),
-
-	    State::Downloading { mut response, cache_info, file_size, mut file, file_pos, mut stats } => {
-		let res = fetch(&mut response, &mut file, buf, &mut stats).await?;
-
-		self.state = match res {
-		    (_, 0)	=> State::Complete {
-			cache_info:	cache_info,
-			file:		file,
-			file_size:	file_pos,
-		    },
-
-		    (_, sz)	=> State::Downloading {
-			response:	response,
-			cache_info:	cache_info,
-			file_size:	file_size,
-			file:		file,
-			file_pos:	file_pos + (sz as u64),
-			stats:		stats,
-		    }
-		};
+	self.state.read(ofs, buf)
+    }
+}
 
-		self.signal_complete(stats);
+pub type Entry = Arc<RwLock<EntryData>>;
 
-		Ok(res.0)
-	    }
+/// A cache entry together with the information for the size limits
+struct Slot {
+    entry:	Entry,
+    size:	u64,
+    last_use:	Instant,
+}
 
-	    s		=> panic!("unexpected state: {s:?}"),
+impl Slot {
+    fn new(entry: Entry, size: u64) -> Self {
+	Self {
+	    entry:	entry,
+	    size:	size,
+	    last_use:	Instant::now(),
 	}
+    }
 
+    /// Returns whether a transfer holds the entry
+    fn is_in_use(&self) -> bool {
+	Arc::strong_count(&self.entry) > 1
     }
 }
 
-pub type Entry = Arc<RwLock<EntryData>>;
-
 struct CacheImpl {
     tmpdir:	std::path::PathBuf,
-    entries:	HashMap<url::Url, Entry>,
+    // configuration of the first user; later ones must match it
+    config:	Option<(GcProperties, bool)>,
+    entries:	HashMap<url::Url, Slot>,
     client:	Arc<reqwest::Client>,
     refcnt:	u32,
+    store:	Option<Store>,
+    // held by running persist jobs
+    persisting:	Arc<tokio::sync::RwLock<()>>,
+
+    // sum of the sizes of all entries
+    size:		u64,
+    max_size:		Option<u64>,
+    max_object_size:	Option<u64>,
 
     abort_ch:	Option<tokio::sync::watch::Sender<()>>,
     gc:		Option<tokio::task::JoinHandle<()>>,
@@ -536,6 +831,12 @@ pub enum LookupResult {
     Missing,
 }
 
+fn write_index(w: IndexWriter) {
+    if let Err(e) = w.write() {
+	warn!("failed to write cache index: {:?}", e);
+    }
+}
+
 impl CacheImpl {
     fn new() -> Self {
         let client = reqwest::Client::builder()
@@ -547,20 +848,65 @@ impl CacheImpl {
 
 	Self {
 	    tmpdir:	std::env::temp_dir(),
+	    config:	None,
 	    entries:	HashMap::new(),
 	    client:	Arc::new(client),
 	    abort_ch:	None,
 	    refcnt:	0,
+	    store:	None,
+	    persisting:	Default::default(),
+	    size:		0,
+	    max_size:		None,
+	    max_object_size:	None,
 	    gc:		None,
 	}
     }
 
+    /// Opens the persistent cache in `dir` and adds its entries
+    fn load(&mut self, dir: &std::path::Path) -> Result<()> {
+	let (store, loaded) = Store::open(dir)?;
+
+	self.store = Some(store);
+
+	for l in loaded {
+	    let key = l.key.clone();
+	    let size = l.file_size;
+
+	    self.insert(&key, Entry::new_cyclic(|this| RwLock::new(EntryData::new_loaded(l, this.clone()))), size);
+	}
+
+	self.gc_size();
+
+	Ok(())
+    }
+
+    /// Writes the changed index of the persistent cache; this syncs to
+    /// disk and happens on the blocking thread pool when called from the
+    /// runtime
+    fn flush_store(&mut self) {
+	let Some(w) = self.store.as_mut().and_then(Store::take_changes) else {
+	    return;
+	};
+
+	match tokio::runtime::Handle::try_current() {
+	    Ok(rt)	=> drop(rt.spawn_blocking(move || write_index(w))),
+	    Err(_)	=> write_index(w),
+	}
+    }
+
     pub fn is_empty(&self) -> bool {
 	self.entries.is_empty()
     }
 
     pub fn clear(&mut self) {
 	self.entries.clear();
+	self.size = 0;
+
+	if let Some(store) = &mut self.store {
+	    store.clear();
+	}
+
+	self.flush_store();
     }
 
     pub fn get_client(&self) -> Arc<reqwest::Client> {
@@ -568,22 +914,60 @@ impl CacheImpl {
     }
 
     pub fn lookup_or_create(&mut self, key: &url::Url) -> Entry {
-	match self.entries.get(key) {
-	    Some(v)	=> v.clone(),
-	    None	=> self.create(key),
+	match self.entries.get_mut(key) {
+	    Some(v)	=> {
+		v.last_use = Instant::now();
+		v.entry.clone()
+	    },
+	    None	=> {
+		// register it at once so that concurrent requests share the
+		// download; the size is set by 'replace()'
+		let entry = self.create(key);
+
+		self.insert(key, entry.clone(), 0);
+		entry
+	    },
 	}
     }
 
     pub fn create(&mut self, key: &url::Url) -> Entry {
-	Entry::new(RwLock::new(EntryData::new(key)))
+	Entry::new_cyclic(|this| RwLock::new(EntryData::new(key, this.clone())))
+    }
+
+    fn insert(&mut self, key: &url::Url, entry: Entry, size: u64) {
+	self.size += size;
+
+	if let Some(old) = self.entries.insert(key.clone(), Slot::new(entry, size)) {
+	    self.size -= old.size;
+	}
+    }
+
+    pub fn replace(&mut self, key: &url::Url, entry: &Entry, size: u64) {
+	self.insert(key, entry.clone(), size);
+	self.gc_size();
     }
 
-    pub fn replace(&mut self, key: &url::Url, entry: &Entry) {
-	self.entries.insert(key.clone(), entry.clone());
+    /// Returns whether an object with `size` bytes can be kept in the cache
+    pub fn is_cacheable(&self, size: u64) -> bool {
+	self.max_object_size.map_or(true, |max| size <= max) &&
+	    self.max_size.map_or(true, |max| size <= max)
     }
 
     pub fn remove(&mut self, key: &url::Url) {
-	self.entries.remove(key);
+	self.remove_entry(key);
+	self.flush_store();
+    }
+
+    /// Removes `key` from memory and from the persistent cache; the index
+    /// must be written by `flush_store()`
+    fn remove_entry(&mut self, key: &url::Url) {
+	if let Some(old) = self.entries.remove(key) {
+	    self.size -= old.size;
+	}
+
+	if let Some(store) = &mut self.store {
+	    store.remove(key);
+	}
     }
 
     /// Removes the `num` oldest cache entries
@@ -594,8 +978,8 @@ impl CacheImpl {
 
 	let mut tmp = Vec::with_capacity(self.entries.len());
 
-	for (key, e) in &self.entries {
-	    let entry = match e.try_read() {
+	for (key, slot) in &self.entries {
+	    let entry = match slot.entry.try_read() {
 		Ok(e)	=> e,
 		_	=> continue,
 	    };
@@ -603,7 +987,7 @@ impl CacheImpl {
 	    tmp.push((key.clone(), entry.get_cache_info().map(|c| c.local_time)));
 	}
 
-	tmp.sort_by(|(_, tm_a), (_, tm_b)| tm_a.cmp(tm_b));
+	tmp.sort_by_key(|(_, tm)| *tm);
 
 	let mut rm_cnt = 0;
 
@@ -613,13 +997,14 @@ impl CacheImpl {
 	    }
 
 	    debug!("gc: removing old {}", key);
-	    self.entries.remove(&key);
+	    self.remove_entry(&key);
 	    num -= 1;
 	    rm_cnt += 1;
 	}
 
 	if rm_cnt > 0 {
 	    info!("gc: removed {} old entries", rm_cnt);
+	    self.flush_store();
 	}
     }
 
@@ -631,8 +1016,8 @@ impl CacheImpl {
 	let mut outdated = Vec::new();
 	let mut cnt = 0;
 
-	for (key, e) in &self.entries {
-	    match e.try_read().map(|v| v.is_outdated(now, max_lt)) {
+	for (key, slot) in &self.entries {
+	    match slot.entry.try_read().map(|v| v.is_outdated(now, max_lt)) {
 		Ok(true)	=> outdated.push(key.clone()),
 		_		=> cnt += 1,
 	    }
@@ -642,22 +1027,60 @@ impl CacheImpl {
 
 	for e in outdated {
 	    debug!("gc: removing outdated {}", e);
-	    self.entries.remove(&e);
+	    self.remove_entry(&e);
 	}
 
 	if rm_cnt > 0 {
 	    info!("gc: removed {} obsolete entries", rm_cnt);
+	    self.flush_store();
 	}
 
 	cnt
     }
+
+    /// Removes least recently used entries until the total size is below
+    /// `max_size`; entries which are in use are kept.
+    pub fn gc_size(&mut self) {
+	let Some(max_size) = self.max_size else {
+	    return;
+	};
+
+	let mut rm_cnt = 0;
+
+	while self.size > max_size {
+	    let lru = self.entries.iter()
+		.filter(|(_, s)| !s.is_in_use())
+		.min_by_key(|(_, s)| s.last_use)
+		.map(|(k, _)| k.clone());
+
+	    let Some(key) = lru else {
+		warn!("gc: cache size {} exceeds limit {} but all entries are in use",
+		      self.size, max_size);
+		break;
+	    };
+
+	    debug!("gc: removing least recently used {}", key);
+	    self.remove_entry(&key);
+	    rm_cnt += 1;
+	}
+
+	if rm_cnt > 0 {
+	    info!("gc: removed {} entries to reduce cache size", rm_cnt);
+	    self.flush_store();
+	}
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct GcProperties {
     pub max_elements:	usize,
     pub max_lifetime:	Duration,
     pub sleep:		Duration,
+    /// total size of all entries; least recently used ones which are not
+    /// in use are removed when it is exceeded
+    pub max_size:	Option<u64>,
+    /// larger objects are not cached
+    pub max_object_size:	Option<u64>,
 }
 
 async fn gc_runner(props: GcProperties, mut abort_ch: tokio::sync::watch::Receiver<()>) {
@@ -674,9 +1097,11 @@ async fn gc_runner(props: GcProperties, mut abort_ch: tokio::sync::watch::Receiv
 		    let cache_cnt = cache.gc_outdated(props.max_lifetime);
 
 		    if cache_cnt > props.max_elements {
-			cache.gc_oldest(props.max_elements - cache_cnt)
+			cache.gc_oldest(cache_cnt - props.max_elements)
 		    }
 
+		    cache.gc_size();
+
 		    props.sleep
 		}
 		Ok(_)				=> props.sleep,
@@ -698,40 +1123,79 @@ async fn gc_runner(props: GcProperties, mut abort_ch: tokio::sync::watch::Receiv
 pub struct Cache();
 
 impl Cache {
+    /// Initializes the cache; bodies are downloaded into `tmpdir`.
+    ///
+    /// The cache is shared by all users within the process; they must use
+    /// the same directory and properties.  Else, [`Error::CacheConflict`]
+    /// is returned.
     #[instrument(level = "trace")]
-    pub fn instanciate(tmpdir: &std::path::Path, props: GcProperties) {
+    pub fn instanciate(tmpdir: &std::path::Path, props: GcProperties, persistent: bool) -> Result<()> {
 	let mut cache = CACHE.write().unwrap();
 
 	if cache.refcnt == 0 {
 	    let (tx, rx) = tokio::sync::watch::channel(());
 
 	    cache.tmpdir = tmpdir.into();
+	    cache.config = Some((props.clone(), persistent));
 	    cache.abort_ch = Some(tx);
-
+	    cache.max_size = props.max_size;
+	    cache.max_object_size = props.max_object_size;
 	    cache.gc = Some(tokio::task::spawn(gc_runner(props, rx)));
+	} else if cache.tmpdir != tmpdir || cache.config != Some((props, persistent)) {
+	    return Err(Error::CacheConflict);
 	}
 
 	cache.refcnt += 1;
+
+	Ok(())
     }
 
+    /// Keeps bodies and their metadata in `dir` and loads the entries
+    /// stored by the previous instance.  With a chroot, this must be
+    /// called after entering it because `dir` is relative to it.
     #[instrument(level = "trace")]
-    // https://github.com/rust-lang/rust-clippy/issues/6446
-    #[allow(clippy::await_holding_lock)]
-    pub async fn close() {
+    pub fn open_store(dir: &std::path::Path) {
 	let mut cache = CACHE.write().unwrap();
 
-	assert!(cache.refcnt > 0);
+	if cache.store.is_some() {
+	    return;
+	}
 
-	cache.refcnt -= 1;
+	if let Err(e) = cache.load(dir) {
+	    // continue with a volatile cache
+	    error!("failed to load persistent cache from {:?}: {:?}", dir, e);
+	}
+    }
 
-	if cache.refcnt == 0 {
-	    cache.entries.clear();
+    #[instrument(level = "trace")]
+    pub async fn close() {
+	// register bodies which are still synced before closing the store
+	let persisting = Self::persisting();
+
+	drop(persisting.write().await);
+
+	// the lock must not be held across the 'await' below; else the
+	// future is not 'Send'
+	let gc = {
+	    let mut cache = CACHE.write().unwrap();
 
-	    let abort_ch = cache.abort_ch.take().unwrap();
-	    let gc = cache.gc.take().unwrap();
+	    assert!(cache.refcnt > 0);
 
-	    drop(cache);
+	    cache.refcnt -= 1;
 
+	    match cache.refcnt {
+		0	=> {
+		    // persistent entries are kept on disk
+		    cache.entries.clear();
+		    cache.store = None;
+
+		    Some((cache.abort_ch.take().unwrap(), cache.gc.take().unwrap()))
+		},
+		_	=> None,
+	    }
+	};
+
+	if let Some((abort_ch, gc)) = gc {
 	    abort_ch.send(()).unwrap();
 	    gc.await.unwrap();
 	}
@@ -751,11 +1215,19 @@ impl Cache {
 	cache.create(key)
     }
 
+    /// Adds `entry` with `size` bytes to the cache; this might remove
+    /// other entries
     #[instrument(level = "trace")]
-    pub fn replace(key: &url::Url, entry: &Entry) {
+    pub fn replace(key: &url::Url, entry: &Entry, size: u64) {
 	let mut cache = CACHE.write().unwrap();
 
-	cache.replace(key, entry)
+	cache.replace(key, entry, size)
+    }
+
+    pub fn is_cacheable(size: u64) -> bool {
+	let cache = CACHE.read().unwrap();
+
+	cache.is_cacheable(size)
     }
 
     #[instrument(level = "trace")]
@@ -771,23 +1243,61 @@ impl Cache {
 	cache.get_client()
     }
 
-    pub fn new_file() -> Result<std::fs::File> {
+    fn persisting() -> Arc<tokio::sync::RwLock<()>> {
+	CACHE.read().unwrap().persisting.clone()
+    }
+
+    /// Creates a file for a body; with the persistent cache, its path is
+    /// returned too
+    pub fn new_file() -> Result<(std::fs::File, Option<std::path::PathBuf>)> {
 	let cache = CACHE.read().unwrap();
 
-	Ok(tempfile::tempfile_in(&cache.tmpdir)?)
+	match &cache.store {
+	    Some(store)	=> store.create_file().map(|(f, p)| (f, Some(p))),
+	    None	=> Ok((tempfile::tempfile_in(&cache.tmpdir)?, None)),
+	}
+    }
+
+    /// Registers a complete body in the persistent cache and returns its
+    /// new path
+    pub fn commit(key: &url::Url, body: &std::path::Path, cache_info: &http::CacheInfo,
+		  file_size: u64) -> Result<Option<std::path::PathBuf>> {
+	let (body, changes) = {
+	    let mut cache = CACHE.write().unwrap();
+
+	    let Some(store) = &mut cache.store else {
+		return Ok(None);
+	    };
+
+	    (store.commit(key, body, cache_info, file_size)?, store.take_changes())
+	};
+
+	// the index is synced to disk without holding the lock
+	if let Some(w) = changes {
+	    w.write()?;
+	}
+
+	Ok(Some(body))
     }
 
     pub async fn dump() {
+	fn limit(v: Option<u64>) -> String {
+	    v.map(|v| v.to_string()).unwrap_or_else(|| "unlimited".into())
+	}
+
 	let mut entries = Vec::new();
 
-	{
+	let (size, max_size, max_object_size) = {
 	    let cache = CACHE.read().unwrap();
 
 	    entries.reserve(cache.entries.len());
-	    entries.extend(cache.entries.values().cloned());
-	}
+	    entries.extend(cache.entries.values().map(|s| s.entry.clone()));
+
+	    (cache.size, cache.max_size, cache.max_object_size)
+	};
 
-	println!("Cache information ({} entries)", entries.len());
+	println!("Cache information ({} entries, {} of {} bytes used, max object size {})",
+		 entries.len(), size, limit(max_size), limit(max_object_size));
 
 	for e in entries {
 	    println!("{}", e.read().await);
@@ -800,3 +1310,197 @@ impl Cache {
 	cache.clear();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gc_size() {
+	let mut cache = CacheImpl::new();
+	let url = |s: &str| url::Url::parse(&format!("http://host/{s}")).unwrap();
+
+	cache.max_size = Some(10);
+	cache.max_object_size = Some(6);
+
+	assert!(cache.is_cacheable(6));
+	assert!(!cache.is_cacheable(7));
+
+	let a = cache.create(&url("a"));
+	let b = cache.create(&url("b"));
+
+	cache.replace(&url("a"), &a, 4);
+	cache.replace(&url("b"), &b, 4);
+	drop(a);
+
+	// 'a' is the least recently used entry which is not in use
+	let c = cache.create(&url("c"));
+
+	cache.replace(&url("c"), &c, 4);
+	drop(c);
+
+	assert_eq!(cache.size, 8);
+	assert!(!cache.entries.contains_key(&url("a")));
+
+	// 'b' is still in use
+	let d = cache.create(&url("d"));
+
+	cache.replace(&url("d"), &d, 4);
+
+	assert_eq!(cache.size, 8);
+	assert!(cache.entries.contains_key(&url("b")));
+	assert!(!cache.entries.contains_key(&url("c")));
+
+	// all entries are in use
+	let e = cache.create(&url("e"));
+
+	cache.replace(&url("e"), &e, 4);
+
+	assert_eq!(cache.size, 12);
+	assert_eq!(cache.entries.len(), 3);
+
+	// lookups update the lru information
+	drop(b);
+	drop(d);
+	cache.lookup_or_create(&url("b"));
+	cache.gc_size();
+
+	assert_eq!(cache.size, 8);
+	assert!(cache.entries.contains_key(&url("b")));
+	assert!(!cache.entries.contains_key(&url("d")));
+    }
+
+    #[tokio::test]
+    async fn test_read_downloading() {
+	use std::os::unix::fs::FileExt;
+
+	let file = Arc::new(tempfile::tempfile().unwrap());
+	let (tx, rx) = tokio::sync::watch::channel(Progress::default());
+	let state = State::Downloading {
+	    cache_info:	http::CacheInfo::new(Time::now(), &Default::default()).unwrap(),
+	    file_size:	None,
+	    file:	file.clone(),
+	    progress:	rx,
+	};
+
+	let mut buf = [MaybeUninit::uninit(); 8];
+	let read = |state: &State, ofs, buf: &mut [MaybeUninit<u8>]| match state.read(ofs, buf).unwrap() {
+	    ReadResult::Data(sz)	=> Some(sz),
+	    ReadResult::Pending(_)	=> None,
+	};
+
+	assert_eq!(read(&state, 0, &mut buf), None);
+
+	let ReadResult::Pending(waiter) = state.read(0, &mut buf).unwrap() else {
+	    panic!("data without progress");
+	};
+
+	let reader = tokio::spawn(waiter.wait(0));
+
+	file.write_all_at(b"hello", 0).unwrap();
+	tx.send_modify(|p| p.pos = 5);
+
+	reader.await.unwrap().unwrap();
+
+	// only the completed prefix is returned
+	assert_eq!(read(&state, 2, &mut buf), Some(3));
+	assert_eq!(read(&state, 5, &mut buf), None);
+
+	let size = tokio::spawn(Waiter(tx.subscribe()).wait_done());
+
+	tx.send_modify(|p| p.done = Some(Ok(())));
+
+	assert_eq!(read(&state, 5, &mut buf), Some(0));
+	assert_eq!(size.await.unwrap().unwrap(), 5);
+    }
+
+    /// Serves a resource on a local port; the first response is broken off
+    /// after 4000 bytes.  When `modified` is set, range requests are
+    /// answered by `200` as when `If-Range` does not match.
+    fn flaky_server(modified: bool) -> (url::Url, Vec<u8>) {
+	use std::io::{ BufRead, Write };
+
+	let data: Vec<u8> = (0..10000_u32).map(|v| (v % 251) as u8).collect();
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}/file", listener.local_addr().unwrap()).parse().unwrap();
+	let body = data.clone();
+
+	std::thread::spawn(move || {
+	    for conn in listener.incoming() {
+		let mut conn = conn.unwrap();
+		let mut reader = std::io::BufReader::new(conn.try_clone().unwrap());
+		let mut range = None;
+
+		loop {
+		    let mut line = String::new();
+
+		    reader.read_line(&mut line).unwrap();
+
+		    if line.trim().is_empty() {
+			break;
+		    }
+
+		    if let Some(r) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+			range = r.trim().trim_end_matches('-').parse::<usize>().ok();
+		    }
+		}
+
+		let res = match range {
+		    None		=> {
+			write!(conn, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\r\n", body.len())
+			    .and_then(|_| conn.write_all(&body[..4000]))
+		    },
+		    Some(_) if modified	=> {
+			write!(conn, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v2\"\r\n\r\n", body.len())
+			    .and_then(|_| conn.write_all(&body))
+		    },
+		    Some(start)		=> {
+			write!(conn, "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nETag: \"v1\"\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+			       body.len() - start, start, body.len() - 1, body.len())
+			    .and_then(|_| conn.write_all(&body[start..]))
+		    },
+		};
+
+		res.unwrap();
+	    }
+	});
+
+	(url, data)
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+	use std::os::unix::fs::FileExt;
+
+	for modified in [false, true] {
+	    let (url, data) = flaky_server(modified);
+	    let response = Cache::get_client().get(url).send().await.unwrap();
+	    let cache_info = http::CacheInfo::new(Time::now(), response.headers()).unwrap();
+	    let resume = Resume::new(&response, &cache_info);
+	    let file = Arc::new(tempfile::tempfile().unwrap());
+	    let (tx, rx) = tokio::sync::watch::channel(Progress::default());
+
+	    assert!(resume.is_some());
+
+	    download(response, file.clone(), resume, tx, std::sync::Weak::new()).await;
+
+	    let p = rx.borrow();
+
+	    match modified {
+		false	=> {
+		    let mut buf = vec![0u8; data.len()];
+
+		    assert!(matches!(p.done, Some(Ok(()))));
+		    assert_eq!(p.pos, data.len() as u64);
+
+		    file.read_exact_at(&mut buf, 0).unwrap();
+		    assert_eq!(buf, data);
+		},
+		true	=> {
+		    assert!(matches!(p.done, Some(Err(Error::BadRange(_)))));
+		    assert_eq!(p.pos, 4000);
+		},
+	    }
+	}
+    }
+}
@@ -18,6 +18,9 @@ pub enum Error {
     #[error("request failed with status {0}")]
     HttpStatus(reqwest::StatusCode),
 
+    #[error("bad range response: {0}")]
+    BadRange(&'static str),
+
     #[error("bad http time")]
     BadHttpTime,
 
@@ -26,6 +29,9 @@ pub enum Error {
 
     #[error("internal error: {0}")]
     Internal(&'static str),
+
+    #[error("cache is already used with a different configuration")]
+    CacheConflict,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -38,9 +44,11 @@ impl Clone for Error {
             Self::HttpError(arg0) => Self::HttpErrorStr(format!("{arg0}").into()),
             Self::HttpErrorStr(arg0) => Self::HttpErrorStr(arg0.clone()),
 	    Self::HttpStatus(s) => Self::HttpStatus(*s),
+            Self::BadRange(arg0) => Self::BadRange(arg0),
             Self::BadHttpTime => Self::BadHttpTime,
             Self::StringConversion => Self::StringConversion,
             Self::Internal(arg0) => Self::Internal(arg0),
+            Self::CacheConflict => Self::CacheConflict,
 	}
     }
 }
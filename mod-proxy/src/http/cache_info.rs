@@ -4,7 +4,7 @@ use crate::{ Result, Error };
 
 use super::{ HttpHeader, Time };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CacheInfo {
     pub not_after:	Option<Instant>,
     // given in remote time
@@ -85,6 +85,16 @@ impl CacheInfo {
 	req
     }
 
+    /// Returns the validator for an `If-Range` header; weak etags can not
+    /// be used there
+    pub fn if_range(&self) -> Option<reqwest::header::HeaderValue> {
+	match (&self.etag, self.modified) {
+	    (Some(e), _) if !e.as_bytes().starts_with(b"W/")	=> Some(e.clone()),
+	    (_, Some(tm))	=> httpdate::fmt_http_date(tm).parse().ok(),
+	    _			=> None,
+	}
+    }
+
     pub fn update(self, localtm: Time, hdrs: &reqwest::header::HeaderMap) -> Result<Self>
     {
 	let tmp = Self::new(localtm, hdrs)?;
@@ -200,4 +210,27 @@ mod test {
 
 	assert!(!e.is_outdated(now.mono, Duration::from_secs(100_000)));
     }
+
+    #[test]
+    fn test_if_range() {
+	use reqwest::header as H;
+	use reqwest::header::HeaderMap;
+
+	let info = |hdrs: &[(H::HeaderName, &str)]| {
+	    let mut map = HeaderMap::new();
+
+	    for (k, v) in hdrs {
+		map.append(k, v.parse().unwrap());
+	    }
+
+	    CacheInfo::new(Time::now(), &map).unwrap().if_range()
+	};
+
+	let tm = "Sun, 23 May 1971 00:00:00 GMT";
+
+	assert_eq!(info(&[]), None);
+	assert_eq!(info(&[(H::ETAG, "\"abc\"")]).unwrap(), "\"abc\"");
+	assert_eq!(info(&[(H::ETAG, "W/\"abc\"")]), None);
+	assert_eq!(info(&[(H::ETAG, "W/\"abc\""), (H::LAST_MODIFIED, tm)]).unwrap(), tm);
+    }
 }
@@ -0,0 +1,69 @@
+use crate::{ Result, Error };
+
+/// The `Content-Range` of a `206 Partial Content` response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start:	u64,
+    // inclusive
+    pub end:	u64,
+    pub total:	Option<u64>,
+}
+
+impl ContentRange {
+    pub fn parse(s: &[u8]) -> Result<Self> {
+	use super::as_u64;
+
+	let range = s.strip_prefix(b"bytes ").ok_or(Error::StringConversion)?;
+
+	let (range, total) = match range.iter().position(|c| *c == b'/') {
+	    Some(p)	=> (&range[..p], &range[p + 1..]),
+	    None	=> return Err(Error::StringConversion),
+	};
+
+	let (start, end) = match range.iter().position(|c| *c == b'-') {
+	    Some(p)	=> (as_u64(&range[..p])?, as_u64(&range[p + 1..])?),
+	    None	=> return Err(Error::StringConversion),
+	};
+
+	let total = match total {
+	    b"*"	=> None,
+	    t		=> Some(as_u64(t)?),
+	};
+
+	if start > end || total.is_some_and(|t| end >= t) {
+	    return Err(Error::StringConversion);
+	}
+
+	Ok(Self {
+	    start:	start,
+	    end:	end,
+	    total:	total,
+	})
+    }
+
+    pub fn from_headers(hdrs: &reqwest::header::HeaderMap) -> Result<Option<Self>> {
+	match hdrs.get(reqwest::header::CONTENT_RANGE) {
+	    Some(v)	=> Ok(Some(Self::parse(v.as_bytes())?)),
+	    None	=> Ok(None),
+	}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+	assert_eq!(ContentRange::parse(b"bytes 10-99/100").unwrap(),
+		   ContentRange { start: 10, end: 99, total: Some(100) });
+	assert_eq!(ContentRange::parse(b"bytes 0-0/*").unwrap(),
+		   ContentRange { start: 0, end: 0, total: None });
+
+	assert!(ContentRange::parse(b"bytes 10-100/100").is_err());
+	assert!(ContentRange::parse(b"bytes 10-9/100").is_err());
+	assert!(ContentRange::parse(b"bytes */100").is_err());
+	assert!(ContentRange::parse(b"bytes 10-/100").is_err());
+	assert!(ContentRange::parse(b"items 0-1/2").is_err());
+    }
+}
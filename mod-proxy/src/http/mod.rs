@@ -2,12 +2,14 @@ mod cache_control;
 mod header;
 mod errors;
 mod cache_info;
+mod content_range;
 mod time;
 
 #[path = "multi-header.rs"]
 mod multi_header;
 
 pub use cache_info::CacheInfo;
+pub use content_range::ContentRange;
 pub use cache_control::{ CacheControl,
 			 Iterator as CacheControlIterator };
 pub use header::HttpHeader;
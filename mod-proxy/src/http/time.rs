@@ -13,6 +13,22 @@ impl Time {
 	    mono:	std::time::Instant::now(),
 	}
     }
+
+    /// Converts a monotonic time into wall clock time relative to `self`
+    pub fn to_system(self, tm: Instant) -> Option<SystemTime> {
+	match tm < self.mono {
+	    true	=> self.local.checked_sub(self.mono - tm),
+	    false	=> self.local.checked_add(tm - self.mono),
+	}
+    }
+
+    /// Converts a wall clock time into monotonic time relative to `self`
+    pub fn to_instant(self, tm: SystemTime) -> Option<Instant> {
+	match tm.duration_since(self.local) {
+	    Ok(d)	=> self.mono.checked_add(d),
+	    Err(e)	=> self.mono.checked_sub(e.duration()),
+	}
+    }
 }
 
 impl PartialEq for Time {
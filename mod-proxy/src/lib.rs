@@ -17,4 +17,5 @@ pub use cache::{ Cache,
 		 EntryData as CacheEntryData };
 
 mod http;
+mod store;
 mod util;
@@ -0,0 +1,437 @@
+//! Persistent storage of cache entries.
+//!
+//! Bodies are downloaded into `tmp-*` files which are renamed to `body-*`
+//! after they are complete and synced.  The `index` file maps urls to
+//! bodies and their http cache information; it is replaced atomically.
+//! Files which are not referenced by the index (e.g. after a crash) are
+//! removed when the store is opened.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, SystemTime };
+
+use crate::{ Error, Result };
+use crate::http::{ CacheInfo, Time };
+
+const INDEX: &str = "index";
+const INDEX_TMP: &str = "index.tmp";
+const INDEX_MAGIC: &str = "r-tftpd-cache 1";
+const BODY_PREFIX: &str = "body-";
+const TMP_PREFIX: &str = "tmp-";
+
+fn to_secs(tm: SystemTime) -> Option<u64> {
+    tm.duration_since(SystemTime::UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn from_secs(s: &str) -> Result<Option<SystemTime>> {
+    match s {
+	""	=> Ok(None),
+	s	=> s.parse()
+	    .map(|s| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(s)))
+	    .map_err(|_| Error::StringConversion),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct IndexEntry {
+    body:	String,
+    size:	u64,
+    stored:	SystemTime,
+    modified:	Option<SystemTime>,
+    not_after:	Option<SystemTime>,
+    etag:	Option<String>,
+}
+
+impl IndexEntry {
+    fn new(body: String, size: u64, info: &CacheInfo, now: Time) -> Self {
+	Self {
+	    body:	body,
+	    size:	size,
+	    stored:	now.to_system(info.local_time).unwrap_or(now.local),
+	    modified:	info.modified,
+	    not_after:	info.not_after.and_then(|t| now.to_system(t)),
+	    // etags with non-visible characters are not stored; they can not
+	    // be represented in the index and are very uncommon
+	    etag:	info.etag.as_ref()
+		.and_then(|e| e.to_str().ok())
+		.map(String::from),
+	}
+    }
+
+    fn to_cache_info(&self, now: Time) -> CacheInfo {
+	CacheInfo {
+	    not_after:	self.not_after.map(|t| now.to_instant(t).unwrap_or(now.mono)),
+	    modified:	self.modified,
+	    etag:	self.etag.as_ref().and_then(|e| e.parse().ok()),
+	    local_time:	now.to_instant(self.stored).unwrap_or(now.mono),
+	}
+    }
+
+    fn format(&self, key: &url::Url) -> String {
+	let tm = |t: Option<SystemTime>| t
+	    .and_then(to_secs)
+	    .map(|t| t.to_string())
+	    .unwrap_or_default();
+
+	format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\n", key, self.body, self.size,
+		tm(Some(self.stored)), tm(self.modified), tm(self.not_after),
+		self.etag.as_deref().unwrap_or(""))
+    }
+
+    fn parse(line: &str) -> Result<(url::Url, Self)> {
+	let mut it = line.split('\t');
+	let mut next = || it.next().ok_or(Error::StringConversion);
+
+	let key = url::Url::parse(next()?).map_err(|_| Error::StringConversion)?;
+	let body = next()?;
+
+	if !body.starts_with(BODY_PREFIX) || body.contains('/') {
+	    return Err(Error::StringConversion);
+	}
+
+	let res = Self {
+	    body:	body.into(),
+	    size:	next()?.parse().map_err(|_| Error::StringConversion)?,
+	    stored:	from_secs(next()?)?.ok_or(Error::StringConversion)?,
+	    modified:	from_secs(next()?)?,
+	    not_after:	from_secs(next()?)?,
+	    etag:	Some(next()?).filter(|e| !e.is_empty()).map(String::from),
+	};
+
+	Ok((key, res))
+    }
+}
+
+/// A cache entry which was read from the store
+#[derive(Debug)]
+pub struct Loaded {
+    pub key:		url::Url,
+    pub cache_info:	CacheInfo,
+    pub file:		std::fs::File,
+    pub file_size:	u64,
+    pub body:		PathBuf,
+}
+
+#[derive(Debug)]
+pub struct Store {
+    dir:	PathBuf,
+    index:	HashMap<url::Url, IndexEntry>,
+    dirty:	bool,
+    generation:	u64,
+    // generation of the last written index
+    written:	Arc<Mutex<u64>>,
+}
+
+/// Snapshot of a changed index; see [`Store::take_changes()`]
+#[derive(Debug)]
+pub struct IndexWriter {
+    dir:	PathBuf,
+    data:	String,
+    generation:	u64,
+    written:	Arc<Mutex<u64>>,
+}
+
+impl IndexWriter {
+    /// Replaces the index file unless a newer snapshot was written already
+    pub fn write(self) -> Result<()> {
+	let mut written = self.written.lock().unwrap();
+
+	if *written >= self.generation {
+	    return Ok(());
+	}
+
+	let tmp_path = self.dir.join(INDEX_TMP);
+	let mut tmp = std::fs::File::create(&tmp_path)?;
+
+	tmp.write_all(self.data.as_bytes())?;
+	tmp.sync_all()?;
+
+	std::fs::rename(tmp_path, self.dir.join(INDEX))?;
+	std::fs::File::open(&self.dir)?.sync_all()?;
+
+	*written = self.generation;
+
+	Ok(())
+    }
+}
+
+impl Store {
+    /// Opens the store in `dir` and returns the usable entries
+    pub fn open(dir: &Path) -> Result<(Self, Vec<Loaded>)> {
+	let mut res = Self {
+	    dir:	dir.into(),
+	    index:	HashMap::new(),
+	    dirty:	false,
+	    generation:	0,
+	    written:	Default::default(),
+	};
+
+	let index = match std::fs::read_to_string(dir.join(INDEX)) {
+	    Ok(s)						=> s,
+	    Err(e) if e.kind() == std::io::ErrorKind::NotFound	=> String::new(),
+	    Err(e)						=> return Err(e.into()),
+	};
+
+	let mut lines = index.lines();
+	let mut loaded = Vec::new();
+	let now = Time::now();
+
+	match lines.next() {
+	    None | Some(INDEX_MAGIC)	=> {},
+	    Some(_)			=> {
+		warn!("unsupported cache index in {:?}; ignoring it", dir);
+		lines = "".lines();
+	    }
+	}
+
+	for line in lines {
+	    let (key, entry) = match IndexEntry::parse(line) {
+		Ok(v)	=> v,
+		Err(_)	=> {
+		    warn!("bad cache index line {:?}", line);
+		    continue;
+		}
+	    };
+
+	    let body = dir.join(&entry.body);
+	    let file = match std::fs::File::open(&body) {
+		Ok(f) if f.metadata()?.len() == entry.size	=> f,
+		Ok(_)	=> {
+		    warn!("cached body of {} has a bad size", key);
+		    continue;
+		},
+		Err(e)	=> {
+		    warn!("failed to open cached body of {}: {}", key, e);
+		    continue;
+		},
+	    };
+
+	    loaded.push(Loaded {
+		key:		key.clone(),
+		cache_info:	entry.to_cache_info(now),
+		file:		file,
+		file_size:	entry.size,
+		body:		body,
+	    });
+
+	    res.index.insert(key, entry);
+	}
+
+	res.remove_orphans()?;
+
+	// rewrite index when bad entries were skipped
+	res.dirty = loaded.len() != index.lines().count().saturating_sub(1);
+	res.flush()?;
+
+	info!("loaded {} cache entries from {:?}", loaded.len(), dir);
+
+	Ok((res, loaded))
+    }
+
+    fn remove_orphans(&self) -> Result<()> {
+	use std::os::unix::ffi::OsStrExt;
+
+	for e in std::fs::read_dir(&self.dir)? {
+	    let e = e?;
+	    let name = e.file_name();
+	    let name = name.as_bytes();
+
+	    let is_orphan = name.starts_with(TMP_PREFIX.as_bytes()) ||
+		(name.starts_with(BODY_PREFIX.as_bytes()) &&
+		 !self.index.values().any(|i| i.body.as_bytes() == name));
+
+	    if is_orphan {
+		debug!("removing orphaned {:?}", e.path());
+
+		if let Err(err) = std::fs::remove_file(e.path()) {
+		    warn!("failed to remove orphaned {:?}: {}", e.path(), err);
+		}
+	    }
+	}
+
+	Ok(())
+    }
+
+    /// Creates a file for downloading a body; it must be passed to
+    /// `commit()` once it is complete
+    pub fn create_file(&self) -> Result<(std::fs::File, PathBuf)> {
+	let tmp = tempfile::Builder::new()
+	    .prefix(TMP_PREFIX)
+	    .tempfile_in(&self.dir)?;
+
+	Ok(tmp.keep().map_err(std::io::Error::from)?)
+    }
+
+    pub fn is_tmp(body: &Path) -> bool {
+	body.file_name()
+	    .and_then(|n| n.to_str())
+	    .is_some_and(|n| n.starts_with(TMP_PREFIX))
+    }
+
+    /// Registers the complete `body` of `key`; temporary files are renamed.
+    /// The body must have been synced before; the index is written by
+    /// `flush()` or `take_changes()`.
+    ///
+    /// Returns the new path of the body.
+    pub fn commit(&mut self, key: &url::Url, body: &Path, cache_info: &CacheInfo,
+		  file_size: u64) -> Result<PathBuf> {
+	let name = body.file_name()
+	    .and_then(|n| n.to_str())
+	    .ok_or(Error::Internal("bad cache file name"))?;
+
+	let name = match name.strip_prefix(TMP_PREFIX) {
+	    Some(suffix)	=> {
+		let name = format!("{BODY_PREFIX}{suffix}");
+
+		std::fs::rename(body, self.dir.join(&name))?;
+		name
+	    },
+	    None		=> name.to_string(),
+	};
+
+	let entry = IndexEntry::new(name.clone(), file_size, cache_info, Time::now());
+
+	if let Some(old) = self.index.insert(key.clone(), entry) {
+	    if old.body != name {
+		self.remove_body(&old.body);
+	    }
+	}
+
+	self.dirty = true;
+
+	Ok(self.dir.join(name))
+    }
+
+    fn remove_body(&self, body: &str) {
+	// the body might still be read by running transfers; they keep the
+	// file open
+	if let Err(e) = std::fs::remove_file(self.dir.join(body)) {
+	    warn!("failed to remove cached body {}: {}", body, e);
+	}
+    }
+
+    /// Removes `key` from the store; the change is written by `flush()`
+    pub fn remove(&mut self, key: &url::Url) {
+	if let Some(old) = self.index.remove(key) {
+	    self.remove_body(&old.body);
+	    self.dirty = true;
+	}
+    }
+
+    pub fn clear(&mut self) {
+	for (_, old) in std::mem::take(&mut self.index) {
+	    self.remove_body(&old.body);
+	}
+
+	self.dirty = true;
+    }
+
+    /// Returns a snapshot of the index when it was changed.  Writing it
+    /// syncs to disk; this is done without holding the lock of the store.
+    pub fn take_changes(&mut self) -> Option<IndexWriter> {
+	if !self.dirty {
+	    return None;
+	}
+
+	let mut data = format!("{INDEX_MAGIC}\n");
+
+	for (key, e) in &self.index {
+	    data.push_str(&e.format(key));
+	}
+
+	self.dirty = false;
+	self.generation += 1;
+
+	Some(IndexWriter {
+	    dir:	self.dir.clone(),
+	    data:	data,
+	    generation:	self.generation,
+	    written:	self.written.clone(),
+	})
+    }
+
+    /// Writes the index when it was changed
+    pub fn flush(&mut self) -> Result<()> {
+	match self.take_changes() {
+	    Some(w)	=> w.write(),
+	    None	=> Ok(()),
+	}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_store() {
+	let dir = tempfile::TempDir::new().unwrap();
+	let key = url::Url::parse("http://host/vmlinuz").unwrap();
+	let now = Time::now();
+
+	let info = CacheInfo {
+	    not_after:	Some(now.mono + Duration::from_secs(3600)),
+	    modified:	Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)),
+	    etag:	Some("\"abc def\"".parse().unwrap()),
+	    local_time:	now.mono,
+	};
+
+	{
+	    let (mut store, loaded) = Store::open(dir.path()).unwrap();
+
+	    assert!(loaded.is_empty());
+
+	    let (mut file, body) = store.create_file().unwrap();
+
+	    assert!(Store::is_tmp(&body));
+
+	    file.write_all(b"hello").unwrap();
+	    file.sync_all().unwrap();
+
+	    let body = store.commit(&key, &body, &info, 5).unwrap();
+
+	    assert!(!Store::is_tmp(&body));
+
+	    // an older snapshot does not overwrite a newer index
+	    let old = store.take_changes().unwrap();
+
+	    store.dirty = true;
+	    store.flush().unwrap();
+	    old.write().unwrap();
+
+	    assert_eq!(*store.written.lock().unwrap(), 2);
+
+	    // an interrupted download
+	    store.create_file().unwrap();
+	}
+
+	std::fs::write(dir.path().join("body-stale"), b"x").unwrap();
+	// can not be removed but does not prevent loading
+	std::fs::create_dir(dir.path().join("tmp-dir")).unwrap();
+
+	let (mut store, loaded) = Store::open(dir.path()).unwrap();
+
+	assert_eq!(loaded.len(), 1);
+
+	let l = &loaded[0];
+
+	assert_eq!(l.key, key);
+	assert_eq!(l.file_size, 5);
+	assert_eq!(std::fs::read(&l.body).unwrap(), b"hello");
+	assert_eq!(l.cache_info.modified, info.modified);
+	assert_eq!(l.cache_info.etag, info.etag);
+	assert!(l.cache_info.not_after.unwrap() > now.mono + Duration::from_secs(3598));
+	assert!(l.cache_info.local_time < now.mono + Duration::from_secs(2));
+
+	// only 'index', the body and the unremovable directory are left
+	assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+
+	store.remove(&key);
+	store.flush().unwrap();
+
+	assert!(!l.body.exists());
+	assert!(Store::open(dir.path()).unwrap().1.is_empty());
+    }
+}
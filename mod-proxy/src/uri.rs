@@ -4,6 +4,7 @@ use crate::{ Error, Result };
 use crate::util::AsInit;
 
 use super::{ Cache, CacheEntry, CacheEntryData };
+use super::cache::{ ReadResult, SizeResult };
 
 #[derive(Debug)]
 pub struct Uri {
@@ -125,22 +126,55 @@ impl Uri {
 	    _					=> Cache::lookup_or_create(&uri),
 	};
 
-	{
+	let size = {
 	    let mut e_locked = entry.write().await;
 
-	    self.open_cached(&mut e_locked, flags).await?;
-	    self.size = Some(e_locked.get_filesize().await?);
+	    if let Err(e) = self.open_cached(&mut e_locked, flags).await {
+		// do not keep placeholders of failed requests
+		if !e_locked.is_complete() && !flags.contains(Flags::NO_CACHE) {
+		    Cache::remove(&e_locked.key);
+		}
+
+		return Err(e);
+	    }
 
-	    if e_locked.is_error() {
+	    // objects which exceed the size limits of the cache are streamed
+	    // without keeping them
+	    if let Some(sz) = e_locked.get_known_size() {
+		if !Cache::is_cacheable(sz) {
+		    e_locked.set_uncached();
+		}
+	    }
+
+	    e_locked.get_filesize()
+	};
+
+	// the lock is released while waiting so that other transfers can
+	// read the body which is downloaded so far
+	let size = match size {
+	    SizeResult::Known(sz)	=> sz,
+	    SizeResult::Pending(waiter)	=> waiter.wait_done().await?,
+	};
+
+	self.size = Some(size);
+
+	let cacheable = {
+	    let mut e_locked = entry.write().await;
+	    let cacheable = !e_locked.is_error() && Cache::is_cacheable(size);
+
+	    if !cacheable {
+		e_locked.set_uncached();
 		Cache::remove(&e_locked.key);
 	    }
+
+	    cacheable
 	};
 
-	{
+	if cacheable {
 	    let e_locked = entry.read().await;
 
-	    Cache::replace(&e_locked.key, &entry)
-	};
+	    Cache::replace(&e_locked.key, &entry, self.size.unwrap());
+	}
 
 	self.cache = Some(entry);
 
@@ -157,13 +191,23 @@ impl Uri {
     {
 	assert!(!self.is_eof);
 
-	let mut entry = self.cache.as_ref().unwrap().write().await;
+	let entry = self.cache.as_ref().unwrap();
 
 	let len = buf.len();
 	let mut pos = 0;
 
 	while pos < len {
-	    let sz = entry.read_some(self.pos, &mut buf[pos..len]).await?.len();
+	    // the lock is released while waiting for the download so that it
+	    // can be finished
+	    let res = entry.read().await.read_some(self.pos, &mut buf[pos..len])?;
+
+	    let sz = match res {
+		ReadResult::Data(sz)		=> sz,
+		ReadResult::Pending(waiter)	=> {
+		    waiter.wait(self.pos).await?;
+		    continue;
+		},
+	    };
 
 	    if sz == 0 {
 		self.is_eof = true;
@@ -1,4 +1,6 @@
 #[path = "../../src/util/uninit.rs"]
+// shared with the main crate; not every helper is used here
+#[allow(dead_code)]
 mod uninit;
 
 pub use uninit::*;
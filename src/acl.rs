@@ -0,0 +1,257 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::{ Regex, RegexBuilder };
+
+use crate::util::{ Cidr, UdpRecvInfo, if_name };
+use crate::{ Error, Result };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    spec:	Box<str>,
+    action:	Action,
+    client:	Option<Cidr>,
+    local:	Option<Cidr>,
+    iface:	Option<String>,
+    /// path pattern; second regex matches case-insensitively
+    path:	Option<(Regex, Regex)>,
+}
+
+/// Translates a shell like glob into an anchored regular expression.
+///
+/// `*` and `?` do not match `/`; `**` matches everything.
+fn glob_to_regex(glob: &str) -> String
+{
+    let mut res = String::from("^");
+    let mut chars = glob.trim_start_matches('/').chars().peekable();
+
+    while let Some(c) = chars.next() {
+	match c {
+	    '*' if chars.peek() == Some(&'*')	=> {
+		chars.next();
+		res.push_str(".*");
+	    },
+	    '*'		=> res.push_str("[^/]*"),
+	    '?'		=> res.push_str("[^/]"),
+	    c		=> res.push_str(&regex::escape(&c.to_string())),
+	}
+    }
+
+    res.push('$');
+    res
+}
+
+fn path_regex(glob: &str) -> Result<(Regex, Regex)>
+{
+    let re = glob_to_regex(glob);
+    let to_error = |e: regex::Error| Error::InvalidArgument(e.to_string().into());
+
+    Ok((Regex::new(&re).map_err(to_error)?,
+	RegexBuilder::new(&re).case_insensitive(true).build().map_err(to_error)?))
+}
+
+impl Rule {
+    pub fn action(&self) -> Action {
+	self.action
+    }
+
+    /// Checks whether rule matches; `path` must be normalized and
+    /// `iface` is the (lazily resolved) name of the receiving interface.
+    /// `icase` must be set when filenames are resolved case-insensitively.
+    fn matches<F>(&self, remote: &IpAddr, local: &IpAddr, mut iface: F,
+		  path: Option<&Path>, icase: bool) -> bool
+    where
+	F: FnMut() -> Option<String>,
+    {
+	if let Some(client) = &self.client {
+	    if !client.contains(remote) {
+		return false;
+	    }
+	}
+
+	if let Some(l) = &self.local {
+	    if !l.contains(local) {
+		return false;
+	    }
+	}
+
+	if let Some(name) = &self.iface {
+	    if iface().as_ref() != Some(name) {
+		return false;
+	    }
+	}
+
+	if let Some((re, re_icase)) = &self.path {
+	    let re = match icase {
+		true	=> re_icase,
+		false	=> re,
+	    };
+
+	    match path.and_then(|p| p.to_str()) {
+		Some(p) if re.is_match(p)	=> {},
+		_				=> return false,
+	    }
+	}
+
+	true
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+	let mut tokens = s.split_whitespace();
+
+	let action = match tokens.next() {
+	    Some("allow")	=> Action::Allow,
+	    Some("deny")	=> Action::Deny,
+	    _			=> return Err(Error::InvalidArgument(
+		format!("acl rule '{s}' must start with 'allow' or 'deny'").into())),
+	};
+
+	let mut res = Self {
+	    spec:	s.trim().into(),
+	    action:	action,
+	    client:	None,
+	    local:	None,
+	    iface:	None,
+	    path:	None,
+	};
+
+	for t in tokens {
+	    match t.split_once('=') {
+		Some(("client", v))	=> res.client = Some(v.parse()?),
+		Some(("local", v))	=> res.local  = Some(v.parse()?),
+		Some(("iface", v))	=> res.iface  = Some(v.to_string()),
+		Some(("path", v))	=> res.path   = Some(path_regex(v)?),
+		_			=> return Err(Error::InvalidArgument(
+		    format!("bad token '{t}' in acl rule '{s}'").into())),
+	    }
+	}
+
+	Ok(res)
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	f.write_str(&self.spec)
+    }
+}
+
+/// Per-client access control.
+///
+/// Rules are given as `allow|deny [client=CIDR] [local=CIDR] [iface=NAME]
+/// [path=GLOB]` and are evaluated in order; the first matching rule
+/// decides.  Requests not matched by any rule are allowed.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules:	Vec<Rule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<Rule>) -> Self {
+	Self {
+	    rules:	rules,
+	}
+    }
+
+    /// Returns the first rule matching the request together with its
+    /// index.  `path` is the filename after applying the rewrite rules
+    /// (i.e. the name which is served, not necessarily the one sent by the
+    /// client); `icase` must be set when filenames are resolved
+    /// case-insensitively.
+    pub fn lookup(&self, info: &UdpRecvInfo, path: &Path, icase: bool) -> Option<(usize, &Rule)> {
+	let path = crate::fetcher::normalize_path(path).ok();
+	let mut iface = None;
+
+	let remote = info.remote.ip();
+
+	self.rules.iter().enumerate().find(|(_, r)| {
+	    r.matches(&remote, &info.local,
+		      || iface.get_or_insert_with(|| if_name(info.if_idx)).clone(),
+		      path.as_deref(), icase)
+	})
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+	s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+	let m = |glob: &str, p: &str| Regex::new(&glob_to_regex(glob)).unwrap().is_match(p);
+
+	assert!(m("/prod/*", "prod/foo"));
+	assert!(m("prod/*", "prod/foo"));
+	assert!(!m("prod/*", "prod/foo/bar"));
+	assert!(m("prod/**", "prod/foo/bar"));
+	assert!(m("**.img", "a/b/c.img"));
+	assert!(!m("*.img", "a/c.img"));
+	assert!(m("pxelinux.cfg/01-??-*", "pxelinux.cfg/01-aa-bb"));
+	assert!(!m("a.b", "axb"));
+	assert!(!m("prod", "production"));
+    }
+
+    #[test]
+    fn test_parse() {
+	assert!("allow".parse::<Rule>().is_ok());
+	assert!("deny client=10.0.0.0/8 local=::1 iface=eth0 path=/a/**".parse::<Rule>().is_ok());
+
+	assert!("".parse::<Rule>().is_err());
+	assert!("permit".parse::<Rule>().is_err());
+	assert!("deny client=10.0.0.0/40".parse::<Rule>().is_err());
+	assert!("deny foo=bar".parse::<Rule>().is_err());
+	assert!("deny client".parse::<Rule>().is_err());
+
+	assert_eq!("  deny  client=10.0.0.0/8 ".parse::<Rule>().unwrap().to_string(),
+		   "deny  client=10.0.0.0/8");
+    }
+
+    #[test]
+    fn test_match() {
+	let rules = [
+	    "allow client=10.0.0.0/8 path=/prod/**",
+	    "deny path=/prod/**",
+	    "deny iface=lab0",
+	    "deny local=192.168.1.1",
+	];
+
+	let rules: Vec<Rule> = rules.iter().map(|r| r.parse().unwrap()).collect();
+
+	let check_ext = |remote: &str, local: &str, iface: &str, path: &str, icase| {
+	    let path = crate::fetcher::normalize_path(Path::new(path)).unwrap();
+	    let iface = || Some(iface.to_string());
+
+	    rules.iter()
+		.position(|r| r.matches(&ip(remote), &ip(local), iface, Some(&path), icase))
+	};
+
+	let check = |remote: &str, local: &str, iface: &str, path: &str| {
+	    check_ext(remote, local, iface, path, false)
+	};
+
+	assert_eq!(check("10.1.2.3",    "10.0.0.1", "eth0", "/prod/img"), Some(0));
+	assert_eq!(check("::ffff:10.1.2.3", "::1",  "eth0", "prod/a/b"),  Some(0));
+	assert_eq!(check("172.16.0.1",  "10.0.0.1", "eth0", "/prod/img"), Some(1));
+	assert_eq!(check("172.16.0.1",  "10.0.0.1", "lab0", "/test/img"), Some(2));
+	assert_eq!(check("172.16.0.1",  "192.168.1.1", "eth0", "/test"),  Some(3));
+	assert_eq!(check("172.16.0.1",  "10.0.0.1", "eth0", "/test/img"), None);
+
+	assert_eq!(check("172.16.0.1",  "10.0.0.1", "eth0", "/PROD/img"), None);
+	assert_eq!(check_ext("172.16.0.1",  "10.0.0.1", "eth0", "/PROD/img", true), Some(1));
+    }
+}
@@ -0,0 +1,10 @@
+//! Encoding and decoding of TFTP packets (RFC 1350) with the options of
+//! RFC 2347, 2348, 2349 and 7440.
+//!
+//! The types are plain data and can be used without a running tokio
+//! runtime (e.g. in sniffers or fuzzers).  The crate itself still depends
+//! on tokio for the server.  Packets are decoded from borrowed slices by
+//! `Datagram::try_from(&[u8])` and encoded by `Datagram::encode_into()`.
+
+pub use crate::tftp::{ Datagram, Request, RequestDir, Mode, Oack, SequenceId,
+		       RequestError, RequestResult };
@@ -27,6 +27,12 @@ pub enum Error {
     #[error("file '{0}' is missing")]
     FileMissing(Box<std::path::Path>),
 
+    #[error("access violation: {0}")]
+    AccessViolation(&'static str),
+
+    #[error("file was modified during transfer")]
+    FileModified,
+
     #[error("internal error: {0}")]
     Internal(&'static str),
 
@@ -44,6 +50,27 @@ pub enum Error {
 
     #[error("too much clients")]
     TooMuchClients,
+
+    #[error("unknown user '{0}'")]
+    UnknownUser(Box<str>),
+
+    #[error("unknown group '{0}'")]
+    UnknownGroup(Box<str>),
+
+    #[error("path '{0}' is outside of chroot")]
+    OutsideChroot(Box<std::path::Path>),
+
+    #[error("failed to setup sandbox: {0}")]
+    Sandbox(Box<str>),
+
+    #[error("invalid argument: {0}")]
+    InvalidArgument(Box<str>),
+
+    #[error("template error: {0}")]
+    Template(Box<str>),
+
+    #[error("program error: {0}")]
+    Exec(&'static str),
 }
 
 impl Clone for Error {
@@ -55,6 +82,8 @@ impl Clone for Error {
             Self::InvalidPathName => Self::InvalidPathName,
             Self::UriParse => Self::UriParse,
             Self::FileMissing(arg0) => Self::FileMissing(arg0.clone()),
+            Self::AccessViolation(arg0) => Self::AccessViolation(arg0),
+            Self::FileModified => Self::FileModified,
             Self::Internal(arg0) => Self::Internal(arg0),
             Self::Timeout => Self::Timeout,
             Self::BadAck => Self::BadAck,
@@ -62,6 +91,13 @@ impl Clone for Error {
             Self::NotImplemented => Self::NotImplemented,
             Self::TooMuchClients => Self::TooMuchClients,
             Self::StringConversion => Self::StringConversion,
+            Self::UnknownUser(arg0) => Self::UnknownUser(arg0.clone()),
+            Self::UnknownGroup(arg0) => Self::UnknownGroup(arg0.clone()),
+            Self::OutsideChroot(arg0) => Self::OutsideChroot(arg0.clone()),
+            Self::Sandbox(arg0) => Self::Sandbox(arg0.clone()),
+            Self::InvalidArgument(arg0) => Self::InvalidArgument(arg0.clone()),
+            Self::Template(arg0) => Self::Template(arg0.clone()),
+            Self::Exec(arg0) => Self::Exec(arg0),
 
 	    #[cfg(feature = "proxy")]
             Self::Proxy(arg0) => Self::Proxy(arg0.clone()),
@@ -0,0 +1,893 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{ ErrorKind, Read, Seek };
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+
+use crate::{ Error, Result };
+use crate::util::AsInit;
+
+use super::{ File, Memory };
+
+/// Maximum number of cached archive indices; the cache is flushed when it
+/// grows beyond this number
+const MAX_ARCHIVES: usize = 16;
+
+/// Maximum size of compressed members; they are decompressed into memory
+const MAX_INFLATE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Maximum size of metadata (zip central directory, tar long names and
+/// pax headers)
+const MAX_META_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Tar,
+    Cpio,
+    Zip,
+    Iso,
+}
+
+impl Kind {
+    fn from_path(p: &Path) -> Option<Self> {
+	let ext = p.extension()?.as_bytes();
+
+	if ext.eq_ignore_ascii_case(b"tar") {
+	    Some(Self::Tar)
+	} else if ext.eq_ignore_ascii_case(b"cpio") {
+	    Some(Self::Cpio)
+	} else if ext.eq_ignore_ascii_case(b"zip") {
+	    Some(Self::Zip)
+	} else if ext.eq_ignore_ascii_case(b"iso") {
+	    Some(Self::Iso)
+	} else {
+	    None
+	}
+    }
+}
+
+/// Returns whether `p` has the extension of a supported archive
+pub fn is_archive(p: &Path) -> bool {
+    Kind::from_path(p).is_some()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Method {
+    Stored,
+    Deflate,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct Entry {
+    /// offset of the data; for zip archives, this is the offset of the
+    /// local header until it has been resolved by `zip_data_offset()`
+    pub offset:	u64,
+    pub size:	u64,
+    /// compressed size
+    pub csize:	u64,
+    pub method:	Method,
+    pub crc:	Option<u32>,
+}
+
+pub(super) type Index = HashMap<PathBuf, Entry>;
+
+pub(super) fn bad_data(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Converts the name of an archive member into the normalized form which is
+/// used for lookups; returns `None` for names with `..` components
+pub(super) fn member_name(raw: &[u8]) -> Option<PathBuf> {
+    let res = super::normalize_path(Path::new(OsStr::from_bytes(raw))).ok()?;
+
+    match res.as_os_str().is_empty() {
+	true	=> None,
+	false	=> Some(res),
+    }
+}
+
+/// Returns the bytes before the first NUL
+fn cstr(b: &[u8]) -> &[u8] {
+    b.split(|c| *c == 0).next().unwrap()
+}
+
+fn parse_num(b: &[u8], radix: u32) -> std::io::Result<u64> {
+    let s = std::str::from_utf8(cstr(b))
+	.map_err(|_| bad_data("bad number"))?
+	.trim();
+
+    match s.is_empty() {
+	true	=> Ok(0),
+	false	=> u64::from_str_radix(s, radix).map_err(|_| bad_data("bad number")),
+    }
+}
+
+pub(super) fn read_at(file: &std::fs::File, pos: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    if len > MAX_META_SIZE {
+	return Err(bad_data("metadata too large"));
+    }
+
+    let mut res = vec![0; len as usize];
+
+    file.read_exact_at(&mut res, pos)?;
+
+    Ok(res)
+}
+
+/// Reads a little endian number of `N` bytes
+pub(super) fn le<const N: usize>(b: &[u8], pos: usize) -> std::io::Result<u64> {
+    let b = b.get(pos..pos + N).ok_or_else(|| bad_data("truncated header"))?;
+
+    Ok(b.iter().rev().fold(0, |acc, c| (acc << 8) | *c as u64))
+}
+
+/// Parses a numeric tar header field (octal or GNU base-256)
+fn tar_num(b: &[u8]) -> std::io::Result<u64> {
+    if b[0] & 0x80 == 0 {
+	return parse_num(b, 8);
+    }
+
+    let mut res: u64 = (b[0] & 0x7f) as u64;
+
+    for c in &b[1..] {
+	res = res.checked_mul(256)
+	    .and_then(|v| v.checked_add(*c as u64))
+	    .ok_or_else(|| bad_data("bad number"))?;
+    }
+
+    Ok(res)
+}
+
+#[derive(Default)]
+struct PaxInfo {
+    path:	Option<Vec<u8>>,
+    linkpath:	Option<Vec<u8>>,
+    size:	Option<u64>,
+}
+
+impl PaxInfo {
+    /// Parses records of the form `<len> <key>=<value>\n`
+    fn parse(mut data: &[u8]) -> std::io::Result<Self> {
+	let mut res = Self::default();
+
+	while !data.is_empty() {
+	    let sp = data.iter().position(|c| *c == b' ')
+		.ok_or_else(|| bad_data("bad pax record"))?;
+	    let len = parse_num(&data[..sp], 10)? as usize;
+
+	    if len <= sp || len > data.len() {
+		return Err(bad_data("bad pax record"));
+	    }
+
+	    let rec = &data[sp + 1..len];
+	    let rec = rec.strip_suffix(b"\n").unwrap_or(rec);
+
+	    if let Some(eq) = rec.iter().position(|c| *c == b'=') {
+		let val = &rec[eq + 1..];
+
+		match &rec[..eq] {
+		    b"path"	=> res.path = Some(val.to_vec()),
+		    b"linkpath"	=> res.linkpath = Some(val.to_vec()),
+		    b"size"	=> res.size = Some(parse_num(val, 10)?),
+		    _		=> {},
+		}
+	    }
+
+	    data = &data[len..];
+	}
+
+	Ok(res)
+    }
+}
+
+fn read_tar(file: &std::fs::File, len: u64) -> std::io::Result<Index> {
+    let mut res = Index::new();
+    let mut pos = 0;
+    let mut long_name = None;
+    let mut pax = PaxInfo::default();
+    let mut hdr = [0u8; 512];
+
+    while pos + 512 <= len {
+	file.read_exact_at(&mut hdr, pos)?;
+
+	if hdr.iter().all(|c| *c == 0) {
+	    break;
+	}
+
+	let chksum = hdr.iter().enumerate()
+	    .map(|(i, c)| if (148..156).contains(&i) { b' ' } else { *c } as u64)
+	    .sum::<u64>();
+
+	if chksum != tar_num(&hdr[148..156])? {
+	    return Err(bad_data("bad tar header checksum"));
+	}
+
+	let typ = hdr[156];
+	let mut size = tar_num(&hdr[124..136])?;
+	let data = pos + 512;
+
+	match typ {
+	    b'L'				=> {
+		long_name = Some(cstr(&read_at(file, data, size)?).to_vec());
+	    },
+	    b'x'				=> {
+		pax = PaxInfo::parse(&read_at(file, data, size)?)?;
+	    },
+	    b'g'				=> {},
+	    b'0' | b'\0' | b'7' | b'1'		=> {
+		let pax = std::mem::take(&mut pax);
+		let name = match (long_name.take(), pax.path) {
+		    (Some(n), _)		=> n,
+		    (None, Some(n))		=> n,
+		    // ustar; name is prefixed
+		    (None, None) if &hdr[257..263] == b"ustar\0" && hdr[345] != 0	=> {
+			let mut n = cstr(&hdr[345..500]).to_vec();
+
+			n.push(b'/');
+			n.extend_from_slice(cstr(&hdr[0..100]));
+			n
+		    },
+		    (None, None)		=> cstr(&hdr[0..100]).to_vec(),
+		};
+
+		if let Some(sz) = pax.size {
+		    size = sz;
+		}
+
+		let entry = match typ {
+		    // hard links refer to an earlier member
+		    b'1'	=> pax.linkpath.as_deref()
+			.or(Some(cstr(&hdr[157..257])))
+			.and_then(member_name)
+			.and_then(|l| res.get(&l).cloned()),
+		    _		=> Some(Entry {
+			offset:	data,
+			size:	size,
+			csize:	size,
+			method:	Method::Stored,
+			crc:	None,
+		    }),
+		};
+
+		if let (Some(name), Some(entry)) = (member_name(&name), entry) {
+		    res.insert(name, entry);
+		}
+
+		if typ == b'1' {
+		    size = 0;
+		}
+	    },
+	    _					=> {
+		long_name = None;
+		pax = PaxInfo::default();
+	    }
+	}
+
+	// sizes are untrusted; require progress to avoid endless loops
+	pos = match size.div_ceil(512).checked_mul(512).and_then(|sz| data.checked_add(sz)) {
+	    Some(next) if next > pos	=> next,
+	    _				=> return Err(bad_data("bad tar member size")),
+	};
+    }
+
+    Ok(res)
+}
+
+fn read_cpio(file: &std::fs::File, len: u64) -> std::io::Result<Index> {
+    let mut res = Index::new();
+    let mut pos = 0;
+    // names of hard links in 'newc' archives whose data comes with the last
+    // link
+    let mut links = HashMap::<u64, Vec<PathBuf>>::new();
+
+    while pos < len {
+	let magic = read_at(file, pos, 6)?;
+
+	let (ino, mode, nlink, size, name, data) = match magic.as_slice() {
+	    b"070701" | b"070702"	=> {
+		let hdr = read_at(file, pos, 110)?;
+		let field = |i: usize| parse_num(&hdr[6 + i * 8..14 + i * 8], 16);
+		let namesize = field(11)?;
+		let name = read_at(file, pos + 110, namesize)?;
+
+		(field(0)?, field(1)?, field(4)?, field(6)?, name,
+		 (pos + 110 + namesize).next_multiple_of(4))
+	    },
+	    b"070707"			=> {
+		let hdr = read_at(file, pos, 76)?;
+		let namesize = parse_num(&hdr[59..65], 8)?;
+		let name = read_at(file, pos + 76, namesize)?;
+
+		(parse_num(&hdr[12..18], 8)?, parse_num(&hdr[18..24], 8)?, 1,
+		 parse_num(&hdr[65..76], 8)?, name, pos + 76 + namesize)
+	    },
+	    _				=> return Err(bad_data("bad cpio header")),
+	};
+
+	let name = cstr(&name);
+
+	if name == b"TRAILER!!!" {
+	    break;
+	}
+
+	let next = match (magic[5], data.checked_add(size)) {
+	    (_, None)		=> return Err(bad_data("bad cpio member size")),
+	    (b'7', Some(end))	=> end,
+	    (_, Some(end))	=> end.next_multiple_of(4),
+	};
+
+	let name = match member_name(name) {
+	    Some(n) if mode & 0o170000 == 0o100000	=> n,
+	    _						=> {
+		pos = next;
+		continue;
+	    }
+	};
+
+	if nlink > 1 && size == 0 {
+	    links.entry(ino).or_default().push(name);
+	} else {
+	    let entry = Entry {
+		offset:	data,
+		size:	size,
+		csize:	size,
+		method:	Method::Stored,
+		crc:	None,
+	    };
+
+	    for n in links.remove(&ino).unwrap_or_default() {
+		res.insert(n, entry.clone());
+	    }
+
+	    res.insert(name, entry);
+	}
+
+	pos = next;
+    }
+
+    Ok(res)
+}
+
+fn read_zip(file: &std::fs::File, len: u64) -> std::io::Result<Index> {
+    let tail_len = len.min(22 + 65535);
+    let tail = read_at(file, len - tail_len, tail_len)?;
+
+    let eocd = tail.windows(4).rposition(|w| w == b"PK\x05\x06")
+	.ok_or_else(|| bad_data("missing end of central directory"))?;
+
+    let mut count = le::<2>(&tail, eocd + 10)?;
+    let mut cd_size = le::<4>(&tail, eocd + 12)?;
+    let mut cd_offs = le::<4>(&tail, eocd + 16)?;
+
+    if eocd >= 20 && &tail[eocd - 20..eocd - 16] == b"PK\x06\x07" {
+	// zip64
+	let z64 = read_at(file, le::<8>(&tail, eocd - 20 + 8)?, 56)?;
+
+	if &z64[0..4] != b"PK\x06\x06" {
+	    return Err(bad_data("bad zip64 end of central directory"));
+	}
+
+	count = le::<8>(&z64, 32)?;
+	cd_size = le::<8>(&z64, 40)?;
+	cd_offs = le::<8>(&z64, 48)?;
+    }
+
+    let cd = read_at(file, cd_offs, cd_size)?;
+    let mut res = Index::new();
+    let mut pos = 0;
+
+    for _ in 0..count {
+	if cd.get(pos..pos + 4) != Some(b"PK\x01\x02") {
+	    return Err(bad_data("bad central directory header"));
+	}
+
+	let flags = le::<2>(&cd, pos + 8)?;
+	let method = le::<2>(&cd, pos + 10)?;
+	let crc = le::<4>(&cd, pos + 16)? as u32;
+	let mut csize = le::<4>(&cd, pos + 20)?;
+	let mut size = le::<4>(&cd, pos + 24)?;
+	let name_len = le::<2>(&cd, pos + 28)? as usize;
+	let extra_len = le::<2>(&cd, pos + 30)? as usize;
+	let comment_len = le::<2>(&cd, pos + 32)? as usize;
+	let mut offset = le::<4>(&cd, pos + 42)?;
+
+	let name = cd.get(pos + 46..pos + 46 + name_len)
+	    .ok_or_else(|| bad_data("truncated header"))?;
+	let mut extra = cd.get(pos + 46 + name_len..pos + 46 + name_len + extra_len)
+	    .ok_or_else(|| bad_data("truncated header"))?;
+
+	while extra.len() >= 4 {
+	    let id = le::<2>(extra, 0)?;
+	    let sz = le::<2>(extra, 2)? as usize;
+	    let data = extra.get(4..4 + sz).ok_or_else(|| bad_data("truncated header"))?;
+
+	    if id == 0x0001 {
+		// zip64 extended information; only overflowed values are present
+		let mut p = 0;
+
+		for v in [&mut size, &mut csize, &mut offset] {
+		    if *v == 0xffff_ffff {
+			*v = le::<8>(data, p)?;
+			p += 8;
+		    }
+		}
+	    }
+
+	    extra = &extra[4 + sz..];
+	}
+
+	pos += 46 + name_len + extra_len + comment_len;
+
+	let method = match method {
+	    0	=> Method::Stored,
+	    8	=> Method::Deflate,
+	    _	=> {
+		debug!("unsupported compression method {} of {:?}", method, OsStr::from_bytes(name));
+		continue;
+	    }
+	};
+
+	// directories and encrypted members
+	if name.ends_with(b"/") || flags & 1 != 0 {
+	    continue;
+	}
+
+	if let Some(name) = member_name(name) {
+	    res.insert(name, Entry {
+		offset:	offset,
+		size:	size,
+		csize:	csize,
+		method:	method,
+		crc:	Some(crc),
+	    });
+	}
+    }
+
+    Ok(res)
+}
+
+/// Returns the offset of the data which follows the local header at `pos`
+fn zip_data_offset(file: &std::fs::File, pos: u64) -> std::io::Result<u64> {
+    let hdr = read_at(file, pos, 30)?;
+
+    if &hdr[0..4] != b"PK\x03\x04" {
+	return Err(bad_data("bad local header"));
+    }
+
+    Ok(pos + 30 + le::<2>(&hdr, 26)? + le::<2>(&hdr, 28)?)
+}
+
+fn inflate(mut file: std::fs::File, entry: &Entry) -> std::io::Result<Vec<u8>> {
+    if entry.size > MAX_INFLATE_SIZE {
+	return Err(bad_data("compressed member too large"));
+    }
+
+    file.seek(std::io::SeekFrom::Start(entry.offset))?;
+
+    let mut res = Vec::with_capacity(entry.size as usize);
+    let mut crc = flate2::Crc::new();
+
+    flate2::read::DeflateDecoder::new(file.take(entry.csize))
+	.take(entry.size + 1)
+	.read_to_end(&mut res)?;
+
+    crc.update(&res);
+
+    if res.len() as u64 != entry.size {
+	return Err(bad_data("size mismatch of compressed member"));
+    }
+
+    if entry.crc.is_some_and(|c| c != crc.sum()) {
+	return Err(bad_data("crc mismatch of compressed member"));
+    }
+
+    Ok(res)
+}
+
+/// (mtime, mtime_nsec, size) of the archive when the index was read, and
+/// the index
+type CachedIndex = ([i64; 3], Arc<Index>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ArchiveId {
+    dev:	u64,
+    ino:	u64,
+}
+
+/// Cache of member indices of archives.
+///
+/// Entries are keyed by device and inode of the archive and are revalidated
+/// by its modification time and size.
+struct IndexCache {
+    archives:	Mutex<HashMap<ArchiveId, CachedIndex>>,
+}
+
+lazy_static::lazy_static! {
+    static ref INDEX_CACHE: IndexCache = IndexCache {
+	archives:	Mutex::new(HashMap::new()),
+    };
+}
+
+impl IndexCache {
+    /// Returns the index of `file`; it is read on the blocking thread pool
+    /// when it is not cached or outdated
+    async fn get(&self, kind: Kind, file: &std::fs::File) -> std::io::Result<Arc<Index>> {
+	use std::os::unix::fs::MetadataExt;
+
+	let meta = file.metadata()?;
+
+	let id = ArchiveId {
+	    dev:	meta.dev(),
+	    ino:	meta.ino(),
+	};
+
+	let stamp = [ meta.mtime(), meta.mtime_nsec(), meta.size() as i64 ];
+
+	match self.archives.lock().unwrap().get(&id) {
+	    Some((s, idx)) if *s == stamp	=> return Ok(idx.clone()),
+	    _					=> {},
+	}
+
+	trace!("reading index of {:?} archive", kind);
+
+	let file = file.try_clone()?;
+	let len = meta.size();
+
+	let idx = Arc::new(tokio::task::spawn_blocking(move || match kind {
+	    Kind::Tar	=> read_tar(&file, len),
+	    Kind::Cpio	=> read_cpio(&file, len),
+	    Kind::Zip	=> read_zip(&file, len),
+	    Kind::Iso	=> super::iso9660::read_iso(&file, len),
+	}).await.map_err(std::io::Error::other)??);
+
+	let mut archives = self.archives.lock().unwrap();
+
+	if archives.len() >= MAX_ARCHIVES {
+	    debug!("flushing archive index cache");
+	    archives.clear();
+	}
+
+	archives.insert(id, (stamp, idx.clone()));
+
+	Ok(idx)
+    }
+}
+
+/// Looks up `member` in `index`; when `icase` is set and there is no exact
+/// match, a unique case-insensitive match is used
+fn find_member(index: &Index, member: &Path, icase: bool) -> Option<Entry> {
+    if let Some(e) = index.get(member) {
+	return Some(e.clone());
+    }
+
+    if !icase {
+	return None;
+    }
+
+    let mut matches = index.iter()
+	.filter(|(n, _)| n.as_os_str().as_bytes().eq_ignore_ascii_case(member.as_os_str().as_bytes()));
+
+    match (matches.next(), matches.next()) {
+	(Some((_, e)), None)	=> Some(e.clone()),
+	_			=> None,
+    }
+}
+
+/// A member of a tar, cpio or zip archive or a file in an ISO 9660 image.
+///
+/// Stored members are read directly from the archive; compressed zip
+/// members are decompressed into memory.
+#[derive(Debug)]
+pub struct Archive {
+    file:	File,
+    kind:	Kind,
+    member:	PathBuf,
+    icase:	bool,
+    entry:	Option<Entry>,
+    data:	Option<Memory>,
+    pos:	u64,
+}
+
+impl Archive {
+    pub fn new(file: File, archive: &Path, member: &Path, icase: bool) -> Self {
+	Self {
+	    file:	file,
+	    kind:	Kind::from_path(archive).unwrap(),
+	    member:	member.into(),
+	    icase:	icase,
+	    entry:	None,
+	    data:	None,
+	    pos:	0,
+	}
+    }
+
+    /// Checks whether `member` exists in the archive
+    pub async fn exists(mut self) -> bool {
+	if self.file.open().is_err() {
+	    return false;
+	}
+
+	INDEX_CACHE.get(self.kind, self.file.as_std()).await
+	    .map(|idx| find_member(&idx, &self.member, self.icase).is_some())
+	    .unwrap_or(false)
+    }
+
+    pub async fn open(&mut self) -> Result<()> {
+	if self.entry.is_some() {
+	    return Err(Error::Internal("archive member already opened"));
+	}
+
+	self.file.open()?;
+
+	let file = self.file.as_std();
+	let index = INDEX_CACHE.get(self.kind, file).await?;
+
+	let mut entry = find_member(&index, &self.member, self.icase)
+	    .ok_or_else(|| Error::FileMissing(self.member.clone().into()))?;
+
+	if self.kind == Kind::Zip {
+	    entry.offset = zip_data_offset(file, entry.offset)?;
+	}
+
+	let len = file.metadata()?.len();
+
+	if entry.offset.checked_add(entry.csize).map_or(true, |end| end > len) {
+	    return Err(bad_data("member exceeds archive").into());
+	}
+
+	if entry.method == Method::Deflate {
+	    let file = file.try_clone()?;
+	    let tmp = entry.clone();
+
+	    let data = tokio::task::spawn_blocking(move || inflate(file, &tmp)).await
+		.map_err(|_| Error::Internal("failed to decompress archive member"))??;
+
+	    self.data = Some(data.into());
+	}
+
+	self.entry = Some(entry);
+
+	Ok(())
+    }
+
+    fn entry(&self) -> &Entry {
+	self.entry.as_ref().unwrap()
+    }
+
+    pub fn get_size(&self) -> Option<u64> {
+	Some(self.entry().size)
+    }
+
+    pub async fn read<'a>(&mut self, buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8]>
+    {
+	if let Some(data) = &mut self.data {
+	    return data.read(buf).await;
+	}
+
+	let entry = self.entry().clone();
+	let len = (buf.len() as u64).min(entry.size - self.pos) as usize;
+	let buf = unsafe { buf.assume_init() };
+	let buf = &mut buf[..len];
+
+	self.file.as_std().read_exact_at(buf, entry.offset + self.pos)?;
+	self.pos += len as u64;
+
+	Ok(buf)
+    }
+
+    pub fn is_eof(&self) -> bool
+    {
+	match &self.data {
+	    Some(data)	=> data.is_eof(),
+	    None	=> self.pos == self.entry().size,
+	}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn tar_header(name: &str, typ: u8, size: usize, link: &str) -> [u8; 512] {
+	let mut hdr = [0u8; 512];
+
+	hdr[..name.len()].copy_from_slice(name.as_bytes());
+	hdr[157..157 + link.len()].copy_from_slice(link.as_bytes());
+	hdr[100..107].copy_from_slice(b"0000644");
+	hdr[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+	hdr[156] = typ;
+	hdr[257..263].copy_from_slice(b"ustar\0");
+	hdr[263..265].copy_from_slice(b"00");
+
+	let sum = hdr.iter().map(|c| *c as u32).sum::<u32>() + 8 * b' ' as u32;
+
+	hdr[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+	hdr[155] = b' ';
+	hdr
+    }
+
+    fn tar_entry(out: &mut Vec<u8>, name: &str, typ: u8, data: &[u8], link: &str) {
+	out.extend(tar_header(name, typ, data.len(), link));
+	out.extend(data);
+	out.resize(out.len().next_multiple_of(512), 0);
+    }
+
+    fn cpio_entry(out: &mut Vec<u8>, ino: u32, name: &str, mode: u32, nlink: u32, data: &[u8]) {
+	let namesize = name.len() + 1;
+
+	write!(out, "070701{ino:08x}{mode:08x}{:08x}{:08x}{nlink:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{namesize:08x}{:08x}",
+	       0, 0, 0, data.len(), 0, 0, 0, 0, 0).unwrap();
+	out.extend(name.as_bytes());
+	out.push(0);
+	out.resize(out.len().next_multiple_of(4), 0);
+	out.extend(data);
+	out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    /// Creates a zip archive with a stored and a deflated member
+    fn zip_archive(members: &[(&str, &[u8], bool)]) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut cd = Vec::new();
+
+	for (name, data, compress) in members {
+	    let mut crc = flate2::Crc::new();
+
+	    crc.update(data);
+
+	    let (method, payload) = match compress {
+		true	=> {
+		    let mut enc = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+
+		    enc.write_all(data).unwrap();
+		    (8u16, enc.finish().unwrap())
+		},
+		false	=> (0u16, data.to_vec()),
+	    };
+
+	    let offset = out.len() as u32;
+	    let common = |v: &mut Vec<u8>| {
+		v.extend(20u16.to_le_bytes());		// version needed
+		v.extend(0u16.to_le_bytes());		// flags
+		v.extend(method.to_le_bytes());
+		v.extend([0u8; 4]);			// time, date
+		v.extend(crc.sum().to_le_bytes());
+		v.extend((payload.len() as u32).to_le_bytes());
+		v.extend((data.len() as u32).to_le_bytes());
+		v.extend((name.len() as u16).to_le_bytes());
+		v.extend(0u16.to_le_bytes());		// extra length
+	    };
+
+	    out.extend(b"PK\x03\x04");
+	    common(&mut out);
+	    out.extend(name.as_bytes());
+	    out.extend(&payload);
+
+	    cd.extend(b"PK\x01\x02");
+	    cd.extend(20u16.to_le_bytes());		// version made by
+	    common(&mut cd);
+	    cd.extend([0u8; 6]);			// comment, disk, attributes
+	    cd.extend([0u8; 4]);			// external attributes
+	    cd.extend(offset.to_le_bytes());
+	    cd.extend(name.as_bytes());
+	}
+
+	let cd_offs = out.len() as u32;
+
+	out.extend(&cd);
+	out.extend(b"PK\x05\x06");
+	out.extend([0u8; 4]);
+	out.extend((members.len() as u16).to_le_bytes());
+	out.extend((members.len() as u16).to_le_bytes());
+	out.extend((cd.len() as u32).to_le_bytes());
+	out.extend(cd_offs.to_le_bytes());
+	out.extend(0u16.to_le_bytes());
+
+	out
+    }
+
+    async fn read_member(root: &Path, archive: &str, member: &str) -> Result<Vec<u8>> {
+	let file = File::new(root, Path::new(archive), false);
+	let mut a = Archive::new(file, Path::new(archive), Path::new(member), false);
+	let mut res = Vec::new();
+
+	a.open().await?;
+
+	let size = a.get_size().unwrap();
+
+	while !a.is_eof() {
+	    let mut buf = vec![MaybeUninit::new(0); 100];
+
+	    res.extend_from_slice(a.read(&mut buf).await?);
+	}
+
+	assert_eq!(res.len() as u64, size);
+
+	Ok(res)
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+	use tempfile::TempDir;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path();
+
+	let kernel: Vec<u8> = (0..3000_u32).map(|v| (v * 7) as u8).collect();
+	let long_name = format!("boot/{}/initrd", "x".repeat(120));
+
+	let mut tar = Vec::new();
+
+	tar_entry(&mut tar, "./boot/", b'5', b"", "");
+	tar_entry(&mut tar, "./boot/vmlinuz", b'0', &kernel, "");
+	tar_entry(&mut tar, "././@LongLink", b'L', format!("{long_name}\0").as_bytes(), "");
+	tar_entry(&mut tar, "ignored", b'0', b"initrd", "");
+	tar_entry(&mut tar, "boot/link", b'1', b"", "boot/vmlinuz");
+	tar_entry(&mut tar, "../escape", b'0', b"escape", "");
+	tar.extend([0u8; 1024]);
+
+	let mut cpio = Vec::new();
+
+	cpio_entry(&mut cpio, 1, "boot", 0o040755, 2, b"");
+	cpio_entry(&mut cpio, 2, "boot/a", 0o100644, 2, b"");
+	cpio_entry(&mut cpio, 2, "boot/b", 0o100644, 2, b"hard linked");
+	cpio_entry(&mut cpio, 3, "boot/vmlinuz", 0o100644, 1, &kernel);
+	cpio_entry(&mut cpio, 0, "TRAILER!!!", 0, 1, b"");
+
+	let zip = zip_archive(&[
+	    ("boot/",		b"",		false),
+	    ("boot/vmlinuz",	&kernel,	true),
+	    ("boot/stored",	b"stored data",	false),
+	]);
+
+	std::fs::write(tmp_path.join("bundle.tar"), tar).unwrap();
+	std::fs::write(tmp_path.join("bundle.cpio"), cpio).unwrap();
+	std::fs::write(tmp_path.join("bundle.zip"), zip).unwrap();
+
+	assert_eq!(read_member(tmp_path, "bundle.tar", "boot/vmlinuz").await.unwrap(), kernel);
+	assert_eq!(read_member(tmp_path, "bundle.tar", &long_name).await.unwrap(), b"initrd");
+	assert_eq!(read_member(tmp_path, "bundle.tar", "boot/link").await.unwrap(), kernel);
+	assert!(matches!(read_member(tmp_path, "bundle.tar", "boot").await, Err(Error::FileMissing(_))));
+	assert!(matches!(read_member(tmp_path, "bundle.tar", "escape").await, Err(Error::FileMissing(_))));
+
+	assert_eq!(read_member(tmp_path, "bundle.cpio", "boot/vmlinuz").await.unwrap(), kernel);
+	assert_eq!(read_member(tmp_path, "bundle.cpio", "boot/a").await.unwrap(), b"hard linked");
+	assert_eq!(read_member(tmp_path, "bundle.cpio", "boot/b").await.unwrap(), b"hard linked");
+
+	assert_eq!(read_member(tmp_path, "bundle.zip", "boot/vmlinuz").await.unwrap(), kernel);
+	assert_eq!(read_member(tmp_path, "bundle.zip", "boot/stored").await.unwrap(), b"stored data");
+	assert!(matches!(read_member(tmp_path, "bundle.zip", "boot/missing").await, Err(Error::FileMissing(_))));
+    }
+
+    #[test]
+    fn test_pax() {
+	let pax = PaxInfo::parse(b"28 path=boot/some/long/name\n12 size=123\n20 mtime=1700000000\n").unwrap();
+
+	assert_eq!(pax.path.as_deref(), Some(&b"boot/some/long/name"[..]));
+	assert_eq!(pax.size, Some(123));
+	assert!(PaxInfo::parse(b"99 path=x\n").is_err());
+    }
+
+    #[test]
+    fn test_bad_tar_size() {
+	let mut tmp = tempfile::tempfile().unwrap();
+	let mut hdr = tar_header("a", b'0', 0, "");
+
+	// base-256 encoded size which overflows the member offset
+	hdr[124] = 0x80;
+	hdr[128..136].copy_from_slice(&(u64::MAX - 100).to_be_bytes());
+	hdr[148..156].copy_from_slice(b"        ");
+
+	let sum = hdr.iter().map(|c| *c as u32).sum::<u32>();
+
+	hdr[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+
+	tmp.write_all(&hdr).unwrap();
+	tmp.write_all(&[0u8; 1024]).unwrap();
+
+	assert!(read_tar(&tmp, 1536).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::mem::MaybeUninit;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use crate::{ Error, Result };
+use crate::util::Client;
+
+/// A data source which can be plugged into the server.
+///
+/// The methods are called in the same order as for the builtin fetchers:
+/// `open()` once, then `get_size()` and a sequence of `read()` (or
+/// `read_mmap()` when `is_mmaped()` returns true) calls until `is_eof()`
+/// returns true.
+#[async_trait::async_trait]
+pub trait Backend: Send + Sync + std::fmt::Debug {
+    /// Opens the object; errors like [`Error::FileMissing`] are reported
+    /// to the client
+    async fn open(&mut self) -> Result<()>;
+
+    /// Returns the size of the object; `None` omits the `tsize` option
+    async fn get_size(&self) -> Option<u64>;
+
+    /// Fills `buf` completely unless the end of the object is reached
+    async fn read<'a>(&mut self, buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8]>;
+
+    /// Returns whether data can be obtained by `read_mmap()` without
+    /// copying it
+    fn is_mmaped(&self) -> bool {
+	false
+    }
+
+    /// Returns up to `cnt` bytes; only called when `is_mmaped()` is true
+    fn read_mmap(&mut self, _cnt: usize) -> Result<&[u8]> {
+	Err(Error::NotImplemented)
+    }
+
+    fn is_eof(&self) -> bool;
+}
+
+/// What a backend has been selected for
+#[derive(Clone, Copy, Debug)]
+pub enum Target<'a> {
+    /// the normalized request path
+    Path(&'a Path),
+    /// the uri given by a symlink, the fallback or a rewrite rule
+    Uri(&'a url::Url),
+}
+
+/// Creates [`Backend`] objects for single requests
+pub trait BackendFactory: Send + Sync {
+    fn create(&self, target: Target<'_>, client: Option<&Client>) -> Result<Box<dyn Backend>>;
+}
+
+impl <F> BackendFactory for F
+where
+    F: Fn(Target<'_>, Option<&Client>) -> Result<Box<dyn Backend>> + Send + Sync,
+{
+    fn create(&self, target: Target<'_>, client: Option<&Client>) -> Result<Box<dyn Backend>> {
+	self(target, client)
+    }
+}
+
+#[derive(Clone)]
+enum Rule {
+    Scheme(String),
+    Prefix(PathBuf),
+}
+
+/// Registered backends; the first matching rule wins
+#[derive(Clone, Default)]
+pub struct Backends(Vec<(Rule, Arc<dyn BackendFactory>)>);
+
+impl std::fmt::Debug for Backends {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	f.debug_list()
+	    .entries(self.0.iter().map(|(r, _)| match r {
+		Rule::Scheme(s)	=> format!("{s}://"),
+		Rule::Prefix(p)	=> format!("/{}", p.display()),
+	    }))
+	    .finish()
+    }
+}
+
+impl Backends {
+    /// Uses `factory` for uris with the given scheme (e.g. `artifact`)
+    pub fn register_scheme<F>(&mut self, scheme: &str, factory: F)
+    where
+	F: BackendFactory + 'static,
+    {
+	self.0.push((Rule::Scheme(scheme.to_ascii_lowercase()), Arc::new(factory)));
+    }
+
+    /// Uses `factory` for requests below `prefix`; the prefix is matched
+    /// against whole path components of the normalized request path.
+    pub fn register_path<F>(&mut self, prefix: &Path, factory: F) -> Result<()>
+    where
+	F: BackendFactory + 'static,
+    {
+	let prefix = super::normalize_path(prefix)?;
+
+	self.0.push((Rule::Prefix(prefix), Arc::new(factory)));
+
+	Ok(())
+    }
+
+    fn find(&self, target: Target<'_>) -> Option<&dyn BackendFactory> {
+	self.0.iter()
+	    .find(|(rule, _)| match (rule, target) {
+		(Rule::Scheme(s), Target::Uri(u))	=> s == u.scheme(),
+		(Rule::Prefix(p), Target::Path(path))	=> path.starts_with(p),
+		_					=> false,
+	    })
+	    .map(|(_, f)| f.as_ref())
+    }
+
+    /// Creates a backend for `target` when a rule matches
+    pub fn create(&self, target: Target<'_>, client: Option<&Client>) -> Option<Result<Box<dyn Backend>>> {
+	self.find(target)
+	    .map(|f| {
+		debug!("using registered backend for {:?}", target);
+		f.create(target, client)
+	    })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Dummy(super::super::Memory);
+
+    #[async_trait::async_trait]
+    impl Backend for Dummy {
+	async fn open(&mut self) -> Result<()> {
+	    self.0.open()
+	}
+
+	async fn get_size(&self) -> Option<u64> {
+	    self.0.get_size()
+	}
+
+	async fn read<'a>(&mut self, buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8]> {
+	    self.0.read(buf).await
+	}
+
+	fn is_eof(&self) -> bool {
+	    self.0.is_eof()
+	}
+    }
+
+    fn dummy(data: &'static str) -> impl BackendFactory {
+	move |_: Target<'_>, _: Option<&Client>| -> Result<Box<dyn Backend>> {
+	    Ok(Box::new(Dummy(super::super::Memory::new(data.as_bytes()))))
+	}
+    }
+
+    #[test]
+    fn test_rules() {
+	let mut backends = Backends::default();
+
+	backends.register_scheme("Artifact", dummy("a"));
+	backends.register_path(Path::new("/store/"), dummy("b")).unwrap();
+
+	let uri = |s: &str| s.parse::<url::Url>().unwrap();
+
+	assert!(backends.find(Target::Uri(&uri("artifact://host/x"))).is_some());
+	assert!(backends.find(Target::Uri(&uri("http://host/x"))).is_none());
+	assert!(backends.find(Target::Path(Path::new("store/x"))).is_some());
+	assert!(backends.find(Target::Path(Path::new("store"))).is_some());
+	assert!(backends.find(Target::Path(Path::new("storex/x"))).is_none());
+	assert!(backends.find(Target::Path(Path::new("artifact/x"))).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetcher() {
+	let mut backends = Backends::default();
+
+	backends.register_path(Path::new("store"), dummy("hello")).unwrap();
+
+	let backend = backends.create(Target::Path(Path::new("store/x")), None).unwrap().unwrap();
+	let mut fetcher = super::super::Fetcher::new_backend(backend);
+	let mut buf = [MaybeUninit::uninit(); 16];
+
+	fetcher.open().await.unwrap();
+
+	assert_eq!(fetcher.get_size().await, Some(5));
+	assert!(!fetcher.is_mmaped());
+	assert_eq!(fetcher.read(&mut buf).await.unwrap(), b"hello");
+	assert!(fetcher.is_eof());
+    }
+
+    #[tokio::test]
+    async fn test_scheme() {
+	let tmp_dir = tempfile::TempDir::new().unwrap();
+	let mut backends = Backends::default();
+	let mut buf = [MaybeUninit::uninit(); 16];
+
+	// schemes may contain digits, '-' and '.'
+	backends.register_scheme("s3", dummy("bucket"));
+	backends.register_scheme("x-foo.v2", dummy("foo"));
+
+	std::os::unix::fs::symlink("s3://bucket/x", tmp_dir.path().join("obj")).unwrap();
+	std::os::unix::fs::symlink("x-foo.v2://host/y", tmp_dir.path().join("foo")).unwrap();
+
+	let config = crate::Config::new(tmp_dir.path()).backends(backends);
+	let builder = super::super::Builder::new(config.env());
+
+	for (name, data) in [("obj", b"bucket".as_slice()), ("foo", b"foo")] {
+	    let mut fetcher = builder.instanciate(Path::new(name)).await.unwrap();
+
+	    fetcher.open().await.unwrap();
+	    assert_eq!(fetcher.read(&mut buf).await.unwrap(), data);
+	}
+
+	assert!(builder.instanciate_uri("s3://bucket/z".as_ref()).is_ok());
+    }
+}
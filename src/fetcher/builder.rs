@@ -1,4 +1,6 @@
 use crate::{ Error, Result };
+use crate::tftp::Request;
+use crate::util::{ Beneath, Client };
 use regex::Regex;
 use std::ffi::{ OsString, OsStr };
 use std::path::{ PathBuf, Path };
@@ -6,14 +8,26 @@ use std::path::{ PathBuf, Path };
 use super::Fetcher;
 
 lazy_static::lazy_static! {
-    static ref URI_REGEX: Regex = Regex::new(r"^[a-z]+(\+[a-z]+)*://").unwrap();
+    // scheme as defined by RFC 3986; '+' separates modifiers like 'nocache'
+    static ref URI_REGEX: Regex = Regex::new(r"^[a-zA-Z][a-zA-Z0-9+.-]*://").unwrap();
+}
+
+/// Returns whether `s` looks like an uri (e.g. `http://...`) instead of a
+/// local path.
+pub fn is_uri<S: AsRef<OsStr>>(s: S) -> bool
+{
+    s.as_ref().to_str().map(|s| URI_REGEX.is_match(s)).unwrap_or(false)
 }
 
 pub struct Builder<'a> {
     env:	&'a crate::Environment,
+    client:	Option<&'a Client>,
+    request:	Option<&'a Request<'a>>,
 }
 
-fn normalize_path(p: &std::path::Path) -> Result<std::path::PathBuf>
+/// Returns the relative path for `p`; fails when `p` contains `..`
+/// components.
+pub fn normalize_path(p: &std::path::Path) -> Result<std::path::PathBuf>
 {
     let mut res = std::path::PathBuf::new();
 
@@ -34,29 +48,103 @@ fn normalize_path(p: &std::path::Path) -> Result<std::path::PathBuf>
     Ok(res)
 }
 
+/// Returns whether a component of `p` is a hidden file or directory
+fn has_dotfile(p: &Path) -> bool
+{
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Component as C;
+
+    p.components().any(|c| match c {
+	C::Normal(c)	=> c.as_bytes().first() == Some(&b'.'),
+	_		=> false,
+    })
+}
+
 #[derive(PartialEq, Debug)]
 enum LookupResult {
-    Path(PathBuf),
-    #[cfg(feature = "proxy")]
+    /// local file; given as root directory and path relative to it
+    Path(PathBuf, PathBuf),
+    /// file from the [`super::MemoryStore`]
+    Memory(std::sync::Arc<[u8]>),
+    /// template file which is rendered instead of the missing path
+    Template(PathBuf, PathBuf),
+    /// member of an archive; given as root directory, path of the archive
+    /// relative to it and the path within the archive
+    Archive(PathBuf, PathBuf, PathBuf),
+    /// compressed variant (`.zst`, `.gz`, `.xz`) of the missing path
+    Compressed(PathBuf, PathBuf),
+    /// program (and trailing path) given by an `exec://` link
+    Exec(OsString),
+    /// uri which is handled by a registered backend or the proxy
     Uri(url::Url),
 }
 
+/// Splits a local fallback prefix into the directory which confines the
+/// lookup and the remaining path prefix.
+fn split_fallback(fallback: &OsStr, path: &Path) -> (PathBuf, PathBuf)
+{
+    use std::os::unix::ffi::OsStrExt;
+
+    let raw = fallback.as_bytes();
+    let (dir, prefix) = match raw.iter().rposition(|c| *c == b'/') {
+	Some(0)		=> (&b"/"[..], &raw[1..]),
+	Some(pos)	=> (&raw[..pos], &raw[pos + 1..]),
+	None		=> (&b"."[..], raw),
+    };
+
+    let mut rel = OsStr::from_bytes(prefix).to_os_string();
+
+    rel.push(path.as_os_str());
+
+    (OsStr::from_bytes(dir).into(), rel.into())
+}
+
+/// Options of [`lookup_path`]
+#[derive(Clone, Copy, Default)]
+struct LookupOpts<'a> {
+    /// uri or local path prefix which is used for missing files
+    fallback:	Option<&'a OsStr>,
+    /// resolve filenames case-insensitively
+    icase:	bool,
+    /// render `*.tmpl` files for missing paths
+    templates:	bool,
+    /// serve compressed variants of missing paths
+    decompress:	bool,
+    store:	Option<&'a super::MemoryStore>,
+}
+
+impl <'a> LookupOpts<'a> {
+    fn new(env: &'a crate::Environment) -> Self {
+	Self {
+	    fallback:	env.fallback_uri.as_deref(),
+	    icase:	env.case_insensitive,
+	    templates:	env.templates,
+	    decompress:	env.decompress,
+	    store:	Some(&env.store),
+	}
+    }
+}
+
 //#[instrument(level = "trace", skip_all, ret)]
-fn lookup_path<A, B, C>(root: A, p: B, fallback: Option<C>, allow_uri: bool) -> Result<LookupResult>
+fn lookup_path<A, B>(root: A, p: B, opts: &LookupOpts<'_>) -> Result<LookupResult>
 where
     A: AsRef<Path>,
     B: AsRef<Path>,
-    C: AsRef<OsStr>,
 {
     use std::os::unix::ffi::OsStrExt;
 
-    let mut uri: Option<OsString> = None;
-    let mut dir = root.as_ref().to_path_buf();
+    let LookupOpts { fallback, icase, templates, decompress, store } = *opts;
+
     let path_norm = normalize_path(p.as_ref())?;
-    let mut is_dangling = false;
 
-    // avoid 'unused_variable' when proxy support is disabled
-    let _ = allow_uri;
+    if let Some(data) = store.and_then(|s| s.get(&path_norm)) {
+	return Ok(LookupResult::Memory(data));
+    }
+
+    let root_dir = Beneath::open(root.as_ref())?;
+    let mut uri: Option<OsString> = None;
+    let mut dir = PathBuf::new();
+    let mut is_dangling = false;
 
     for c in path_norm.components() {
 	uri = match uri {
@@ -78,15 +166,22 @@ where
 	    },
 
 	    None		=> {
-		let p = dir.join(c);
-		let meta = p.symlink_metadata();
+		let mut p = dir.join(c);
+		let mut is_symlink = root_dir.is_symlink(&p);
 
-		is_dangling = meta.is_err();
+		if icase && is_symlink.is_err() {
+		    if let Ok(Some(name)) = super::icase::lookup(&root_dir, &dir, c.as_os_str()) {
+			p = dir.join(name);
+			is_symlink = root_dir.is_symlink(&p);
+		    }
+		}
 
-		let uri_raw = if is_dangling || !meta.unwrap().is_symlink() {
+		is_dangling = is_symlink.is_err();
+
+		let uri_raw = if is_dangling || !is_symlink.unwrap() {
 		    None
 		} else {
-		    let data = std::fs::read_link(&p)?.into_os_string();
+		    let data = root_dir.read_link(&p)?;
 		    let data_str = data.to_str();
 
 		    match data_str {
@@ -104,26 +199,69 @@ where
 	};
     }
 
+    let mut root = root_dir.root().to_path_buf();
+
+    if uri.is_none() && !root_dir.exists(&dir) {
+	let archive = dir.ancestors()
+	    .skip(1)
+	    .find(|a| super::archive::is_archive(a) && root_dir.is_file(a));
+
+	if let Some(archive) = archive {
+	    let member = dir.strip_prefix(archive).unwrap().to_path_buf();
+
+	    return Ok(LookupResult::Archive(root, archive.into(), member));
+	}
+    }
+
+    if uri.is_none() && decompress && !root_dir.exists(&dir) {
+	let compressed = super::decompress::SUFFIXES.iter()
+	    .map(|(suffix, _)| {
+		let mut tmp = dir.clone().into_os_string();
+
+		tmp.push(suffix);
+		PathBuf::from(tmp)
+	    })
+	    .find(|p| root_dir.is_file(p));
+
+	if let Some(compressed) = compressed {
+	    return Ok(LookupResult::Compressed(root, compressed));
+	}
+    }
+
+    if uri.is_none() && templates && !root_dir.exists(&dir) {
+	let mut tmpl = dir.clone().into_os_string();
+
+	tmpl.push(super::template::SUFFIX);
+
+	if root_dir.exists(Path::new(&tmpl)) {
+	    return Ok(LookupResult::Template(root, tmpl.into()));
+	}
+    }
+
     #[allow(clippy::unnecessary_unwrap)]
-    if uri.is_none() && fallback.is_some() && !dir.exists() {
+    if uri.is_none() && fallback.is_some() && !root_dir.exists(&dir) {
 	let fallback = fallback.unwrap();
-	let mut tmp: OsString = fallback.as_ref().to_os_string();
+	let mut tmp: OsString = fallback.to_os_string();
 
 	tmp.push(path_norm.as_os_str());
 
-	match fallback.as_ref().to_str() {
+	match fallback.to_str() {
 	    Some(d) if URI_REGEX.is_match(d)	=> uri = Some(tmp),
-	    _					=> dir = tmp.into(),
+	    _					=> {
+		(root, dir) = split_fallback(fallback, &path_norm);
+	    }
 	}
     }
 
+    if let Some(spec) = uri.as_ref().and_then(|u| u.as_bytes().strip_prefix(super::exec::SCHEME.as_bytes())) {
+	return Ok(LookupResult::Exec(OsStr::from_bytes(spec).into()));
+    }
+
     match uri.map(|u| u.to_str().map(|u| u.parse::<url::Url>())) {
-	None				=> Ok(LookupResult::Path(dir)),
+	None				=> Ok(LookupResult::Path(root, dir)),
 	Some(None)			=> Err(Error::StringConversion),
 	Some(Some(Err(_)))		=> Err(Error::UriParse),
-	#[cfg(feature = "proxy")]
-	Some(Some(Ok(u))) if allow_uri	=> Ok(LookupResult::Uri(u)),
-	Some(Some(Ok(_)))		=> Err(Error::NotImplemented),
+	Some(Some(Ok(u)))		=> Ok(LookupResult::Uri(u)),
     }
 }
 
@@ -131,15 +269,161 @@ impl <'a> Builder<'a> {
     pub fn new(env: &'a crate::Environment) -> Self {
 	Self {
 	    env:	env,
+	    client:	None,
+	    request:	None,
+	}
+    }
+
+    /// Sets the client for which files are looked up
+    pub fn client(mut self, client: &'a Client) -> Self {
+	self.client = Some(client);
+	self
+    }
+
+    /// Sets the request whose options are available in templates
+    pub fn request(mut self, request: &'a Request<'a>) -> Self {
+	self.request = Some(request);
+	self
+    }
+
+    fn lookup(&self, p: &Path) -> Result<LookupResult> {
+	lookup_path(&self.env.dir, p, &LookupOpts::new(self.env))
+    }
+
+    /// Resolves a virtual name configured by `--pxe-search` to the first
+    /// existing PXELINUX candidate.  Candidates are looked up locally; the
+    /// fallback is applied to the virtual name when none of them exists.
+    async fn lookup_pxe(&self, p: &Path, client: &Client) -> Result<LookupResult> {
+	let opts = LookupOpts {
+	    fallback:	None,
+	    ..LookupOpts::new(self.env)
+	};
+
+	for c in super::pxe::candidates(p, client) {
+	    let res = lookup_path(&self.env.dir, &c, &opts)?;
+
+	    let exists = match &res {
+		LookupResult::Path(root, rel)	=> Beneath::open(root)
+		    .map(|r| r.exists(rel))
+		    .unwrap_or(false),
+		LookupResult::Memory(_)		=> true,
+		LookupResult::Template(..)	=> true,
+		LookupResult::Compressed(..)	=> true,
+		LookupResult::Archive(root, a, m)	=> super::Archive::new(
+		    super::File::new(root, a, self.env.world_readable), a, m, self.env.case_insensitive)
+		    .exists().await,
+		// without fallback, these come from explicit symlinks
+		LookupResult::Exec(_)		=> true,
+		LookupResult::Uri(_)		=> true,
+	    };
+
+	    if exists {
+		debug!("resolved {:?} to {:?}", p, c);
+		return Ok(res);
+	    }
+
+	    trace!("candidate {:?} does not exist", c);
+	}
+
+	match self.env.fallback_uri {
+	    Some(_)	=> self.lookup(p),
+	    None	=> Err(Error::FileMissing(p.into())),
 	}
     }
 
     #[instrument(level = "trace", skip(self), ret)]
-    pub fn instanciate(&'a self, p: &std::path::Path) -> Result<super::Fetcher> {
-	match lookup_path(&self.env.dir, p, self.env.fallback_uri.as_ref(), self.env.allow_uri())? {
-	    LookupResult::Path(p)	=> Ok(Fetcher::new_file(&p)),
+    pub async fn instanciate(&'a self, p: &std::path::Path) -> Result<super::Fetcher> {
+	if !self.env.allow_dotfiles && has_dotfile(p) {
+	    return Err(Error::AccessViolation("access to dotfiles is not allowed"));
+	}
+
+	let path = normalize_path(p)?;
+
+	if let Some(backend) = self.env.backends.create(super::backend::Target::Path(&path), self.client) {
+	    return Ok(Fetcher::new_backend(backend?));
+	}
+
+	let res = match self.client {
+	    Some(client) if self.env.pxe_search.contains(&path)	=>
+		self.lookup_pxe(p, client).await?,
+	    _							=>
+		self.lookup(p)?,
+	};
+
+	match res {
+	    LookupResult::Path(root, p)		=>
+		Ok(Fetcher::new_file(&root, &p, self.env.world_readable, self.env.file_cache.as_ref(),
+				     self.env.snapshot_dir().as_deref())),
+	    LookupResult::Memory(data)		=> Ok(Fetcher::new_stored(data)),
+	    LookupResult::Template(root, p)	=> {
+		let vars = super::template::Vars::new(self.client, self.request);
+
+		Ok(Fetcher::new_template(&root, &p, self.env.world_readable, vars))
+	    },
+	    LookupResult::Compressed(root, p)	=>
+		Ok(Fetcher::new_compressed(&root, &p, self.env.world_readable)),
+	    LookupResult::Archive(root, a, m)	=>
+		Ok(Fetcher::new_archive(&root, &a, &m, self.env.world_readable, self.env.case_insensitive)),
+	    LookupResult::Exec(spec)		=> self.instanciate_exec(&spec),
+	    LookupResult::Uri(uri)		=> self.instanciate_url(&uri),
+	}
+    }
+
+    /// Creates a fetcher which runs the program in `--exec-dir` given by
+    /// the first component of `spec`; the remaining path is passed in
+    /// `TFTP_PATH_INFO`
+    fn instanciate_exec(&self, spec: &OsStr) -> Result<super::Fetcher> {
+	use std::os::unix::ffi::OsStrExt;
+
+	let Some(dir) = &self.env.exec_dir else {
+	    return Err(Error::AccessViolation("program execution is disabled"));
+	};
+
+	let spec = spec.as_bytes();
+	let (name, path_info) = match spec.iter().position(|c| *c == b'/') {
+	    Some(pos)	=> (&spec[..pos], &spec[pos + 1..]),
+	    None	=> (spec, &b""[..]),
+	};
+
+	if name.is_empty() || name.first() == Some(&b'.') {
+	    return Err(Error::InvalidPathName);
+	}
+
+	let vars = super::template::Vars::new(self.client, self.request);
+
+	Ok(Fetcher::new_exec(dir.join(OsStr::from_bytes(name)), OsStr::from_bytes(path_info).into(),
+			     vars, self.env.exec_limits.clone()))
+    }
+
+    /// Creates a fetcher for an uri which was produced by a rewrite rule
+    #[instrument(level = "trace", skip(self), ret)]
+    pub fn instanciate_uri(&'a self, uri: &OsStr) -> Result<super::Fetcher> {
+	let uri = uri.to_str().ok_or(Error::StringConversion)?;
+
+	if let Some(spec) = uri.strip_prefix(super::exec::SCHEME) {
+	    return self.instanciate_exec(spec.as_ref());
+	}
+
+	if !URI_REGEX.is_match(uri) {
+	    return Err(Error::UriParse);
+	}
+
+	let uri = uri.parse::<url::Url>().map_err(|_| Error::UriParse)?;
+
+	self.instanciate_url(&uri)
+    }
+
+    /// Creates a fetcher for `uri` by a backend which is registered for
+    /// its scheme or by the proxy
+    fn instanciate_url(&self, uri: &url::Url) -> Result<super::Fetcher> {
+	if let Some(backend) = self.env.backends.create(super::backend::Target::Uri(uri), self.client) {
+	    return Ok(Fetcher::new_backend(backend?));
+	}
+
+	match self.env.allow_uri() {
 	    #[cfg(feature = "proxy")]
-	    LookupResult::Uri(uri)	=> Ok(Fetcher::new_uri(&uri)),
+	    true	=> Ok(Fetcher::new_uri(uri)),
+	    _		=> Err(Error::NotImplemented),
 	}
     }
 }
@@ -180,6 +464,11 @@ mod test {
 	std::fs::create_dir(tmp_path.join("b")).unwrap();
 
 	std::fs::File::create(tmp_path.join("b/foo")).unwrap();
+	std::fs::File::create(tmp_path.join("b/foo.tmpl")).unwrap();
+	std::fs::File::create(tmp_path.join("b/menu.tmpl")).unwrap();
+	std::fs::File::create(tmp_path.join("b/bundle.tar")).unwrap();
+	std::fs::File::create(tmp_path.join("b/initrd.gz")).unwrap();
+	std::fs::File::create(tmp_path.join("b/initrd.zst")).unwrap();
 
 	symlink("http://test.example.com/foo",          tmp_path.join("a/link-0")).unwrap();
 	symlink("http://test.example.com/bar/",         tmp_path.join("a/link-1")).unwrap();
@@ -187,29 +476,235 @@ mod test {
 	symlink("https+nocache://test.example.com/foo", tmp_path.join("a/link-3")).unwrap();
 	symlink("https+nocache+nocompress://test.example.com/foo", tmp_path.join("a/link-4")).unwrap();
 	symlink("./http://test.example.com/foo",        tmp_path.join("a/nolink-0")).unwrap();
+	symlink("exec://inventory",                     tmp_path.join("a/exec")).unwrap();
 
-	let fb_none = None::<OsString>;
-	let _fb_some = Some::<OsString>("http://fb.example.com/redir/".into());
-
+	let none = LookupOpts::default();
+	let icase = LookupOpts { icase: true, ..none };
+	let templates = LookupOpts { templates: true, ..none };
+	let decompress = LookupOpts { decompress: true, ..none };
 
-	assert_eq!(lookup_path(tmp_path, "/b/foo", fb_none.clone(), true).unwrap(),
-		   LookupResult::Path(tmp_path.join("b/foo")));
+	assert_eq!(lookup_path(tmp_path, "/b/foo", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/foo".into()));
 
 	#[cfg(feature = "proxy")]
 	{
-	    assert_eq!(lookup_path(tmp_path, "/a/link-0", fb_none.clone(), true).unwrap(),
+	    assert_eq!(lookup_path(tmp_path, "/a/link-0", &none).unwrap(),
 		       LookupResult::Uri("http://test.example.com/foo".parse().unwrap()));
-	    assert_eq!(lookup_path(tmp_path, "/a/link-0/test", fb_none.clone(), true).unwrap(),
+	    assert_eq!(lookup_path(tmp_path, "/a/link-0/test", &none).unwrap(),
 		       LookupResult::Uri("http://test.example.com/foo/test".parse().unwrap()));
-	    assert_eq!(lookup_path(tmp_path, "/a/link-3/test", fb_none.clone(), true).unwrap(),
+	    assert_eq!(lookup_path(tmp_path, "/a/link-3/test", &none).unwrap(),
 		       LookupResult::Uri("https+nocache://test.example.com/foo/test".parse().unwrap()));
-	    assert_eq!(lookup_path(tmp_path, "/a/link-4/test", fb_none.clone(), true).unwrap(),
+	    assert_eq!(lookup_path(tmp_path, "/a/link-4/test", &none).unwrap(),
 		       LookupResult::Uri("https+nocache+nocompress://test.example.com/foo/test".parse().unwrap()));
 	}
 
-	assert_eq!(lookup_path(tmp_path, "/a/nolink-0", fb_none.clone(), true).unwrap(),
-		   LookupResult::Path(tmp_path.join("a/nolink-0")));
-	assert_eq!(lookup_path(tmp_path, "/a/nolink-0/file", fb_none.clone(), true).unwrap(),
-		   LookupResult::Path(tmp_path.join("a/nolink-0/file")));
+	assert_eq!(lookup_path(tmp_path, "/a/nolink-0", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "a/nolink-0".into()));
+	assert_eq!(lookup_path(tmp_path, "/a/nolink-0/file", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "a/nolink-0/file".into()));
+
+	// case insensitive lookup
+	assert_eq!(lookup_path(tmp_path, "/B/FOO", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "B/FOO".into()));
+	assert_eq!(lookup_path(tmp_path, "/B/FOO", &icase).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/foo".into()));
+	assert_eq!(lookup_path(tmp_path, "/B/FOO/bar", &icase).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/foo/bar".into()));
+	assert_eq!(lookup_path(tmp_path, "/B/missing", &icase).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/missing".into()));
+
+	#[cfg(feature = "proxy")]
+	assert_eq!(lookup_path(tmp_path, "/A/LINK-0/test", &icase).unwrap(),
+		   LookupResult::Uri("http://test.example.com/foo/test".parse().unwrap()));
+
+	assert_eq!(lookup_path(tmp_path, "/a/exec", &none).unwrap(),
+		   LookupResult::Exec("inventory".into()));
+	assert_eq!(lookup_path(tmp_path, "/a/exec/foo/bar", &none).unwrap(),
+		   LookupResult::Exec("inventory/foo/bar".into()));
+
+	assert_eq!(lookup_path(tmp_path, "/b/bundle.tar/boot/vmlinuz", &none).unwrap(),
+		   LookupResult::Archive(tmp_path.into(), "b/bundle.tar".into(), "boot/vmlinuz".into()));
+	assert_eq!(lookup_path(tmp_path, "/b/bundle.tar", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/bundle.tar".into()));
+	assert_eq!(lookup_path(tmp_path, "/b/foo.tar/boot", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/foo.tar/boot".into()));
+
+	// templates are used only for missing files
+	assert_eq!(lookup_path(tmp_path, "/b/menu", &templates).unwrap(),
+		   LookupResult::Template(tmp_path.into(), "b/menu.tmpl".into()));
+	assert_eq!(lookup_path(tmp_path, "/b/menu", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/menu".into()));
+	assert_eq!(lookup_path(tmp_path, "/b/foo", &templates).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/foo".into()));
+
+	// the memory store is consulted before the filesystem
+	let store = super::super::MemoryStore::new();
+
+	store.insert("b/foo", b"stored".as_slice(), None).unwrap();
+	store.insert("b/token", b"token".as_slice(), None).unwrap();
+
+	let stored = LookupOpts { store: Some(&store), ..none };
+
+	assert_eq!(lookup_path(tmp_path, "/b/foo", &stored).unwrap(),
+		   LookupResult::Memory(b"stored".as_slice().into()));
+	assert_eq!(lookup_path(tmp_path, "/b//token", &stored).unwrap(),
+		   LookupResult::Memory(b"token".as_slice().into()));
+	assert_eq!(lookup_path(tmp_path, "/b/token", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/token".into()));
+
+	// compressed variants are used only for missing files
+	assert_eq!(lookup_path(tmp_path, "/b/initrd", &decompress).unwrap(),
+		   LookupResult::Compressed(tmp_path.into(), "b/initrd.zst".into()));
+	assert_eq!(lookup_path(tmp_path, "/b/initrd", &none).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/initrd".into()));
+	assert_eq!(lookup_path(tmp_path, "/b/initrd.gz", &decompress).unwrap(),
+		   LookupResult::Path(tmp_path.into(), "b/initrd.gz".into()));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_pxe() {
+	use std::os::unix::fs::symlink;
+
+	async fn lookup(config: &crate::Config) -> Result<LookupResult> {
+	    let client = Client {
+		remote:	"192.168.1.10".parse().unwrap(),
+		local:	"192.168.1.1".parse().unwrap(),
+		mac:	None,
+	    };
+
+	    Builder::new(config.env()).lookup_pxe(Path::new("pxelinux.cfg/auto"), &client).await
+	}
+
+	let tmp_dir = tempfile::TempDir::new().unwrap();
+	let cfg = tmp_dir.path().join("pxelinux.cfg");
+
+	std::fs::create_dir(&cfg).unwrap();
+	std::fs::File::create(cfg.join("C0A8")).unwrap();
+	std::fs::File::create(cfg.join("default")).unwrap();
+
+	let config = crate::Config::new(tmp_dir.path())
+	    .fallback("http://fb.example.com/");
+
+	// the fallback does not hide local candidates
+	assert_eq!(lookup(&config).await.unwrap(),
+		   LookupResult::Path(tmp_dir.path().into(), "pxelinux.cfg/C0A8".into()));
+
+	symlink("exec://inventory", cfg.join("C0A8010")).unwrap();
+
+	assert_eq!(lookup(&config).await.unwrap(), LookupResult::Exec("inventory".into()));
+
+	std::fs::remove_file(cfg.join("C0A8010")).unwrap();
+	std::fs::remove_file(cfg.join("C0A8")).unwrap();
+	std::fs::remove_file(cfg.join("default")).unwrap();
+
+	// the fallback is applied to the virtual name
+	assert_eq!(lookup(&config).await.unwrap(),
+		   LookupResult::Uri("http://fb.example.com/pxelinux.cfg/auto".parse().unwrap()));
+
+	let config = crate::Config::new(tmp_dir.path());
+
+	assert!(matches!(lookup(&config).await, Err(Error::FileMissing(_))));
+    }
+
+    #[test]
+    fn test_dotfile() {
+	assert!(!has_dotfile(Path::new("/a/b/foo")));
+	assert!(!has_dotfile(Path::new("./a/b/foo")));
+	assert!(!has_dotfile(Path::new("a/b./foo.")));
+	assert!(has_dotfile(Path::new("/.a/b/foo")));
+	assert!(has_dotfile(Path::new("a/b/.foo")));
+	assert!(has_dotfile(Path::new("a/.cache/foo")));
+    }
+
+    #[test]
+    fn test_file_access() {
+	use tempfile::TempDir;
+	use std::os::unix::fs::{ symlink, PermissionsExt };
+	use super::super::File;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path();
+
+	std::fs::create_dir(tmp_path.join("dir")).unwrap();
+	std::fs::File::create(tmp_path.join("public")).unwrap();
+	std::fs::File::create(tmp_path.join("private")).unwrap();
+	std::fs::set_permissions(tmp_path.join("public"),
+				 std::fs::Permissions::from_mode(0o644)).unwrap();
+	std::fs::set_permissions(tmp_path.join("private"),
+				 std::fs::Permissions::from_mode(0o640)).unwrap();
+	nix::unistd::mkfifo(&tmp_path.join("fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+	symlink("fifo", tmp_path.join("fifo-link")).unwrap();
+
+	let open = |p: &str, world_readable| File::new(tmp_path, Path::new(p), world_readable).open();
+
+	assert!(open("public",  false).is_ok());
+	assert!(open("private", false).is_ok());
+	assert!(open("public",  true).is_ok());
+
+	assert!(matches!(open("private",   true),  Err(Error::AccessViolation(_))));
+	assert!(matches!(open("dir",       false), Err(Error::AccessViolation(_))));
+	assert!(matches!(open("fifo",      false), Err(Error::AccessViolation(_))));
+	assert!(matches!(open("fifo-link", false), Err(Error::AccessViolation(_))));
+    }
+
+    #[test]
+    fn test_split_fallback() {
+	assert_eq!(split_fallback(OsStr::new("/srv/fb/"), Path::new("a/b")),
+		   (PathBuf::from("/srv/fb"), PathBuf::from("a/b")));
+	assert_eq!(split_fallback(OsStr::new("/srv/fb/pre-"), Path::new("a/b")),
+		   (PathBuf::from("/srv/fb"), PathBuf::from("pre-a/b")));
+	assert_eq!(split_fallback(OsStr::new("/pre-"), Path::new("a")),
+		   (PathBuf::from("/"), PathBuf::from("pre-a")));
+	assert_eq!(split_fallback(OsStr::new("pre-"), Path::new("a")),
+		   (PathBuf::from("."), PathBuf::from("pre-a")));
+    }
+
+    #[test]
+    fn test_lookup_escape() {
+	use tempfile::TempDir;
+	use std::os::unix::fs::symlink;
+	use super::super::File;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path().join("root");
+	let tmp_path = tmp_path.as_path();
+
+	std::fs::create_dir(tmp_path).unwrap();
+	std::fs::create_dir(tmp_path.join("a")).unwrap();
+	std::fs::create_dir(tmp_path.join("b")).unwrap();
+
+	std::fs::File::create(tmp_path.join("b/foo")).unwrap();
+	std::fs::File::create(tmp_dir.path().join("secret")).unwrap();
+
+	symlink("/etc",                  tmp_path.join("a/esc-abs")).unwrap();
+	symlink("/etc/passwd",           tmp_path.join("a/esc-abs-file")).unwrap();
+	symlink("../../secret",          tmp_path.join("a/esc-rel")).unwrap();
+	symlink("../..",                 tmp_path.join("a/esc-dir")).unwrap();
+	symlink(tmp_path.join("b/foo"),  tmp_path.join("a/in-abs")).unwrap();
+	symlink("/proc/self/root",       tmp_path.join("a/magic")).unwrap();
+	symlink("../b/foo",              tmp_path.join("a/in-rel")).unwrap();
+	symlink("../b",                  tmp_path.join("a/in-dir")).unwrap();
+
+	let none = LookupOpts::default();
+
+	let open = |p: &str| {
+	    match lookup_path(tmp_path, p, &none).unwrap() {
+		LookupResult::Path(root, p)	=> File::new(&root, &p, false).open(),
+		r				=> panic!("unexpected lookup result {r:?}"),
+	    }
+	};
+
+	assert!(open("/b/foo").is_ok());
+	assert!(open("/a/in-rel").is_ok());
+	assert!(open("/a/in-dir/foo").is_ok());
+
+	assert!(matches!(open("/a/esc-abs/passwd"), Err(Error::AccessViolation(_))));
+	assert!(matches!(open("/a/esc-abs-file"),   Err(Error::AccessViolation(_))));
+	assert!(matches!(open("/a/esc-rel"),        Err(Error::AccessViolation(_))));
+	assert!(matches!(open("/a/esc-dir/secret"), Err(Error::AccessViolation(_))));
+	assert!(matches!(open("/a/in-abs"),         Err(Error::AccessViolation(_))));
+	assert!(matches!(open("/a/magic/etc/passwd"), Err(Error::AccessViolation(_))));
+
+	assert!(matches!(open("/a/missing"),        Err(Error::FileMissing(_))));
     }
 }
@@ -0,0 +1,457 @@
+use std::ffi::OsString;
+use std::io::Read;
+use std::mem::MaybeUninit;
+use std::os::unix::fs::FileExt;
+use std::path::{ Path, PathBuf };
+
+use tokio::sync::mpsc;
+
+use crate::{ Error, Result };
+use crate::util::{ AsInit, CopyInit };
+
+use super::File;
+
+/// Size of the chunks which are passed from the decompressing thread
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks which are decompressed in advance
+const CHUNK_CNT: usize = 4;
+
+/// Suffix of files which contain the decompressed size
+pub const SIZE_SUFFIX: &str = ".size";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+    Xz,
+}
+
+/// Suffixes which are tried (in this order) when a file is missing
+pub const SUFFIXES: &[(&str, Codec)] = &[
+    (".zst",	Codec::Zstd),
+    (".gz",	Codec::Gzip),
+    (".xz",	Codec::Xz),
+];
+
+impl Codec {
+    fn from_path(p: &Path) -> Option<Self> {
+	let name = p.file_name()?.to_str()?;
+
+	SUFFIXES.iter()
+	    .find(|(s, _)| name.ends_with(s))
+	    .map(|(_, c)| *c)
+    }
+
+    fn decoder(self, file: std::fs::File) -> std::io::Result<Box<dyn Read + Send>> {
+	let file = std::io::BufReader::new(file);
+
+	Ok(match self {
+	    Self::Zstd	=> Box::new(zstd::stream::read::Decoder::with_buffer(file)?),
+	    Self::Gzip	=> Box::new(flate2::read::MultiGzDecoder::new(file)),
+	    Self::Xz	=> Box::new(xz2::read::XzDecoder::new_multi_decoder(file)),
+	})
+    }
+
+    /// Reads the decompressed size from the frame headers; returns `None`
+    /// when it is not available
+    fn read_size(self, file: &std::fs::File) -> std::io::Result<Option<u64>> {
+	match self {
+	    Self::Zstd	=> zstd_size(file),
+	    Self::Xz	=> xz_size(file),
+	    // the size in the gzip trailer is truncated to 32 bit and covers
+	    // only the last member
+	    Self::Gzip	=> Ok(None),
+	}
+    }
+}
+
+fn read_array<const N: usize>(file: &std::fs::File, pos: u64) -> std::io::Result<[u8; N]> {
+    let mut res = [0; N];
+
+    file.read_exact_at(&mut res, pos)?;
+
+    Ok(res)
+}
+
+/// Sums up the content sizes of all zstd frames
+fn zstd_size(file: &std::fs::File) -> std::io::Result<Option<u64>> {
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    let mut res = 0_u64;
+
+    while pos < len {
+	let magic = u32::from_le_bytes(read_array(file, pos)?);
+
+	if magic & 0xffff_fff0 == 0x184d_2a50 {
+	    // skippable frame
+	    let sz = u32::from_le_bytes(read_array(file, pos + 4)?);
+
+	    pos += 8 + sz as u64;
+	    continue;
+	}
+
+	if magic != 0xfd2f_b528 {
+	    return Ok(None);
+	}
+
+	let [fhd] = read_array(file, pos + 4)?;
+	let single_segment = fhd & 0x20 != 0;
+	let has_checksum = fhd & 0x04 != 0;
+	let did_sz = [0, 1, 2, 4][(fhd & 0x03) as usize];
+	let fcs_sz = match fhd >> 6 {
+	    0 if single_segment	=> 1,
+	    0			=> return Ok(None),
+	    1			=> 2,
+	    2			=> 4,
+	    _			=> 8,
+	};
+
+	let fcs_pos = pos + 5 + (!single_segment) as u64 + did_sz;
+	let mut fcs = [0; 8];
+
+	file.read_exact_at(&mut fcs[..fcs_sz as usize], fcs_pos)?;
+
+	let fcs = u64::from_le_bytes(fcs) + if fcs_sz == 2 { 256 } else { 0 };
+
+	res = res.saturating_add(fcs);
+	pos = fcs_pos + fcs_sz;
+
+	// skip the blocks
+	loop {
+	    let hdr = read_array::<3>(file, pos)?;
+	    let hdr = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], 0]);
+	    let size = match (hdr >> 1) & 0x03 {
+		1	=> 1,			// RLE block
+		3	=> return Ok(None),	// reserved
+		_	=> hdr >> 3,
+	    };
+
+	    pos += 3 + size as u64;
+
+	    if hdr & 1 != 0 {
+		break;
+	    }
+	}
+
+	if has_checksum {
+	    pos += 4;
+	}
+    }
+
+    match pos == len {
+	true	=> Ok(Some(res)),
+	false	=> Ok(None),
+    }
+}
+
+/// Reads a xz multibyte integer from `b`
+fn xz_varint(b: &mut &[u8]) -> Option<u64> {
+    let mut res = 0_u64;
+
+    for i in 0..9 {
+	let (c, rest) = b.split_first()?;
+
+	*b = rest;
+	res |= ((c & 0x7f) as u64) << (i * 7);
+
+	if c & 0x80 == 0 {
+	    return Some(res);
+	}
+    }
+
+    None
+}
+
+/// Sums up the uncompressed sizes in the index of a single stream xz file
+fn xz_size(file: &std::fs::File) -> std::io::Result<Option<u64>> {
+    let len = file.metadata()?.len();
+
+    if len < 24 {
+	return Ok(None);
+    }
+
+    let footer = read_array::<12>(file, len - 12)?;
+
+    if &footer[10..12] != b"YZ" {
+	// e.g. stream padding
+	return Ok(None);
+    }
+
+    let index_sz = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+
+    if index_sz + 24 > len || index_sz > 16 * 1024 * 1024 {
+	return Ok(None);
+    }
+
+    let mut index = vec![0; index_sz as usize];
+
+    file.read_exact_at(&mut index, len - 12 - index_sz)?;
+
+    let mut p = &index[..];
+    let mut blocks_sz = 0_u64;
+    let mut res = 0_u64;
+
+    if p.first() != Some(&0) {
+	return Ok(None);
+    }
+
+    p = &p[1..];
+
+    let Some(cnt) = xz_varint(&mut p) else {
+	return Ok(None);
+    };
+
+    for _ in 0..cnt {
+	let (Some(unpadded), Some(size)) = (xz_varint(&mut p), xz_varint(&mut p)) else {
+	    return Ok(None);
+	};
+
+	blocks_sz = blocks_sz.saturating_add(unpadded.next_multiple_of(4));
+	res = res.saturating_add(size);
+    }
+
+    // only a single stream without padding is supported
+    match 12 + blocks_sz + index_sz + 12 == len {
+	true	=> Ok(Some(res)),
+	false	=> Ok(None),
+    }
+}
+
+/// Decompresses `file` and sends the result in chunks over `tx`; runs in a
+/// blocking thread
+fn decode(codec: Codec, file: std::fs::File, tx: mpsc::Sender<std::io::Result<Vec<u8>>>)
+{
+    let mut rd = match codec.decoder(file) {
+	Ok(rd)	=> rd,
+	Err(e)	=> {
+	    let _ = tx.blocking_send(Err(e));
+	    return;
+	}
+    };
+
+    loop {
+	let mut buf = vec![0; CHUNK_SIZE];
+
+	let res = match rd.read(&mut buf) {
+	    Ok(0)	=> break,
+	    Ok(sz)	=> {
+		buf.truncate(sz);
+		Ok(buf)
+	    },
+	    Err(e) if e.kind() == std::io::ErrorKind::Interrupted	=> continue,
+	    Err(e)	=> Err(e),
+	};
+
+	let is_err = res.is_err();
+
+	// fails when the transfer has been aborted
+	if tx.blocking_send(res).is_err() || is_err {
+	    break;
+	}
+    }
+}
+
+/// A file which is decompressed on the fly in a blocking thread
+#[derive(Debug)]
+pub struct Compressed {
+    file:	File,
+    size_file:	File,
+    codec:	Codec,
+    size:	Option<u64>,
+    rx:		Option<mpsc::Receiver<std::io::Result<Vec<u8>>>>,
+    chunk:	Vec<u8>,
+    chunk_pos:	usize,
+    total:	u64,
+    is_eof:	bool,
+}
+
+impl Compressed {
+    pub fn new(root: &Path, path: &Path, world_readable: bool) -> Self {
+	let mut size_path: OsString = path.into();
+
+	size_path.push(SIZE_SUFFIX);
+
+	Self {
+	    file:	File::new(root, path, world_readable),
+	    size_file:	File::new(root, &PathBuf::from(size_path), world_readable),
+	    codec:	Codec::from_path(path).unwrap(),
+	    size:	None,
+	    rx:		None,
+	    chunk:	Vec::new(),
+	    chunk_pos:	0,
+	    total:	0,
+	    is_eof:	false,
+	}
+    }
+
+    /// Reads the decompressed size from the sidecar file
+    fn read_size_file(&mut self) -> Option<u64> {
+	self.size_file.open().ok()?;
+
+	let mut buf = [0u8; 32];
+	let sz = self.size_file.as_std().read_at(&mut buf, 0).ok()?;
+
+	std::str::from_utf8(&buf[..sz]).ok()?
+	    .trim()
+	    .parse().ok()
+    }
+
+    pub async fn open(&mut self) -> Result<()> {
+	if self.rx.is_some() {
+	    return Err(Error::Internal("file already opened"));
+	}
+
+	self.file.open()?;
+
+	let codec = self.codec;
+
+	self.size = match self.read_size_file() {
+	    Some(sz)	=> Some(sz),
+	    None	=> {
+		let file = self.file.as_std().try_clone()?;
+
+		tokio::task::spawn_blocking(move || codec.read_size(&file)).await
+		    .map_err(|_| Error::Internal("failed to read size of compressed file"))?
+		    .unwrap_or_else(|e| {
+			warn!("failed to read size of compressed file: {}", e);
+			None
+		    })
+	    },
+	};
+
+	let file = self.file.as_std().try_clone()?;
+	let (tx, rx) = mpsc::channel(CHUNK_CNT);
+
+	tokio::task::spawn_blocking(move || decode(codec, file, tx));
+
+	self.rx = Some(rx);
+
+	Ok(())
+    }
+
+    pub fn get_size(&self) -> Option<u64> {
+	self.size
+    }
+
+    pub async fn read<'a>(&mut self, buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8]>
+    {
+	let mut pos = 0;
+
+	while pos < buf.len() {
+	    if self.chunk_pos == self.chunk.len() {
+		match self.rx.as_mut().unwrap().recv().await {
+		    None		=> {
+			self.is_eof = true;
+			break;
+		    },
+		    Some(Err(e))	=> return Err(e.into()),
+		    Some(Ok(chunk))	=> {
+			self.chunk = chunk;
+			self.chunk_pos = 0;
+		    },
+		}
+	    }
+
+	    let sz = (buf.len() - pos).min(self.chunk.len() - self.chunk_pos);
+
+	    buf[pos..pos + sz].write_copy_of_slice_x(&self.chunk[self.chunk_pos..self.chunk_pos + sz]);
+
+	    pos += sz;
+	    self.chunk_pos += sz;
+	}
+
+	self.total += pos as u64;
+
+	// do not send more data than announced; a short file is detected
+	// at EOF
+	if self.size.is_some_and(|sz| self.total > sz || (self.is_eof && sz != self.total)) {
+	    warn!("decompressed size {} does not match announced size {:?}", self.total, self.size);
+	    return Err(Error::Internal("size mismatch of compressed file"));
+	}
+
+	// SAFETY: the first 'pos' bytes have been initialized above
+	Ok(unsafe { buf[..pos].assume_init() })
+    }
+
+    pub fn is_eof(&self) -> bool
+    {
+	self.is_eof
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    async fn read_all(root: &Path, name: &str) -> Result<(Option<u64>, Vec<u8>)> {
+	let mut f = Compressed::new(root, Path::new(name), false);
+	let mut res = Vec::new();
+
+	f.open().await?;
+
+	while !f.is_eof() {
+	    let mut buf = vec![MaybeUninit::uninit(); 1000];
+
+	    res.extend_from_slice(f.read(&mut buf).await?);
+	}
+
+	Ok((f.get_size(), res))
+    }
+
+    #[tokio::test]
+    async fn test_decompress() {
+	use tempfile::TempDir;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path();
+
+	let data: Vec<u8> = (0..200_000_u32).map(|v| (v % 251) as u8).collect();
+	let sz = Some(data.len() as u64);
+
+	// two frames with content size
+	let mut zst = zstd::bulk::compress(&data[..1000], 3).unwrap();
+	zst.extend(zstd::bulk::compress(&data[1000..], 3).unwrap());
+	std::fs::write(tmp_path.join("a.zst"), &zst).unwrap();
+
+	// streaming encoder does not know the size
+	std::fs::write(tmp_path.join("b.zst"), zstd::stream::encode_all(&data[..], 3).unwrap()).unwrap();
+
+	let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+	gz.write_all(&data).unwrap();
+	std::fs::write(tmp_path.join("c.gz"), gz.finish().unwrap()).unwrap();
+	std::fs::write(tmp_path.join("c.gz.size"), format!("{}\n", data.len())).unwrap();
+
+	let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+	xz.write_all(&data).unwrap();
+	std::fs::write(tmp_path.join("d.xz"), xz.finish().unwrap()).unwrap();
+
+	// wrong size in the sidecar file
+	std::fs::copy(tmp_path.join("c.gz"), tmp_path.join("e.gz")).unwrap();
+	std::fs::write(tmp_path.join("e.gz.size"), "1000").unwrap();
+
+	std::fs::write(tmp_path.join("f.xz"), b"garbage").unwrap();
+
+	assert_eq!(read_all(tmp_path, "a.zst").await.unwrap(), (sz, data.clone()));
+	assert_eq!(read_all(tmp_path, "c.gz").await.unwrap(), (sz, data.clone()));
+	assert_eq!(read_all(tmp_path, "d.xz").await.unwrap(), (sz, data.clone()));
+
+	let (size, res) = read_all(tmp_path, "b.zst").await.unwrap();
+	assert_eq!(size, None);
+	assert_eq!(res, data);
+
+	assert!(read_all(tmp_path, "e.gz").await.is_err());
+
+	// excess data is reported before reaching the end of the stream
+	let mut f = Compressed::new(tmp_path, Path::new("e.gz"), false);
+	let mut buf = vec![MaybeUninit::uninit(); 1000];
+
+	f.open().await.unwrap();
+	assert_eq!(f.read(&mut buf).await.unwrap(), &data[..1000]);
+	assert!(f.read(&mut buf).await.is_err());
+	assert!(!f.is_eof());
+	assert!(read_all(tmp_path, "f.xz").await.is_err());
+	assert!(matches!(read_all(tmp_path, "missing.gz").await, Err(Error::FileMissing(_))));
+    }
+}
@@ -0,0 +1,224 @@
+use std::ffi::OsString;
+use std::mem::MaybeUninit;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::process::{ Child, Command };
+use tokio::time::Instant;
+
+use crate::{ Error, Result };
+use crate::util::AsInit;
+
+use super::template::Vars;
+
+/// Prefix of symlink targets (and rewrite results) which are served by
+/// running a program
+pub const SCHEME: &str = "exec://";
+
+/// Limits for the execution of programs
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// time after which the program is killed
+    pub timeout:	Duration,
+    /// maximum size of the output
+    pub max_size:	u64,
+}
+
+/// Output of a program which is run with the request details in its
+/// environment (`TFTP_REMOTE_IP`, `TFTP_MAC`, `TFTP_FILENAME`, ...).
+#[derive(Debug)]
+pub struct Exec {
+    program:	PathBuf,
+    path_info:	OsString,
+    vars:	Vars,
+    limits:	Limits,
+    child:	Option<Child>,
+    deadline:	Instant,
+    size:	u64,
+    is_eof:	bool,
+}
+
+impl Exec {
+    pub fn new(program: PathBuf, path_info: OsString, vars: Vars, limits: Limits) -> Self {
+	Self {
+	    program:	program,
+	    path_info:	path_info,
+	    vars:	vars,
+	    limits:	limits,
+	    child:	None,
+	    deadline:	Instant::now(),
+	    size:	0,
+	    is_eof:	false,
+	}
+    }
+
+    pub fn open(&mut self) -> Result<()> {
+	if self.child.is_some() {
+	    return Err(Error::Internal("program already started"));
+	}
+
+	let mut cmd = Command::new(&self.program);
+
+	cmd.env_clear()
+	    .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+	    .env("TFTP_PATH_INFO", &self.path_info)
+	    .envs(self.vars.iter().map(|(k, v)| (format!("TFTP_{}", k.to_uppercase()), v)))
+	    .stdin(Stdio::null())
+	    .stdout(Stdio::piped())
+	    .kill_on_drop(true);
+
+	if let Some(dir) = self.program.parent() {
+	    cmd.current_dir(dir);
+	}
+
+	debug!("running {:?}", self.program);
+
+	self.child = match cmd.spawn() {
+	    Err(e) if e.kind() == std::io::ErrorKind::NotFound		=>
+		return Err(Error::FileMissing(self.program.clone().into())),
+	    Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied	=>
+		return Err(Error::AccessViolation("program is not executable")),
+	    Err(e)	=> return Err(Error::Io(e)),
+	    Ok(c)	=> Some(c),
+	};
+
+	self.deadline = Instant::now() + self.limits.timeout;
+
+	Ok(())
+    }
+
+    pub fn get_size(&self) -> Option<u64> {
+	None
+    }
+
+    /// Waits for the termination of the program and checks its exit code
+    async fn finish(&mut self) -> Result<()> {
+	let child = self.child.as_mut().unwrap();
+	let status = tokio::time::timeout_at(self.deadline, child.wait()).await??;
+
+	if !status.success() {
+	    warn!("{:?} failed with {}", self.program, status);
+	    return Err(Error::Exec("program failed"));
+	}
+
+	Ok(())
+    }
+
+    async fn read_some(&mut self, buf: &mut [u8]) -> Result<usize> {
+	let child = self.child.as_mut().unwrap();
+	let stdout = child.stdout.as_mut().unwrap();
+
+	Ok(tokio::time::timeout_at(self.deadline, stdout.read(buf)).await??)
+    }
+
+    pub async fn read<'a>(&mut self, buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8]>
+    {
+	assert!(!self.is_eof());
+
+	let mut pos = 0;
+
+	let buf = unsafe { buf.assume_init() };
+
+	while pos < buf.len() {
+	    let sz = match self.read_some(&mut buf[pos..]).await {
+		Err(Error::Timeout)	=> {
+		    warn!("{:?} timed out", self.program);
+		    return Err(Error::Timeout);
+		},
+		r			=> r?,
+	    };
+
+	    if sz == 0 {
+		trace!("eof reached");
+		self.finish().await?;
+		self.is_eof = true;
+		break;
+	    }
+
+	    self.size += sz as u64;
+	    pos += sz;
+
+	    if self.size > self.limits.max_size {
+		warn!("output of {:?} exceeds {} bytes", self.program, self.limits.max_size);
+		return Err(Error::Exec("output too large"));
+	    }
+	}
+
+	Ok(&buf[..pos])
+    }
+
+    pub fn is_eof(&self) -> bool
+    {
+	self.is_eof
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn script(dir: &std::path::Path, name: &str, body: &str) -> PathBuf {
+	use std::os::unix::fs::PermissionsExt;
+
+	let path = dir.join(name);
+
+	std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+	std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+	path
+    }
+
+    async fn run(program: PathBuf, limits: Limits) -> Result<Vec<u8>> {
+	let client = crate::util::Client {
+	    remote:	"192.168.1.10".parse().unwrap(),
+	    local:	"192.168.1.1".parse().unwrap(),
+	    mac:	None,
+	};
+
+	let mut exec = Exec::new(program, "sub/file".into(), Vars::new(Some(&client), None), limits);
+	let mut res = Vec::new();
+
+	exec.open()?;
+
+	while !exec.is_eof() {
+	    let mut buf = vec![MaybeUninit::new(0); 7];
+
+	    res.extend_from_slice(exec.read(&mut buf).await?);
+	}
+
+	Ok(res)
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+	use tempfile::TempDir;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path();
+
+	let limits = Limits {
+	    timeout:	Duration::from_secs(5),
+	    max_size:	64,
+	};
+
+	let ok = script(tmp_path, "ok", r#"echo "$TFTP_REMOTE_IP $TFTP_PATH_INFO [$TFTP_MAC]""#);
+	let fail = script(tmp_path, "fail", "echo partial; exit 1");
+	let large = script(tmp_path, "large", "yes");
+	let slow = script(tmp_path, "slow", "exec sleep 10");
+
+	assert_eq!(run(ok, limits.clone()).await.unwrap(), b"192.168.1.10 sub/file []\n");
+
+	assert!(matches!(run(fail, limits.clone()).await, Err(Error::Exec(_))));
+	assert!(matches!(run(large, limits.clone()).await, Err(Error::Exec(_))));
+	assert!(matches!(run(tmp_path.join("missing"), limits.clone()).await, Err(Error::FileMissing(_))));
+
+	let limits = Limits {
+	    timeout:	Duration::from_millis(200),
+	    ..limits
+	};
+
+	assert!(matches!(run(slow, limits).await, Err(Error::Timeout)));
+    }
+}
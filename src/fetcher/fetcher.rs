@@ -4,14 +4,25 @@ use std::mem::MaybeUninit;
 pub enum Fetcher {
     File(Box<super::File>),
     Memory(Box<super::Memory>),
+    Template(Box<super::Template>),
+    Exec(Box<super::Exec>),
+    Archive(Box<super::Archive>),
+    Compressed(Box<super::Compressed>),
+    Backend(Box<dyn super::Backend>),
     #[cfg(feature = "proxy")]
     Uri(Box<super::Uri>),
 }
 
 impl Fetcher {
     #[instrument(level = "trace")]
-    pub fn new_file(path: &std::path::Path) -> Self {
-	Self::File(Box::new(super::file::File::new(path)))
+    pub fn new_file(root: &std::path::Path, path: &std::path::Path, world_readable: bool,
+		    cache: Option<&super::FileCache>, snapshot_dir: Option<&std::path::Path>) -> Self {
+	let mut file = super::file::File::new(root, path, world_readable);
+
+	file.set_cache(cache.cloned());
+	file.set_snapshot(snapshot_dir.map(Into::into));
+
+	Self::File(Box::new(file))
     }
 
     #[cfg(feature = "proxy")]
@@ -20,6 +31,42 @@ impl Fetcher {
 	Self::Uri(Box::new(super::Uri::new(uri)))
     }
 
+    #[instrument(level = "trace")]
+    pub fn new_template(root: &std::path::Path, path: &std::path::Path, world_readable: bool,
+			vars: super::template::Vars) -> Self {
+	let file = super::file::File::new(root, path, world_readable);
+
+	Self::Template(Box::new(super::Template::new(file, vars)))
+    }
+
+    #[instrument(level = "trace")]
+    pub fn new_exec(program: std::path::PathBuf, path_info: std::ffi::OsString,
+		    vars: super::template::Vars, limits: super::ExecLimits) -> Self {
+	Self::Exec(Box::new(super::Exec::new(program, path_info, vars, limits)))
+    }
+
+    #[instrument(level = "trace")]
+    pub fn new_archive(root: &std::path::Path, archive: &std::path::Path, member: &std::path::Path,
+		       world_readable: bool, icase: bool) -> Self {
+	let file = super::file::File::new(root, archive, world_readable);
+
+	Self::Archive(Box::new(super::Archive::new(file, archive, member, icase)))
+    }
+
+    #[instrument(level = "trace")]
+    pub fn new_compressed(root: &std::path::Path, path: &std::path::Path, world_readable: bool) -> Self {
+	Self::Compressed(Box::new(super::Compressed::new(root, path, world_readable)))
+    }
+
+    pub fn new_backend(backend: Box<dyn super::Backend>) -> Self {
+	Self::Backend(backend)
+    }
+
+    /// Serves data from the [`super::MemoryStore`] without copying it
+    pub fn new_stored(data: std::sync::Arc<[u8]>) -> Self {
+	Self::Memory(Box::new(super::memory::Memory::from(data)))
+    }
+
     #[cfg(test)]
     pub fn new_memory(buf: &[u8]) -> Self {
 	Self::Memory(Box::new(super::memory::Memory::new(buf)))
@@ -29,6 +76,11 @@ impl Fetcher {
 	match self {
 	    Self::File(f)	=> f.is_mmaped(),
 	    Self::Memory(_)	=> true,
+	    Self::Template(_)	=> true,
+	    Self::Exec(_)	=> false,
+	    Self::Archive(_)	=> false,
+	    Self::Compressed(_)	=> false,
+	    Self::Backend(b)	=> b.is_mmaped(),
 	    #[cfg(feature = "proxy")]
 	    Self::Uri(_)	=> false,
 	}
@@ -37,8 +89,13 @@ impl Fetcher {
     #[instrument(level = "trace")]
     pub async fn open(&mut self) -> crate::Result<()> {
 	match self {
-	    Self::File(f)	=> f.open(),
+	    Self::File(f)	=> f.open_cached().await,
 	    Self::Memory(m)	=> m.open(),
+	    Self::Template(t)	=> t.open().await,
+	    Self::Exec(e)	=> e.open(),
+	    Self::Archive(a)	=> a.open().await,
+	    Self::Compressed(c)	=> c.open().await,
+	    Self::Backend(b)	=> b.open().await,
 	    #[cfg(feature = "proxy")]
 	    Self::Uri(u)	=> Ok(u.open().await?),
 	}
@@ -49,6 +106,11 @@ impl Fetcher {
 	match self {
 	    Self::File(f)	=> f.get_size(),
 	    Self::Memory(m)	=> m.get_size(),
+	    Self::Template(t)	=> t.get_size(),
+	    Self::Exec(e)	=> e.get_size(),
+	    Self::Archive(a)	=> a.get_size(),
+	    Self::Compressed(c)	=> c.get_size(),
+	    Self::Backend(b)	=> b.get_size().await,
 	    #[cfg(feature = "proxy")]
 	    Self::Uri(u)	=> u.get_size().await,
 	}
@@ -60,6 +122,11 @@ impl Fetcher {
 	match self {
 	    Self::File(f)	=> f.read(buf).await,
 	    Self::Memory(m)	=> m.read(buf).await,
+	    Self::Template(t)	=> t.read(buf).await,
+	    Self::Exec(e)	=> e.read(buf).await,
+	    Self::Archive(a)	=> a.read(buf).await,
+	    Self::Compressed(c)	=> c.read(buf).await,
+	    Self::Backend(b)	=> b.read(buf).await,
 	    #[cfg(feature = "proxy")]
 	    Self::Uri(u)	=> Ok(u.read(buf).await?),
 	}
@@ -71,6 +138,11 @@ impl Fetcher {
 	match self {
 	    Self::File(f)	=> f.read_mmap(cnt),
 	    Self::Memory(m)	=> m.read_mmap(cnt),
+	    Self::Template(t)	=> t.read_mmap(cnt),
+	    Self::Exec(_)	=> unimplemented!(),
+	    Self::Archive(_)	=> unimplemented!(),
+	    Self::Compressed(_)	=> unimplemented!(),
+	    Self::Backend(b)	=> b.read_mmap(cnt),
 	    #[cfg(feature = "proxy")]
 	    Self::Uri(_)	=> unimplemented!(),
 	}
@@ -81,6 +153,11 @@ impl Fetcher {
 	match self {
 	    Self::File(f)	=> f.is_eof(),
 	    Self::Memory(m)	=> m.is_eof(),
+	    Self::Template(t)	=> t.is_eof(),
+	    Self::Exec(e)	=> e.is_eof(),
+	    Self::Archive(a)	=> a.is_eof(),
+	    Self::Compressed(c)	=> c.is_eof(),
+	    Self::Backend(b)	=> b.is_eof(),
 	    #[cfg(feature = "proxy")]
 	    Self::Uri(u)	=> u.is_eof(),
 	}
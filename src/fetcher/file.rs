@@ -1,45 +1,326 @@
 use crate::{ Error, Result };
-use crate::util::AsInit;
+use crate::util::{ AsInit, Beneath };
 
 use std::io::Read;
 use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
+use std::path::{ Path, PathBuf };
+
+use tokio::sync::watch;
+
+use super::filecache::Key as Version;
 
 #[derive(Debug)]
 pub struct File {
+    root:	std::path::PathBuf,
     path:	std::path::PathBuf,
     file:	Option<std::fs::File>,
     is_eof:	bool,
+    world_readable:	bool,
+    cache:	Option<super::FileCache>,
+    cached:	Option<super::Memory>,
+    snapshot_dir:	Option<PathBuf>,
+    // progress of copying the file into its snapshot
+    copied:	Option<watch::Receiver<CopyProgress>>,
+    pinned:	bool,
+    version:	Option<Version>,
+    offset:	u64,
+}
+
+/// Size of the chunks which are copied into a snapshot
+const COPY_CHUNK: usize = 256 * 1024;
+
+#[derive(Clone, Debug, Default)]
+struct CopyProgress {
+    pos:	u64,
+    // set when the copy is complete or failed
+    done:	Option<Result<()>>,
+}
+
+/// Creates a private copy of `src` as an unnamed file in `dir`.  Returns
+/// whether the data must still be copied by [`copy_snapshot()`]; this is
+/// not needed for reflinks.  Else, disk space is reserved for the copy so
+/// that concurrent transfers can not exhaust it.
+fn create_snapshot(dir: &Path, src: &std::fs::File, version: Version) -> Result<(std::fs::File, bool)> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use nix::fcntl::{ fallocate, FallocateFlags };
+    use nix::errno::Errno;
+
+    let tmp = std::fs::OpenOptions::new()
+	.read(true)
+	.write(true)
+	.custom_flags(nix::libc::O_TMPFILE)
+	.mode(0o600)
+	.open(dir)?;
+
+    // SAFETY: both file descriptors are valid
+    let rc = unsafe { nix::libc::ioctl(tmp.as_raw_fd(), nix::libc::FICLONE, src.as_raw_fd()) };
+
+    if rc >= 0 {
+	if Version::new(&src.metadata()?) != version {
+	    return Err(Error::FileModified);
+	}
+
+	return Ok((tmp, false));
+    }
+
+    debug!("reflink failed ({}); copying the file", std::io::Error::last_os_error());
+
+    match fallocate(&tmp, FallocateFlags::empty(), 0, version.size as nix::libc::off_t) {
+	Ok(_) | Err(Errno::EOPNOTSUPP)	=> {},
+	Err(e)				=> return Err(e.into()),
+    }
+
+    Ok((tmp, true))
+}
+
+/// Copies `src` into `dst` and reports the progress.  The version is
+/// checked before the last chunk is reported so that readers of a
+/// complete copy never see modified data.
+fn copy_snapshot(src: &std::fs::File, dst: &std::fs::File, version: Version,
+		 progress: &watch::Sender<CopyProgress>) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut pos = 0;
+
+    while pos < version.size {
+	let len = COPY_CHUNK.min((version.size - pos).try_into().unwrap_or(usize::MAX));
+
+	match src.read_exact_at(&mut buf[..len], pos) {
+	    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof	=> return Err(Error::FileModified),
+	    r								=> r?,
+	}
+
+	dst.write_all_at(&buf[..len], pos)?;
+	pos += len as u64;
+
+	if pos == version.size && Version::new(&src.metadata()?) != version {
+	    return Err(Error::FileModified);
+	}
+
+	progress.send_modify(|p| p.pos = pos);
+
+	// the transfer was aborted
+	if progress.is_closed() {
+	    break;
+	}
+    }
+
+    Ok(())
 }
 
 impl File {
-    pub fn new(path: &std::path::Path) -> Self {
+    /// Creates a file object; `path` is relative to `root` and must not
+    /// resolve outside of it.  When `world_readable` is set, only files
+    /// readable by everybody will be served.
+    pub fn new(root: &std::path::Path, path: &std::path::Path, world_readable: bool) -> Self {
 	Self {
-	    path:	path.into(),
-	    file:	None,
-	    is_eof:	false,
+	    root:		root.into(),
+	    path:		path.into(),
+	    file:		None,
+	    is_eof:		false,
+	    world_readable:	world_readable,
+	    cache:		None,
+	    cached:		None,
+	    snapshot_dir:	None,
+	    copied:		None,
+	    pinned:		false,
+	    version:		None,
+	    offset:		0,
 	}
     }
 
+    /// Serves the file from `cache` when it fits into it
+    pub fn set_cache(&mut self, cache: Option<super::FileCache>) {
+	self.cache = cache;
+    }
+
+    /// Serves the file from a private copy in `dir` (a reflink when
+    /// supported by the filesystem) so that modifications during the
+    /// transfer are not seen.  Without it, transfers of modified files are
+    /// aborted.
+    pub fn set_snapshot(&mut self, dir: Option<PathBuf>) {
+	self.snapshot_dir = dir;
+    }
+
+    fn check_access(&self, file: &std::fs::File) -> Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	use nix::fcntl::{ fcntl, FcntlArg, OFlag };
+
+	let meta = file.metadata()?;
+
+	if !meta.file_type().is_file() {
+	    return Err(Error::AccessViolation("not a regular file"));
+	}
+
+	if self.world_readable && meta.permissions().mode() & 0o004 == 0 {
+	    return Err(Error::AccessViolation("file is not world readable"));
+	}
+
+	// file was opened with O_NONBLOCK to avoid hangs on FIFOs
+	let flags = OFlag::from_bits_truncate(fcntl(file, FcntlArg::F_GETFL)?);
+
+	fcntl(file, FcntlArg::F_SETFL(flags - OFlag::O_NONBLOCK))?;
+
+	Ok(())
+    }
+
     pub fn open(&mut self) -> Result<()> {
+	self.open_file()
+    }
+
+    /// Opens the file like [`Self::open()`] and serves it from the cache
+    /// or a snapshot when configured
+    pub async fn open_cached(&mut self) -> Result<()> {
+	self.open_file()?;
+
+	if let Some(cache) = &self.cache {
+	    match cache.get(self.as_std()).await {
+		Ok(data)	=> self.cached = data.map(super::Memory::from),
+		Err(e)		=> warn!("failed to cache {:?}: {:?}", self.path, e),
+	    }
+	}
+
+	if let (Some(dir), None) = (&self.snapshot_dir, &self.cached) {
+	    if let Err(e) = self.open_snapshot(dir.clone()).await {
+		warn!("failed to snapshot {:?}: {:?}", self.path, e);
+		return Err(e);
+	    }
+	}
+
+	Ok(())
+    }
+
+    /// Replaces the file by a snapshot in `dir`.  When it can not be
+    /// reflinked, the data are copied in the background and `read()` waits
+    /// for them.
+    async fn open_snapshot(&mut self, dir: PathBuf) -> Result<()> {
+	let src = self.as_std().try_clone()?;
+	let version = self.version.ok_or(Error::Internal("file not opened"))?;
+
+	let (tmp, need_copy) = {
+	    let src = src.try_clone()?;
+
+	    tokio::task::spawn_blocking(move || create_snapshot(&dir, &src, version)).await
+		.map_err(|_| Error::Internal("failed to create snapshot"))??
+	};
+
+	if need_copy {
+	    let (tx, rx) = watch::channel(CopyProgress::default());
+	    let dst = tmp.try_clone()?;
+
+	    tokio::task::spawn_blocking(move || {
+		let res = copy_snapshot(&src, &dst, version, &tx);
+
+		tx.send_modify(|p| p.done = Some(res));
+	    });
+
+	    self.copied = Some(rx);
+	}
+
+	self.file = Some(tmp);
+	self.pinned = true;
+
+	Ok(())
+    }
+
+    /// Waits until the snapshot has been copied up to `end`
+    async fn wait_copied(&mut self, end: u64) -> Result<()> {
+	let Some(rx) = &mut self.copied else {
+	    return Ok(());
+	};
+
+	let progress = rx.wait_for(|p| p.pos >= end || p.done.is_some()).await
+	    .map_err(|_| Error::Internal("snapshot copy vanished"))?;
+
+	match &progress.done {
+	    Some(Err(e))	=> {
+		warn!("failed to snapshot {:?}: {:?}", self.path, e);
+		Err(e.clone())
+	    },
+	    Some(Ok(()))	=> {
+		drop(progress);
+		self.copied = None;
+		Ok(())
+	    },
+	    None		=> Ok(()),
+	}
+    }
+
+    fn open_file(&mut self) -> Result<()> {
 	if self.file.is_some() {
 	    return Err(Error::Internal("file already opened"));
 	}
 
-	self.file = match std::fs::File::open(&self.path) {
+	use nix::libc::{ EXDEV, ELOOP };
+
+	let file = Beneath::open(&self.root)
+	    .and_then(|root| root.open_file(&self.path));
+
+	match file {
 	    Err(e) if e.kind() == std::io::ErrorKind::NotFound	=>
-                return Err(Error::FileMissing(self.path.clone().into())),
+		return Err(Error::FileMissing(self.root.join(&self.path).into())),
+	    Err(e) if e.raw_os_error() == Some(EXDEV)	=>
+		return Err(Error::AccessViolation("path escapes the root directory")),
+	    Err(e) if e.raw_os_error() == Some(ELOOP)	=>
+		return Err(Error::AccessViolation("too many symlinks")),
 	    Err(e)	=> return Err(Error::Io(e)),
-	    Ok(f)	=> Some(f),
+	    Ok(f)	=> {
+		self.check_access(&f)?;
+		self.file = Some(f);
+	    },
+	};
+
+	self.version = Some(Version::new(&self.as_std().metadata()?));
+
+	Ok(())
+    }
+
+    /// Aborts the transfer when the file was modified since `open()`.
+    /// Replacing the file (e.g. by `rename()`) is not a modification; the
+    /// old file continues to be served.
+    fn check_version(&self) -> Result<()> {
+	use std::cmp::Ordering;
+
+	let Some(version) = &self.version else {
+	    return Ok(());
 	};
 
+	let modified = match self.offset.cmp(&version.size) {
+	    // read past the announced size or truncated
+	    Ordering::Greater			=> true,
+	    Ordering::Less if self.is_eof	=> true,
+	    _ if self.pinned			=> false,
+	    _					=> Version::new(&self.as_std().metadata()?) != *version,
+	};
+
+	if modified {
+	    warn!("{:?} was modified during transfer", self.path);
+	    return Err(Error::FileModified);
+	}
+
 	Ok(())
     }
 
+    /// Returns the underlying file; must be called after `open()`
+    pub fn as_std(&self) -> &std::fs::File {
+	self.file.as_ref().unwrap()
+    }
+
     pub fn is_mmaped(&self) -> bool {
-	false
+	self.cached.is_some()
     }
 
     pub fn get_size(&self) -> Option<u64> {
+	if let Some(m) = &self.cached {
+	    return m.get_size();
+	}
+
+	if let Some(v) = &self.version {
+	    return Some(v.size);
+	}
+
 	let file = self.file.as_ref().unwrap();
 
 	file.metadata().ok().map(|v| v.len())
@@ -49,6 +330,14 @@ impl File {
     {
 	assert!(!self.is_eof());
 
+	if let Some(m) = &mut self.cached {
+	    return m.read(buf).await;
+	}
+
+	if let Some(v) = self.version {
+	    self.wait_copied(v.size.min(self.offset + buf.len() as u64)).await?;
+	}
+
 	let mut file = self.file.as_ref().unwrap();
 	let mut len = buf.len();
 	let mut pos = 0;
@@ -68,16 +357,105 @@ impl File {
 	    pos += sz;
 	}
 
+	self.offset += pos as u64;
+	self.check_version()?;
+
 	Ok(&buf[..pos])
     }
 
-    pub fn read_mmap(&mut self, _cnt: usize) -> crate::Result<&[u8]>
+    pub fn read_mmap(&mut self, cnt: usize) -> crate::Result<&[u8]>
     {
-	Err(Error::Internal("File::read_mmap() not implemented"))
+	match &mut self.cached {
+	    Some(m)	=> m.read_mmap(cnt),
+	    None	=> Err(Error::Internal("File::read_mmap() not implemented")),
+	}
     }
 
     pub fn is_eof(&self) -> bool
     {
-	self.is_eof
+	match &self.cached {
+	    Some(m)	=> m.is_eof(),
+	    None	=> self.is_eof,
+	}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    async fn read_all(file: &mut File) -> Result<Vec<u8>> {
+	let mut res = Vec::new();
+
+	while !file.is_eof() {
+	    let mut buf = [MaybeUninit::uninit(); 4];
+
+	    res.extend_from_slice(file.read(&mut buf).await?);
+	}
+
+	Ok(res)
+    }
+
+    #[tokio::test]
+    async fn test_modified() {
+	let dir = tempfile::TempDir::new().unwrap();
+	let path = dir.path().join("image");
+
+	for (snapshot, truncate) in [(false, false), (false, true), (true, false), (true, true)] {
+	    std::fs::write(&path, b"0123456789").unwrap();
+
+	    let mut file = File::new(dir.path(), Path::new("image"), false);
+	    let mut buf = [MaybeUninit::uninit(); 4];
+
+	    file.set_snapshot(snapshot.then(|| dir.path().into()));
+	    file.open_cached().await.unwrap();
+
+	    assert_eq!(file.get_size(), Some(10));
+	    assert_eq!(file.read(&mut buf).await.unwrap(), b"0123");
+
+	    match truncate {
+		true	=> std::fs::write(&path, b"abc").unwrap(),
+		false	=> std::fs::OpenOptions::new().write(true).open(&path).unwrap()
+		    .write_all_at(b"abcdefghijkl", 0).unwrap(),
+	    }
+
+	    match snapshot {
+		true	=> assert_eq!(read_all(&mut file).await.unwrap(), b"456789"),
+		false	=> assert!(matches!(read_all(&mut file).await, Err(Error::FileModified))),
+	    }
+	}
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+	let dir = tempfile::TempDir::new().unwrap();
+	let snapshots = dir.path().join("snapshots");
+	let data: Vec<u8> = (0..3 * COPY_CHUNK + 5).map(|i| i as u8).collect();
+
+	std::fs::write(dir.path().join("image"), &data).unwrap();
+
+	// snapshot directory does not exist
+	let mut file = File::new(dir.path(), Path::new("image"), false);
+
+	file.set_snapshot(Some(snapshots.clone()));
+	assert!(file.open_cached().await.is_err());
+
+	std::fs::create_dir(&snapshots).unwrap();
+
+	let mut file = File::new(dir.path(), Path::new("image"), false);
+	let mut res = Vec::new();
+
+	file.set_snapshot(Some(snapshots));
+	file.open_cached().await.unwrap();
+
+	while !file.is_eof() {
+	    let mut buf = [MaybeUninit::uninit(); 1428];
+
+	    res.extend_from_slice(file.read(&mut buf).await.unwrap());
+	}
+
+	assert!(res == data);
     }
 }
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::os::unix::fs::{ FileExt, MetadataExt };
+use std::sync::{ Arc, Mutex };
+
+use crate::Result;
+
+/// Identifies a file version; a modified file gets a new key because its
+/// mtime or size changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct Key {
+    dev:	u64,
+    ino:	u64,
+    mtime:	(i64, i64),
+    pub(super) size:	u64,
+}
+
+impl Key {
+    pub(super) fn new(meta: &std::fs::Metadata) -> Self {
+	Self {
+	    dev:	meta.dev(),
+	    ino:	meta.ino(),
+	    mtime:	(meta.mtime(), meta.mtime_nsec()),
+	    size:	meta.size(),
+	}
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    data:	Arc<[u8]>,
+    last_use:	u64,
+}
+
+/// A file which is being read; concurrent requests wait for its result
+type Pending = Arc<tokio::sync::OnceCell<Option<Arc<[u8]>>>>;
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries:	HashMap<Key, Entry>,
+    pending:	HashMap<Key, Pending>,
+    size:	u64,
+    tick:	u64,
+    hits:	u64,
+    misses:	u64,
+}
+
+impl Inner {
+    fn get(&mut self, key: &Key) -> Option<Arc<[u8]>> {
+	self.tick += 1;
+
+	let entry = self.entries.get_mut(key)?;
+
+	entry.last_use = self.tick;
+
+	Some(entry.data.clone())
+    }
+
+    /// Removes least recently used entries until `sz` more bytes fit into
+    /// `max_size`.  Sessions which still serve an evicted file keep their
+    /// copy.
+    fn evict(&mut self, sz: u64, max_size: u64) {
+	while self.size + sz > max_size {
+	    let Some(key) = self.entries.iter()
+		.min_by_key(|(_, e)| e.last_use)
+		.map(|(k, _)| *k) else {
+		    break;
+		};
+
+	    let entry = self.entries.remove(&key).unwrap();
+
+	    trace!("evicting {:?}", key);
+	    self.size -= entry.data.len() as u64;
+	}
+    }
+}
+
+/// Statistics of a [`FileCache`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileCacheStats {
+    pub hits:		u64,
+    pub misses:		u64,
+    pub entries:	usize,
+    pub size:		u64,
+}
+
+/// Bounded cache of recently served local files.
+///
+/// Files are identified by device, inode, mtime and size so that
+/// concurrent sessions share one copy of e.g. a kernel which is requested
+/// by many clients at once.  Files larger than the cache are read
+/// directly.  Clones share the cache.
+#[derive(Clone, Debug)]
+pub struct FileCache {
+    max_size:	u64,
+    inner:	Arc<Mutex<Inner>>,
+}
+
+impl FileCache {
+    /// Creates a cache which holds up to `max_size` bytes
+    pub fn new(max_size: u64) -> Self {
+	Self {
+	    max_size:	max_size,
+	    inner:	Default::default(),
+	}
+    }
+
+    /// Reads `file` completely; returns `None` when it was modified
+    /// meanwhile
+    fn read(file: &std::fs::File, key: &Key) -> Result<Option<Arc<[u8]>>> {
+	let mut data = vec![0u8; key.size as usize];
+
+	// do not move the file position; the caller falls back to reading
+	// the file when the content can not be cached
+	file.read_exact_at(&mut data, 0)?;
+
+	if Key::new(&file.metadata()?) != *key {
+	    debug!("file changed while caching it");
+	    return Ok(None);
+	}
+
+	Ok(Some(data.into()))
+    }
+
+    /// Returns the content of the opened `file`; it is read completely on
+    /// the blocking thread pool when it is not cached yet.  Concurrent
+    /// requests for the same file wait for a single read.  Returns `None`
+    /// when the file is not suitable for caching.
+    pub async fn get(&self, file: &std::fs::File) -> Result<Option<Arc<[u8]>>> {
+	let meta = file.metadata()?;
+	let key = Key::new(&meta);
+
+	if key.size == 0 || key.size > self.max_size {
+	    return Ok(None);
+	}
+
+	let pending = {
+	    let mut inner = self.inner.lock().unwrap();
+
+	    if let Some(data) = inner.get(&key) {
+		inner.hits += 1;
+		return Ok(Some(data));
+	    }
+
+	    match inner.pending.get(&key).cloned() {
+		Some(p)	=> {
+		    inner.hits += 1;
+		    p
+		},
+		None	=> {
+		    inner.misses += 1;
+		    inner.pending.entry(key).or_default().clone()
+		},
+	    }
+	};
+
+	let res = pending.get_or_try_init(|| async {
+	    let file = file.try_clone()?;
+
+	    tokio::task::spawn_blocking(move || Self::read(&file, &key)).await
+		.map_err(|_| crate::Error::Internal("failed to read file into cache"))?
+	}).await.cloned();
+
+	let mut inner = self.inner.lock().unwrap();
+
+	// the first finished request moves the data into the cache
+	if !inner.pending.get(&key).is_some_and(|p| Arc::ptr_eq(p, &pending)) {
+	    return res;
+	}
+
+	inner.pending.remove(&key);
+
+	let Ok(Some(data)) = &res else {
+	    return res;
+	};
+
+	inner.evict(key.size, self.max_size);
+	inner.size += key.size;
+
+	let tick = inner.tick;
+
+	inner.entries.insert(key, Entry {
+	    data:	data.clone(),
+	    last_use:	tick,
+	});
+
+	res
+    }
+
+    pub fn stats(&self) -> FileCacheStats {
+	let inner = self.inner.lock().unwrap();
+
+	FileCacheStats {
+	    hits:	inner.hits,
+	    misses:	inner.misses,
+	    entries:	inner.entries.len(),
+	    size:	inner.size,
+	}
+    }
+
+    /// Removes all entries; the statistics are kept
+    pub fn clear(&self) {
+	let mut inner = self.inner.lock().unwrap();
+
+	inner.entries.clear();
+	inner.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache() {
+	let dir = tempfile::TempDir::new().unwrap();
+	let cache = FileCache::new(10);
+	let open = |name: &str| std::fs::File::open(dir.path().join(name)).unwrap();
+
+	std::fs::write(dir.path().join("a"), b"aaaa").unwrap();
+	std::fs::write(dir.path().join("b"), b"bbbb").unwrap();
+	std::fs::write(dir.path().join("c"), b"cccc").unwrap();
+	std::fs::write(dir.path().join("big"), [0u8; 11]).unwrap();
+	std::fs::write(dir.path().join("empty"), b"").unwrap();
+
+	let a = cache.get(&open("a")).await.unwrap().unwrap();
+
+	assert_eq!(&*a, b"aaaa");
+	assert!(Arc::ptr_eq(&a, &cache.get(&open("a")).await.unwrap().unwrap()));
+
+	assert!(cache.get(&open("big")).await.unwrap().is_none());
+	assert!(cache.get(&open("empty")).await.unwrap().is_none());
+
+	cache.get(&open("b")).await.unwrap();
+
+	// "b" is the least recently used one and gets evicted
+	cache.get(&open("a")).await.unwrap();
+	cache.get(&open("c")).await.unwrap();
+
+	assert_eq!(cache.stats(), FileCacheStats {
+	    hits:	2,
+	    misses:	3,
+	    entries:	2,
+	    size:	8,
+	});
+
+	// the evicted data stays valid for its users
+	assert_eq!(&*a, b"aaaa");
+
+	// modified files are read again
+	std::fs::write(dir.path().join("a"), b"AAAAA").unwrap();
+	assert_eq!(&*cache.get(&open("a")).await.unwrap().unwrap(), b"AAAAA");
+	assert_eq!(cache.stats().misses, 4);
+
+	cache.clear();
+	assert_eq!(cache.stats().entries, 0);
+	assert_eq!(cache.stats().size, 0);
+
+	// concurrent misses share one read
+	let (f0, f1) = (open("b"), open("b"));
+	let (b0, b1) = tokio::join!(cache.get(&f0), cache.get(&f1));
+
+	assert!(Arc::ptr_eq(&b0.unwrap().unwrap(), &b1.unwrap().unwrap()));
+	assert_eq!(cache.stats().misses, 5);
+	assert_eq!(cache.stats().entries, 1);
+	assert!(cache.inner.lock().unwrap().pending.is_empty());
+    }
+}
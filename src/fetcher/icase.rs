@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::ffi::{ OsStr, OsString };
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use nix::dir::Dir;
+use nix::fcntl::OFlag;
+use nix::sys::stat;
+
+use crate::util::Beneath;
+
+/// Maximum number of cached directory listings; the cache is flushed
+/// when it grows beyond this number
+const MAX_DIRS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct DirId {
+    dev:	u64,
+    ino:	u64,
+}
+
+struct Listing {
+    /// (mtime, ctime) of the directory when the listing was read
+    stamp:	[(i64, i64); 2],
+    names:	Vec<OsString>,
+}
+
+/// Cache of directory listings used for case-insensitive lookups.
+///
+/// Entries are keyed by device and inode of the directory and are
+/// revalidated by its modification time.
+struct DirCache {
+    dirs:	Mutex<HashMap<DirId, Listing>>,
+}
+
+lazy_static::lazy_static! {
+    static ref DIR_CACHE: DirCache = DirCache {
+	dirs:	Mutex::new(HashMap::new()),
+    };
+}
+
+fn read_names(dir: std::os::fd::OwnedFd) -> std::io::Result<Vec<OsString>> {
+    let mut dir = Dir::from_fd(dir)?;
+    let mut res = Vec::new();
+
+    for e in dir.iter() {
+	let name = e?.file_name().to_bytes().to_vec();
+
+	if name == b"." || name == b".." {
+	    continue;
+	}
+
+	res.push(OsStr::from_bytes(&name).to_os_string());
+    }
+
+    Ok(res)
+}
+
+impl DirCache {
+    fn lookup(&self, root: &Beneath, dir: &Path, name: &OsStr) -> std::io::Result<Option<OsString>> {
+	let fd = root.open_at(dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY)?;
+	let st = stat::fstat(&fd)?;
+
+	let id = DirId {
+	    dev:	st.st_dev,
+	    ino:	st.st_ino,
+	};
+
+	#[allow(clippy::unnecessary_cast)]
+	let stamp = [
+	    (st.st_mtime as i64, st.st_mtime_nsec as i64),
+	    (st.st_ctime as i64, st.st_ctime_nsec as i64),
+	];
+
+	let mut dirs = self.dirs.lock().unwrap();
+
+	let is_valid = dirs.get(&id).map(|l| l.stamp == stamp).unwrap_or(false);
+
+	if !is_valid {
+	    trace!("reading directory {:?}", dir);
+
+	    let names = read_names(fd)?;
+
+	    if dirs.len() >= MAX_DIRS {
+		debug!("flushing directory cache");
+		dirs.clear();
+	    }
+
+	    dirs.insert(id, Listing {
+		stamp:	stamp,
+		names:	names,
+	    });
+	}
+
+	let mut matches = dirs[&id].names.iter()
+	    .filter(|n| n.as_bytes().eq_ignore_ascii_case(name.as_bytes()));
+
+	match (matches.next(), matches.next()) {
+	    (Some(n), None)	=> Ok(Some(n.clone())),
+	    (Some(_), Some(_))	=> {
+		debug!("ambiguous case-insensitive match for {:?} in {:?}", name, dir);
+		Ok(None)
+	    },
+	    (None, _)		=> Ok(None),
+	}
+    }
+}
+
+/// Returns the unique entry of `dir` (relative to `root`) which matches
+/// `name` case-insensitively (ASCII only).  Ambiguous matches result in
+/// `None`.
+pub fn lookup(root: &Beneath, dir: &Path, name: &OsStr) -> std::io::Result<Option<OsString>> {
+    DIR_CACHE.lookup(root, dir, name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+	use tempfile::TempDir;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path();
+
+	std::fs::create_dir(tmp_path.join("dir")).unwrap();
+	std::fs::File::create(tmp_path.join("dir/pxelinux.0")).unwrap();
+	std::fs::File::create(tmp_path.join("dir/Ambig")).unwrap();
+	std::fs::File::create(tmp_path.join("dir/AMBIG")).unwrap();
+
+	let root = Beneath::open(tmp_path).unwrap();
+	let lookup = |dir: &str, name: &str| lookup(&root, Path::new(dir), OsStr::new(name)).unwrap();
+
+	assert_eq!(lookup("", "DIR"), Some("dir".into()));
+	assert_eq!(lookup("dir", "PXELINUX.0"), Some("pxelinux.0".into()));
+	assert_eq!(lookup("dir", "ambig"), None);
+	assert_eq!(lookup("dir", "missing"), None);
+
+	// cached listing must be revalidated after modifications
+	std::fs::File::create(tmp_path.join("dir/new-file")).unwrap();
+	assert_eq!(lookup("dir", "NEW-FILE"), Some("new-file".into()));
+
+	std::fs::File::create(tmp_path.join("dir/Pxelinux.0")).unwrap();
+	assert_eq!(lookup("dir", "PXELINUX.0"), None);
+    }
+}
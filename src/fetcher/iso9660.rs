@@ -0,0 +1,434 @@
+use std::collections::HashSet;
+
+use super::archive::{ Entry, Index, Method, bad_data, le, member_name, read_at };
+
+/// Size of the sectors which contain the volume descriptors
+const SECTOR: u64 = 2048;
+
+/// Maximum depth of the directory tree
+const MAX_DEPTH: usize = 32;
+
+/// Maximum number of continuation areas of a Rock Ridge entry
+const MAX_CONTINUATIONS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Names {
+    /// plain ISO 9660 names (`NAME.EXT;1`)
+    Plain,
+    /// names from the Rock Ridge `NM` entries
+    RockRidge,
+    /// UCS-2 names from the Joliet supplementary volume descriptor
+    Joliet,
+}
+
+struct DirRecord<'a> {
+    extent:	u64,
+    size:	u64,
+    flags:	u8,
+    name:	&'a [u8],
+    system_use:	&'a [u8],
+}
+
+impl <'a> DirRecord<'a> {
+    const FLAG_DIRECTORY: u8 = 0x02;
+    const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+    fn parse(b: &'a [u8]) -> std::io::Result<Self> {
+	let len = *b.first().unwrap_or(&0) as usize;
+
+	if len < 34 || len > b.len() {
+	    return Err(bad_data("bad directory record"));
+	}
+
+	let b = &b[..len];
+	let name_len = b[32] as usize;
+	// names of even length are followed by a padding byte
+	let su_start = 33 + name_len + (name_len + 1) % 2;
+
+	if su_start > len {
+	    return Err(bad_data("bad directory record"));
+	}
+
+	Ok(Self {
+	    extent:	le::<4>(b, 2)?,
+	    size:	le::<4>(b, 10)?,
+	    flags:	b[25],
+	    name:	&b[33..33 + name_len],
+	    system_use:	&b[su_start..],
+	})
+    }
+
+    fn is_special(&self) -> bool {
+	self.name == [0] || self.name == [1]
+    }
+}
+
+struct Reader<'a> {
+    file:	&'a std::fs::File,
+    block:	u64,
+    names:	Names,
+    visited:	HashSet<u64>,
+    res:	Index,
+}
+
+impl Reader<'_> {
+    /// Returns the name given by the Rock Ridge `NM` entries in the system
+    /// use area (and its continuation areas)
+    fn rr_name(&self, su: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+	let mut area = su.to_vec();
+	let mut res = None::<Vec<u8>>;
+
+	for _ in 0..MAX_CONTINUATIONS {
+	    let mut cont = None;
+	    let mut pos = 0;
+
+	    while pos + 4 <= area.len() {
+		let len = area[pos + 2] as usize;
+
+		if len < 4 || pos + len > area.len() {
+		    break;
+		}
+
+		let e = &area[pos..pos + len];
+
+		match &e[0..2] {
+		    // CURRENT and PARENT flags are not relevant for files
+		    b"NM" if len > 5 && e[4] & 0x06 == 0	=>
+			res.get_or_insert_with(Vec::new).extend_from_slice(&e[5..]),
+		    b"CE" if len >= 28		=>
+			cont = Some((le::<4>(e, 4)? * self.block + le::<4>(e, 12)?, le::<4>(e, 20)?)),
+		    b"ST"			=> break,
+		    _				=> {},
+		}
+
+		pos += len;
+	    }
+
+	    match cont {
+		Some((offs, len))	=> area = read_at(self.file, offs, len)?,
+		None			=> break,
+	    }
+	}
+
+	Ok(res)
+    }
+
+    fn name(&self, rec: &DirRecord) -> std::io::Result<Vec<u8>> {
+	// strip the ';1' version and the trailing '.' of names without
+	// extension
+	fn strip_version(n: &[u8]) -> &[u8] {
+	    let n = n.split(|c| *c == b';').next().unwrap();
+
+	    n.strip_suffix(b".").unwrap_or(n)
+	}
+
+	match self.names {
+	    Names::RockRidge	=> {
+		if let Some(n) = self.rr_name(rec.system_use)? {
+		    return Ok(n);
+		}
+	    },
+	    Names::Joliet	=> {
+		let ucs2 = rec.name.chunks_exact(2)
+		    .map(|c| u16::from_be_bytes([c[0], c[1]]));
+		let name = char::decode_utf16(ucs2)
+		    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+		    .collect::<String>();
+
+		return Ok(strip_version(name.as_bytes()).to_vec());
+	    },
+	    Names::Plain	=> {},
+	}
+
+	Ok(strip_version(rec.name).to_vec())
+    }
+
+    fn walk(&mut self, extent: u64, size: u64, prefix: &[u8], depth: usize) -> std::io::Result<()> {
+	if depth > MAX_DEPTH || !self.visited.insert(extent) {
+	    warn!("ignoring loop or too deep directory in iso image");
+	    return Ok(());
+	}
+
+	let data = read_at(self.file, extent * self.block, size)?;
+	let mut pos = 0;
+	let mut in_multi_extent = false;
+
+	while pos < data.len() {
+	    // records do not cross sector boundaries; the remaining space is
+	    // zero filled
+	    if data[pos] == 0 {
+		pos = (pos / self.block as usize + 1) * self.block as usize;
+		continue;
+	    }
+
+	    let rec = DirRecord::parse(&data[pos..])?;
+
+	    pos += data[pos] as usize;
+
+	    if rec.is_special() {
+		continue;
+	    }
+
+	    if rec.flags & DirRecord::FLAG_MULTI_EXTENT != 0 || in_multi_extent {
+		// only the last extent has the flag cleared
+		in_multi_extent = rec.flags & DirRecord::FLAG_MULTI_EXTENT != 0;
+		debug!("ignoring multi-extent file {:?}", String::from_utf8_lossy(rec.name));
+		continue;
+	    }
+
+	    let mut path = prefix.to_vec();
+
+	    if !path.is_empty() {
+		path.push(b'/');
+	    }
+
+	    path.extend(self.name(&rec)?);
+
+	    if rec.flags & DirRecord::FLAG_DIRECTORY != 0 {
+		self.walk(rec.extent, rec.size, &path, depth + 1)?;
+	    } else if let Some(name) = member_name(&path) {
+		self.res.insert(name, Entry {
+		    offset:	rec.extent * self.block,
+		    size:	rec.size,
+		    csize:	rec.size,
+		    method:	Method::Stored,
+		    crc:	None,
+		});
+	    }
+	}
+
+	Ok(())
+    }
+}
+
+/// Returns whether the supplementary volume descriptor `vd` is a Joliet one
+fn is_joliet(vd: &[u8]) -> bool {
+    matches!(&vd[88..91], b"%/@" | b"%/C" | b"%/E")
+}
+
+/// Reads the files of an ISO 9660 image.  Rock Ridge names are preferred
+/// over Joliet names which are preferred over plain ISO 9660 names.
+pub fn read_iso(file: &std::fs::File, len: u64) -> std::io::Result<Index> {
+    let mut pvd = None;
+    let mut joliet = None;
+
+    for sector in 16.. {
+	if (sector + 1) * SECTOR > len {
+	    break;
+	}
+
+	let vd = read_at(file, sector * SECTOR, SECTOR)?;
+
+	if &vd[1..6] != b"CD001" {
+	    return Err(bad_data("bad volume descriptor"));
+	}
+
+	match vd[0] {
+	    1			=> pvd = Some(vd),
+	    2 if is_joliet(&vd)	=> joliet = Some(vd),
+	    255			=> break,
+	    _			=> {},
+	}
+    }
+
+    let pvd = pvd.ok_or_else(|| bad_data("missing primary volume descriptor"))?;
+    let block = le::<2>(&pvd, 128)?;
+
+    if !(512..=SECTOR).contains(&block) || !block.is_power_of_two() {
+	return Err(bad_data("bad logical block size"));
+    }
+
+    let root = DirRecord::parse(&pvd[156..190])?;
+
+    // Rock Ridge is announced by a SUSP 'SP' entry in the '.' record of the
+    // root directory
+    let first = read_at(file, root.extent * block, 255.min(root.size))?;
+    let is_rr = DirRecord::parse(&first)?.system_use.starts_with(b"SP");
+
+    let (names, root) = match (is_rr, &joliet) {
+	(true, _)		=> (Names::RockRidge, root),
+	(false, Some(vd))	=> (Names::Joliet, DirRecord::parse(&vd[156..190])?),
+	(false, None)		=> (Names::Plain, root),
+    };
+
+    trace!("reading iso image with {:?} names", names);
+
+    let mut reader = Reader {
+	file:		file,
+	block:		block,
+	names:		names,
+	visited:	HashSet::new(),
+	res:		Index::new(),
+    };
+
+    reader.walk(root.extent, root.size, b"", 0)?;
+
+    Ok(reader.res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn record(extent: u32, size: u32, flags: u8, name: &[u8], su: &[u8]) -> Vec<u8> {
+	let mut res = vec![0u8; 33];
+
+	res[2..6].copy_from_slice(&extent.to_le_bytes());
+	res[6..10].copy_from_slice(&extent.to_be_bytes());
+	res[10..14].copy_from_slice(&size.to_le_bytes());
+	res[14..18].copy_from_slice(&size.to_be_bytes());
+	res[25] = flags;
+	res[28] = 1;
+	res[31] = 1;
+	res[32] = name.len() as u8;
+	res.extend(name);
+
+	if name.len() & 1 == 0 {
+	    res.push(0);
+	}
+
+	res.extend(su);
+	res[0] = res.len() as u8;
+	res
+    }
+
+    fn nm(name: &str) -> Vec<u8> {
+	let mut res = vec![b'N', b'M', 5 + name.len() as u8, 1, 0];
+
+	res.extend(name.as_bytes());
+	res
+    }
+
+    fn ucs2(name: &str) -> Vec<u8> {
+	name.encode_utf16().flat_map(|c| c.to_be_bytes()).collect()
+    }
+
+    fn dir(entries: &[Vec<u8>]) -> Vec<u8> {
+	let mut res = entries.concat();
+
+	res.resize(2048, 0);
+	res
+    }
+
+    fn descriptor(typ: u8, root: &[u8], joliet: bool) -> Vec<u8> {
+	let mut res = vec![0u8; 2048];
+
+	res[0] = typ;
+	res[1..6].copy_from_slice(b"CD001");
+	res[6] = 1;
+	res[128..130].copy_from_slice(&2048u16.to_le_bytes());
+	res[130..132].copy_from_slice(&2048u16.to_be_bytes());
+
+	if joliet {
+	    res[88..91].copy_from_slice(b"%/E");
+	}
+
+	res[156..156 + root.len()].copy_from_slice(root);
+	res
+    }
+
+    /// Creates an image with `images/pxeboot/vmlinuz` (sectors 24-25)
+    fn iso_image(rr: bool, joliet: bool, data: &[u8]) -> Vec<u8> {
+	let sz = data.len() as u32;
+	let sp = match rr {
+	    true	=> b"SP\x07\x01\xbe\xef\x00".to_vec(),
+	    false	=> Vec::new(),
+	};
+	let nm = |n: &str| match rr {
+	    true	=> nm(n),
+	    false	=> Vec::new(),
+	};
+
+	let mut img = vec![0u8; 16 * 2048];
+
+	img.extend(descriptor(1, &record(20, 2048, 2, &[0], &[]), false));
+	img.extend(match joliet {
+	    true	=> descriptor(2, &record(22, 2048, 2, &[0], &[]), true),
+	    false	=> descriptor(255, &[], false),
+	});
+	img.extend(descriptor(255, &[], false));
+	img.resize(20 * 2048, 0);
+
+	// sector 20: root; 21: images; 26: images/pxeboot
+	img.extend(dir(&[
+	    record(20, 2048, 2, &[0], &sp),
+	    record(20, 2048, 2, &[1], &[]),
+	    record(21, 2048, 2, b"IMAGES", &nm("images")),
+	]));
+	img.extend(dir(&[
+	    record(21, 2048, 2, &[0], &[]),
+	    record(20, 2048, 2, &[1], &[]),
+	    record(26, 2048, 2, b"PXEBOOT", &nm("pxeboot")),
+	]));
+
+	// sector 22: joliet root; 23: joliet images
+	img.extend(dir(&[
+	    record(22, 2048, 2, &[0], &[]),
+	    record(22, 2048, 2, &[1], &[]),
+	    record(23, 2048, 2, &ucs2("images"), &[]),
+	]));
+	img.extend(dir(&[
+	    record(23, 2048, 2, &[0], &[]),
+	    record(22, 2048, 2, &[1], &[]),
+	    record(27, 2048, 2, &ucs2("pxeboot"), &[]),
+	]));
+
+	let mut file = data.to_vec();
+
+	file.resize(2 * 2048, 0);
+	img.extend(file);
+
+	img.extend(dir(&[
+	    record(26, 2048, 2, &[0], &[]),
+	    record(21, 2048, 2, &[1], &[]),
+	    record(24, sz, 0, b"VMLINUZ.;1", &nm("vmlinuz")),
+	    // loop back to the root directory
+	    record(20, 2048, 2, b"LOOP", &nm("loop")),
+	]));
+	img.extend(dir(&[
+	    record(27, 2048, 2, &[0], &[]),
+	    record(23, 2048, 2, &[1], &[]),
+	    record(24, sz, 0, &ucs2("vmlinuz;1"), &[]),
+	]));
+
+	img
+    }
+
+    #[test]
+    fn test_iso() {
+	use std::io::Write;
+
+	let data: Vec<u8> = (0..3000_u32).map(|v| (v * 13) as u8).collect();
+
+	let index = |rr, joliet| {
+	    let mut f = tempfile::tempfile().unwrap();
+
+	    f.write_all(&iso_image(rr, joliet, &data)).unwrap();
+
+	    let len = f.metadata().unwrap().len();
+
+	    read_iso(&f, len).unwrap()
+	};
+
+	let expected = Entry {
+	    offset:	24 * 2048,
+	    size:	data.len() as u64,
+	    csize:	data.len() as u64,
+	    method:	Method::Stored,
+	    crc:	None,
+	};
+
+	let rr = index(true, true);
+	assert_eq!(rr.get(Path::new("images/pxeboot/vmlinuz")), Some(&expected));
+	assert_eq!(rr.len(), 1);
+
+	let joliet = index(false, true);
+	assert_eq!(joliet.get(Path::new("images/pxeboot/vmlinuz")), Some(&expected));
+	assert_eq!(joliet.len(), 1);
+
+	let plain = index(false, false);
+	assert_eq!(plain.get(Path::new("IMAGES/PXEBOOT/VMLINUZ")), Some(&expected));
+	assert_eq!(plain.len(), 1);
+    }
+}
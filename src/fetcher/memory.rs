@@ -1,21 +1,35 @@
 use std::mem::MaybeUninit;
+use std::sync::Arc;
 
 use crate::Result;
 use crate::util::CopyInit;
 
 #[derive(Debug)]
 pub struct Memory {
-    buf:	Vec<u8>,
+    buf:	Arc<[u8]>,
     pos:	usize,
 }
 
-impl Memory {
-    pub fn new(data: &[u8]) -> Self {
+impl From<Vec<u8>> for Memory {
+    fn from(buf: Vec<u8>) -> Self {
+	Self::from(Arc::<[u8]>::from(buf))
+    }
+}
+
+/// Shares the data without copying it (e.g. with the [`super::MemoryStore`])
+impl From<Arc<[u8]>> for Memory {
+    fn from(buf: Arc<[u8]>) -> Self {
 	Self {
-	    buf:	Vec::from(data),
+	    buf:	buf,
 	    pos:	0,
 	}
     }
+}
+
+impl Memory {
+    pub fn new(data: &[u8]) -> Self {
+	Self::from(Arc::<[u8]>::from(data))
+    }
 
     pub fn open(&mut self) -> Result<()> {
 	Ok(())
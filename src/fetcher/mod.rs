@@ -5,13 +5,32 @@ mod builder;
 mod fetcher;
 mod file;
 mod memory;
+mod icase;
+mod pxe;
+mod template;
+mod exec;
+mod archive;
+mod iso9660;
+mod decompress;
+mod backend;
+mod store;
+mod filecache;
 
 
-pub use builder::Builder;
+pub use builder::{ Builder, is_uri, normalize_path };
 pub use fetcher::Fetcher;
+pub use store::MemoryStore;
+pub use filecache::{ FileCache, FileCacheStats };
+pub use backend::{ Backend, BackendFactory, Backends, Target as BackendTarget };
 
 use file::File;
 use memory::Memory;
+use template::Template;
+use exec::Exec;
+use archive::Archive;
+use decompress::Compressed;
+
+pub use exec::Limits as ExecLimits;
 
 #[cfg(feature = "proxy")]
 use r_tftpd_proxy::*;
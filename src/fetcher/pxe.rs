@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+use std::path::{ Path, PathBuf };
+
+use crate::util::Client;
+
+/// Returns the names probed by PXELINUX for `client` in the directory
+/// of `path`; i.e. `01-<mac>`, the hex ip address with decreasing
+/// length and `default`.
+pub fn candidates(path: &Path, client: &Client) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut res = Vec::with_capacity(10);
+
+    if let Some(mac) = &client.mac {
+	res.push(dir.join(format!("01-{}", mac.format("-"))));
+    }
+
+    if let IpAddr::V4(ip) = client.remote.to_canonical() {
+	let hex = format!("{:08X}", u32::from(ip));
+
+	for len in (1..=hex.len()).rev() {
+	    res.push(dir.join(&hex[..len]));
+	}
+    }
+
+    res.push(dir.join("default"));
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+	let client = Client {
+	    remote:	"::ffff:192.168.1.10".parse().unwrap(),
+	    local:	"192.168.1.1".parse().unwrap(),
+	    mac:	Some("88:99:aa:bb:cc:dd".parse().unwrap()),
+	};
+
+	let names: Vec<PathBuf> = [
+	    "pxelinux.cfg/01-88-99-aa-bb-cc-dd",
+	    "pxelinux.cfg/C0A8010A",
+	    "pxelinux.cfg/C0A8010",
+	    "pxelinux.cfg/C0A801",
+	    "pxelinux.cfg/C0A80",
+	    "pxelinux.cfg/C0A8",
+	    "pxelinux.cfg/C0A",
+	    "pxelinux.cfg/C0",
+	    "pxelinux.cfg/C",
+	    "pxelinux.cfg/default",
+	].iter().map(PathBuf::from).collect();
+
+	assert_eq!(candidates(Path::new("pxelinux.cfg/auto"), &client), names);
+
+	let client = Client {
+	    remote:	"fd00::1".parse().unwrap(),
+	    local:	"fd00::2".parse().unwrap(),
+	    mac:	None,
+	};
+
+	assert_eq!(candidates(Path::new("auto"), &client), vec![PathBuf::from("default")]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, RwLock };
+use std::time::{ Duration, Instant };
+
+use crate::Result;
+
+#[derive(Debug)]
+struct Entry {
+    data:	Arc<[u8]>,
+    expires:	Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+	self.expires.is_some_and(|e| e <= now)
+    }
+}
+
+/// Named in-memory files which are served before files from the
+/// filesystem.
+///
+/// The store is shared between its clones; it can be modified while the
+/// server is running.  Transfers which are already running are not
+/// affected by `insert()` or `remove()`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore(Arc<RwLock<HashMap<PathBuf, Entry>>>);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+	Self::default()
+    }
+
+    /// Adds or replaces the file `name`; it is removed after `expiry` when
+    /// given.
+    pub fn insert<P, D>(&self, name: P, data: D, expiry: Option<Duration>) -> Result<()>
+    where
+	P: AsRef<Path>,
+	D: Into<Arc<[u8]>>,
+    {
+	let name = super::normalize_path(name.as_ref())?;
+	let now = Instant::now();
+	let entry = Entry {
+	    data:	data.into(),
+	    expires:	expiry.map(|e| now + e),
+	};
+
+	debug!("storing {:?} ({} bytes, expiry {:?})", name, entry.data.len(), expiry);
+
+	let mut store = self.0.write().unwrap();
+
+	// expired files which are never requested again would stay forever
+	store.retain(|_, e| !e.is_expired(now));
+	store.insert(name, entry);
+
+	Ok(())
+    }
+
+    /// Removes the file `name`; returns whether it existed
+    pub fn remove<P: AsRef<Path>>(&self, name: P) -> bool {
+	let Ok(name) = super::normalize_path(name.as_ref()) else {
+	    return false;
+	};
+
+	let mut store = self.0.write().unwrap();
+
+	match store.remove(&name) {
+	    Some(e)	=> !e.is_expired(Instant::now()),
+	    None	=> false,
+	}
+    }
+
+    /// Returns the content of the file `name` unless it does not exist or
+    /// is expired.  `name` must be normalized.
+    pub fn get(&self, name: &Path) -> Option<Arc<[u8]>> {
+	let now = Instant::now();
+	let store = self.0.read().unwrap();
+
+	match store.get(name) {
+	    None			=> None,
+	    Some(e) if !e.is_expired(now)	=> Some(e.data.clone()),
+	    Some(_)			=> {
+		drop(store);
+
+		// the file might have been replaced in the meantime
+		let mut store = self.0.write().unwrap();
+
+		if store.get(name).is_some_and(|e| e.is_expired(now)) {
+		    store.remove(name);
+		}
+
+		None
+	    },
+	}
+    }
+
+    /// Removes all expired files
+    pub fn purge(&self) {
+	let now = Instant::now();
+
+	self.0.write().unwrap().retain(|_, e| !e.is_expired(now));
+    }
+
+    /// Returns the names of all files which are not expired
+    pub fn names(&self) -> Vec<PathBuf> {
+	let now = Instant::now();
+
+	self.0.read().unwrap().iter()
+	    .filter(|(_, e)| !e.is_expired(now))
+	    .map(|(n, _)| n.clone())
+	    .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.0.read().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_store() {
+	let store = MemoryStore::new();
+	let other = store.clone();
+
+	store.insert("/boot/token", b"abc".as_slice(), None).unwrap();
+	store.insert("boot/expired", b"old".as_slice(), Some(Duration::ZERO)).unwrap();
+	store.insert("boot/later", vec![1, 2, 3], Some(Duration::from_secs(3600))).unwrap();
+
+	assert!(store.insert("../escape", b"x".as_slice(), None).is_err());
+
+	assert_eq!(other.get(Path::new("boot/token")).as_deref(), Some(b"abc".as_slice()));
+	assert_eq!(other.get(Path::new("boot/later")).as_deref(), Some([1, 2, 3].as_slice()));
+	assert!(other.get(Path::new("boot/expired")).is_none());
+
+	let data = store.get(Path::new("boot/token")).unwrap();
+
+	store.insert("boot/token", b"new".as_slice(), None).unwrap();
+
+	// replacing does not affect data which is already in use
+	assert_eq!(&*data, b"abc");
+	assert_eq!(other.get(Path::new("boot/token")).as_deref(), Some(b"new".as_slice()));
+
+	let mut names = store.names();
+
+	names.sort();
+	assert_eq!(names, [PathBuf::from("boot/later"), PathBuf::from("boot/token")]);
+
+	assert!(store.remove("/boot/token"));
+	assert!(!store.remove("/boot/token"));
+	assert!(other.get(Path::new("boot/token")).is_none());
+
+	store.purge();
+	assert!(!store.is_empty());
+
+	// inserting purges expired files
+	store.insert("boot/expired", b"old".as_slice(), Some(Duration::ZERO)).unwrap();
+	store.insert("boot/other", b"x".as_slice(), Some(Duration::ZERO)).unwrap();
+	assert!(!store.0.read().unwrap().contains_key(Path::new("boot/expired")));
+    }
+}
@@ -0,0 +1,225 @@
+use std::mem::MaybeUninit;
+
+use crate::{ Error, Result };
+use crate::tftp::Request;
+use crate::util::Client;
+
+use super::{ File, Memory };
+
+/// Maximum size of a template file
+const MAX_SIZE: usize = 1024 * 1024;
+
+/// Suffix of template files; a template `foo.tmpl` is rendered when `foo`
+/// is requested but does not exist
+pub const SUFFIX: &str = ".tmpl";
+
+/// Variables which are available in templates
+#[derive(Clone, Debug, Default)]
+pub struct Vars(Vec<(&'static str, String)>);
+
+impl Vars {
+    pub fn new(client: Option<&Client>, req: Option<&Request<'_>>) -> Self {
+	fn opt<T: ToString>(v: Option<T>) -> String {
+	    v.map(|v| v.to_string()).unwrap_or_default()
+	}
+
+	let mut res = Vec::new();
+
+	if let Some(client) = client {
+	    let remote_hex = match client.remote {
+		std::net::IpAddr::V4(ip)	=> format!("{:08X}", u32::from(ip)),
+		std::net::IpAddr::V6(ip)	=> format!("{:032X}", u128::from(ip)),
+	    };
+
+	    res.extend([
+		("remote_ip",		client.remote.to_string()),
+		("remote_ip_hex",	remote_hex),
+		("local_ip",		client.local.to_string()),
+		("mac",			opt(client.mac)),
+		("mac_dash",		opt(client.mac.map(|m| m.format("-")))),
+	    ]);
+	}
+
+	if let Some(req) = req {
+	    res.extend([
+		("filename",		req.get_filename().to_string_lossy().into_owned()),
+		("blksize",		opt(req.block_size)),
+		("windowsize",		opt(req.window_size)),
+		("timeout",		opt(req.timeout.map(|t| t.as_secs()))),
+		("tsize",		opt(req.tsize)),
+	    ]);
+	}
+
+	Self(res)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+	self.0.iter().map(|(n, v)| (*n, v.as_str()))
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+	self.0.iter()
+	    .find(|(n, _)| *n == name)
+	    .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Replaces `{{ name }}` placeholders in `tmpl` by the value of the
+/// corresponding variable; unknown variables are an error.
+pub fn render(tmpl: &[u8], vars: &Vars) -> Result<Vec<u8>> {
+    let mut res = Vec::with_capacity(tmpl.len());
+    let mut tmpl = tmpl;
+
+    while let Some(start) = tmpl.windows(2).position(|w| w == b"{{") {
+	res.extend_from_slice(&tmpl[..start]);
+
+	let rest = &tmpl[start + 2..];
+	let end = rest.windows(2).position(|w| w == b"}}")
+	    .ok_or_else(|| Error::Template("unterminated placeholder".into()))?;
+
+	let name = std::str::from_utf8(&rest[..end])
+	    .map_err(|_| Error::Template("bad placeholder".into()))?
+	    .trim();
+
+	let value = vars.get(name)
+	    .ok_or_else(|| Error::Template(format!("unknown variable '{name}'").into()))?;
+
+	res.extend_from_slice(value.as_bytes());
+	tmpl = &rest[end + 2..];
+    }
+
+    res.extend_from_slice(tmpl);
+
+    Ok(res)
+}
+
+/// A file which is rendered with client specific variables and served from
+/// memory
+#[derive(Debug)]
+pub struct Template {
+    file:	File,
+    vars:	Vars,
+    data:	Option<Memory>,
+}
+
+impl Template {
+    pub fn new(file: File, vars: Vars) -> Self {
+	Self {
+	    file:	file,
+	    vars:	vars,
+	    data:	None,
+	}
+    }
+
+    pub async fn open(&mut self) -> Result<()> {
+	if self.data.is_some() {
+	    return Err(Error::Internal("template already opened"));
+	}
+
+	self.file.open()?;
+
+	let mut tmpl = Vec::<u8>::new();
+
+	while !self.file.is_eof() {
+	    if tmpl.len() > MAX_SIZE {
+		return Err(Error::Template("template too large".into()));
+	    }
+
+	    tmpl.reserve(64 * 1024);
+
+	    let sz = self.file.read(tmpl.spare_capacity_mut()).await?.len();
+
+	    // SAFETY: 'sz' bytes of the spare capacity have been initialized
+	    // by the read above
+	    unsafe { tmpl.set_len(tmpl.len() + sz) };
+	}
+
+	self.data = Some(Memory::new(&render(&tmpl, &self.vars)?));
+
+	Ok(())
+    }
+
+    fn data(&self) -> &Memory {
+	self.data.as_ref().unwrap()
+    }
+
+    pub fn get_size(&self) -> Option<u64> {
+	self.data().get_size()
+    }
+
+    pub async fn read<'a>(&mut self, buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8]>
+    {
+	self.data.as_mut().unwrap().read(buf).await
+    }
+
+    pub fn read_mmap(&mut self, cnt: usize) -> Result<&[u8]>
+    {
+	self.data.as_mut().unwrap().read_mmap(cnt)
+    }
+
+    pub fn is_eof(&self) -> bool
+    {
+	self.data().is_eof()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client() -> Client {
+	Client {
+	    remote:	"192.168.1.10".parse().unwrap(),
+	    local:	"192.168.1.1".parse().unwrap(),
+	    mac:	Some("88:99:aa:bb:cc:dd".parse().unwrap()),
+	}
+    }
+
+    #[test]
+    fn test_render() {
+	use crate::tftp::RequestDir;
+
+	let client = client();
+	let req = Request::from_slice(b"pxelinux.cfg/default\0octet\0blksize\x001428\0", RequestDir::Read)
+	    .unwrap();
+	let vars = Vars::new(Some(&client), Some(&req));
+
+	let render = |s: &str| render(s.as_bytes(), &vars)
+	    .map(|r| String::from_utf8(r).unwrap());
+
+	assert_eq!(render("no placeholders").unwrap(), "no placeholders");
+	assert_eq!(render("APPEND ip={{remote_ip}}::{{ local_ip }} BOOTIF=01-{{mac_dash}}").unwrap(),
+		   "APPEND ip=192.168.1.10::192.168.1.1 BOOTIF=01-88-99-aa-bb-cc-dd");
+	assert_eq!(render("{{remote_ip_hex}} {{mac}}").unwrap(),
+		   "C0A8010A 88:99:aa:bb:cc:dd");
+	assert_eq!(render("{{filename}} {{blksize}}/{{tsize}}").unwrap(),
+		   "pxelinux.cfg/default 1428/");
+	assert_eq!(render("chain ${next}").unwrap(), "chain ${next}");
+
+	assert!(matches!(render("{{unknown}}"), Err(Error::Template(_))));
+	assert!(matches!(render("{{remote_ip"), Err(Error::Template(_))));
+    }
+
+    #[tokio::test]
+    async fn test_template() {
+	use tempfile::TempDir;
+	use std::path::Path;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path();
+
+	std::fs::write(tmp_path.join("boot.ipxe.tmpl"), "#!ipxe\nset mac {{mac}}\n").unwrap();
+
+	let client = client();
+	let file = File::new(tmp_path, Path::new("boot.ipxe.tmpl"), false);
+	let mut tmpl = Template::new(file, Vars::new(Some(&client), None));
+
+	tmpl.open().await.unwrap();
+
+	let expected = b"#!ipxe\nset mac 88:99:aa:bb:cc:dd\n";
+
+	assert_eq!(tmpl.get_size(), Some(expected.len() as u64));
+	assert_eq!(tmpl.read_mmap(1024).unwrap(), expected);
+	assert!(tmpl.is_eof());
+    }
+}
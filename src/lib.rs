@@ -0,0 +1,436 @@
+#![allow(clippy::redundant_field_names)]
+//#![allow(dead_code)]
+//#![allow(unused_variables)]
+
+#[macro_use]
+extern crate tracing;
+
+mod tftp;
+mod acl;
+mod vhost;
+mod rewrite;
+mod server;
+pub mod codec;
+pub mod errors;
+pub mod util;
+pub mod fetcher;
+pub mod sandbox;
+
+use std::sync::Arc;
+use std::time::Duration;
+use util::{ UdpSocket, UdpRecvInfo, Bucket, ToFormatted };
+
+use tftp::Session;
+
+pub use errors::{ Error, Result };
+pub use server::{ Config, Server, Listen, SessionCallback };
+pub use tftp::{ SessionStats, SessionDirection };
+pub use acl::Rule as AclRule;
+pub use vhost::Spec as VHostSpec;
+
+#[cfg(test)]
+mod test;
+
+pub struct Environment {
+    dir:		std::path::PathBuf,
+    cache_dir:		std::path::PathBuf,
+    fallback_uri:	Option<std::ffi::OsString>,
+    max_block_size:	u16,
+    max_window_size:	u16,
+    max_connections:	u32,
+    timeout:		Duration,
+    no_rfc2347:		bool,
+    wrq_devnull:	bool,
+    world_readable:	bool,
+    allow_dotfiles:	bool,
+    case_insensitive:	bool,
+    pxe_search:		Vec<std::path::PathBuf>,
+    templates:		bool,
+    decompress:		bool,
+    exec_dir:		Option<std::path::PathBuf>,
+    exec_limits:	fetcher::ExecLimits,
+    backends:		fetcher::Backends,
+    store:		fetcher::MemoryStore,
+    file_cache:		Option<fetcher::FileCache>,
+    snapshot:		bool,
+    acl:		acl::Acl,
+    rewrite:		rewrite::Rewrite,
+    vhosts:		Vec<vhost::VHost>,
+    privileges:		sandbox::PrivDrop,
+
+    #[cfg(feature = "proxy")]
+    allow_uri:		bool,
+    #[cfg(feature = "proxy")]
+    persistent_cache:	bool,
+    #[cfg(feature = "proxy")]
+    cache_gc:		fetcher::CacheGcProperties,
+
+    #[cfg(feature = "sandbox")]
+    sandbox:		bool,
+}
+
+impl Environment {
+    pub fn allow_uri(&self) -> bool {
+	#[cfg(feature = "proxy")]
+	return self.allow_uri;
+
+	#[cfg(not(feature = "proxy"))]
+	false
+    }
+
+    /// Returns the directory for snapshots of served files when they are
+    /// enabled
+    fn snapshot_dir(&self) -> Option<std::path::PathBuf> {
+	self.snapshot.then(|| self.cache_dir.join("snapshots"))
+    }
+
+    /// Creates the snapshot directories of this environment and its
+    /// virtual hosts; they are private to the (possibly dropped) user.
+    fn create_snapshot_dirs(&self) -> Result<()> {
+	use std::os::unix::fs::DirBuilderExt;
+
+	if let Some(dir) = self.snapshot_dir() {
+	    std::fs::DirBuilder::new()
+		.recursive(true)
+		.mode(0o700)
+		.create(dir)?;
+	}
+
+	for v in &self.vhosts {
+	    v.env.create_snapshot_dirs()?;
+	}
+
+	Ok(())
+    }
+
+    /// Returns the restrictions for serving files from this environment
+    /// and its virtual hosts
+    #[cfg(feature = "sandbox")]
+    fn get_sandbox(&self) -> sandbox::Sandbox {
+	let mut res = sandbox::Sandbox::default();
+
+	self.add_sandbox_rules(&mut res);
+
+	// fallback for MAC address lookups when netlink is not available; it
+	// does not exist within a chroot
+	if self.privileges.chroot.is_none() {
+	    res.ro_paths.push("/proc/net/arp".into());
+	}
+
+	res
+    }
+
+    #[cfg(feature = "sandbox")]
+    fn add_sandbox_rules(&self, res: &mut sandbox::Sandbox) {
+	res.ro_paths.push(self.dir.clone());
+
+	if self.allow_uri() {
+	    res.rw_paths.push(self.cache_dir.clone());
+	    res.allow_http = true;
+	}
+
+	#[cfg(feature = "proxy")]
+	{
+	    res.persistent_cache |= self.allow_uri() && self.persistent_cache;
+	}
+
+	if let Some(dir) = &self.exec_dir {
+	    res.ro_paths.push(dir.clone());
+	    res.allow_exec = true;
+	}
+
+	if let Some(dir) = self.snapshot_dir() {
+	    res.rw_paths.push(dir);
+	    res.snapshot = true;
+	}
+
+	match &self.fallback_uri {
+	    Some(f) if !fetcher::is_uri(f)	=> {
+		// fallback is a path prefix; allow access to its directory
+		let f = std::path::Path::new(f);
+
+		res.ro_paths.push(match f.as_os_str().to_string_lossy().ends_with('/') {
+		    true	=> f.into(),
+		    false	=> f.parent().unwrap_or(f).into(),
+		});
+	    }
+	    _					=> {},
+	}
+
+	for v in &self.vhosts {
+	    v.env.add_sandbox_rules(res);
+	}
+    }
+
+    /// Updates paths so that they are valid after a `chroot(root)`
+    fn rebase(&mut self, root: &std::path::Path) -> Result<()> {
+	use sandbox::rebase_path;
+
+	self.dir = rebase_path(root, &self.dir)?;
+	self.cache_dir = rebase_path(root, &self.cache_dir)?;
+
+	if let Some(d) = self.exec_dir.take() {
+	    self.exec_dir = Some(rebase_path(root, &d)?);
+	}
+
+	self.fallback_uri = match self.fallback_uri.take() {
+	    Some(f) if !fetcher::is_uri(&f)	=> {
+		use std::os::unix::ffi::OsStrExt;
+
+		// fallback is a path prefix; preserve a trailing '/'
+		let has_slash = f.as_bytes().ends_with(b"/");
+		let mut f = rebase_path(root, f.as_ref())?.into_os_string();
+
+		if has_slash && !f.as_bytes().ends_with(b"/") {
+		    f.push("/");
+		}
+
+		Some(f)
+	    },
+	    f					=> f,
+	};
+
+	for v in &mut self.vhosts {
+	    v.env.rebase(root)?;
+	}
+
+	Ok(())
+    }
+
+    /// Creates the environment for a virtual host; settings which are not
+    /// given by `spec` are inherited.
+    fn new_vhost(&self, spec: vhost::Spec) -> vhost::VHost {
+	let env = Self {
+	    dir:		spec.dir.unwrap_or_else(|| self.dir.clone()),
+	    cache_dir:		self.cache_dir.clone(),
+	    fallback_uri:	spec.fallback_uri.or_else(|| self.fallback_uri.clone()),
+	    max_block_size:	spec.max_block_size.unwrap_or(self.max_block_size),
+	    max_window_size:	spec.max_window_size.unwrap_or(self.max_window_size),
+	    max_connections:	spec.max_connections.unwrap_or(self.max_connections),
+	    timeout:		spec.timeout.unwrap_or(self.timeout),
+	    no_rfc2347:		self.no_rfc2347,
+	    wrq_devnull:	self.wrq_devnull,
+	    world_readable:	self.world_readable,
+	    allow_dotfiles:	self.allow_dotfiles,
+	    case_insensitive:	self.case_insensitive,
+	    pxe_search:		self.pxe_search.clone(),
+	    templates:		self.templates,
+	    decompress:		self.decompress,
+	    exec_dir:		self.exec_dir.clone(),
+	    exec_limits:	self.exec_limits.clone(),
+	    backends:		self.backends.clone(),
+	    store:		self.store.clone(),
+	    file_cache:		self.file_cache.clone(),
+	    snapshot:		self.snapshot,
+	    acl:		self.acl.clone(),
+	    rewrite:		self.rewrite.clone(),
+	    vhosts:		Vec::new(),
+	    privileges:		Default::default(),
+
+	    #[cfg(feature = "proxy")]
+	    allow_uri:		self.allow_uri,
+	    #[cfg(feature = "proxy")]
+	    persistent_cache:	self.persistent_cache,
+	    #[cfg(feature = "proxy")]
+	    cache_gc:		self.cache_gc.clone(),
+
+	    #[cfg(feature = "sandbox")]
+	    sandbox:		false,
+	};
+
+	vhost::VHost {
+	    local:	spec.local,
+	    iface:	spec.iface,
+	    env:	env,
+	}
+    }
+
+    /// Returns the environment responsible for a request together with its
+    /// index (0 for the default one, `i + 1` for `vhosts[i]`)
+    fn select_vhost(&self, info: &UdpRecvInfo) -> (usize, &Self) {
+	let mut iface = None;
+
+	let pos = self.vhosts.iter().position(|v| {
+	    v.matches(&info.local,
+		      || iface.get_or_insert_with(|| util::if_name(info.if_idx)).clone())
+	});
+
+	match pos {
+	    Some(idx)	=> (idx + 1, &self.vhosts[idx].env),
+	    None	=> (0, self),
+	}
+    }
+}
+
+struct SpeedInfo<'a> {
+    duration:		Duration,
+    stats:		&'a SessionStats,
+}
+
+impl <'a> SpeedInfo<'a> {
+    pub fn new(now: std::time::Instant, stats: &'a SessionStats) -> Self {
+	Self {
+	    duration:	now.elapsed(),
+	    stats:	stats,
+	}
+    }
+}
+
+impl std::fmt::Display for SpeedInfo<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	write!(f, "duration={} ms", self.duration.as_millis().to_formatted())?;
+
+	match self.stats.speed_bit_per_s(self.duration) {
+	    None			=> Ok(()),
+	    Some((speed_f, speed_n)) if speed_f == speed_n	=>
+		write!(f, " => total={} bytes/s",
+		       (speed_f as u64).to_formatted()),
+
+	    Some((_, speed_n)) if !self.stats.is_complete	=>
+		write!(f, " => net={} bytes/s", (speed_n as u64).to_formatted()),
+
+	    Some((speed_f, speed_n))	=>
+		write!(f, " => file={} bytes/s, net={} bytes/s",
+		       (speed_f as u64).to_formatted(),
+		       (speed_n as u64).to_formatted()),
+	}
+    }
+}
+
+async fn sigusr1_handler(mut stream: tokio::signal::unix::Signal,
+			 file_cache: Option<fetcher::FileCache>)
+{
+    loop {
+	stream.recv().await;
+	debug!("got SIGUSR1");
+
+	if let Some(cache) = &file_cache {
+	    let stats = cache.stats();
+
+	    info!("file cache: {} entries, {} bytes, {} hits, {} misses",
+		  stats.entries, stats.size.to_formatted(), stats.hits, stats.misses);
+	}
+
+	#[cfg(feature = "proxy")]
+	fetcher::Cache::dump().await;
+    }
+}
+
+async fn sigusr2_handler(mut stream: tokio::signal::unix::Signal,
+			 file_cache: Option<fetcher::FileCache>)
+{
+    loop {
+	stream.recv().await;
+	debug!("got SIGUSR2");
+
+	if let Some(cache) = &file_cache {
+	    cache.clear();
+	}
+
+	#[cfg(feature = "proxy")]
+	fetcher::Cache::clear().await;
+    }
+}
+
+fn init_sighandlers(file_cache: Option<fetcher::FileCache>) -> Result<()>
+{
+    use tokio::signal::unix::{ signal, SignalKind };
+
+    let stream = signal(SignalKind::from_raw(nix::libc::SIGUSR1))?;
+    tokio::spawn(sigusr1_handler(stream, file_cache.clone()));
+
+    let stream = signal(SignalKind::from_raw(nix::libc::SIGUSR2))?;
+    tokio::spawn(sigusr2_handler(stream, file_cache));
+
+    Ok(())
+}
+
+use tracing::field::Empty;
+
+#[instrument(skip_all,
+	     fields(id = id,
+		    remote = Empty,
+		    local = Empty,
+		    mac = Empty,
+		    vhost = Empty,
+		    filename = Empty,
+		    op = Empty))]
+async fn handle_request(env: std::sync::Arc<Environment>,
+			id: u64,
+			info: UdpRecvInfo,
+			req: Vec<u8>,
+			buckets: Arc<Vec<Bucket>>,
+			on_session: Option<SessionCallback>)
+{
+    let instant = std::time::Instant::now();
+    let (vhost_idx, vhost_env) = env.select_vhost(&info);
+
+    if vhost_idx > 0 {
+	tracing::Span::current().record("vhost", env.vhosts[vhost_idx - 1].get_name());
+    }
+
+    let bucket = &buckets[vhost_idx];
+    let session = Session::new(vhost_env, &info).await;
+
+    if let Err(e) = session {
+	warn!("failed to create tftp session: {:?}", e);
+	return;
+    }
+
+    let session = session.unwrap();
+
+    let b = bucket.acquire();
+
+    let res = match b.is_some() {
+	false	=> session.do_reject().await,
+	true	=> session.run(req).await
+    };
+
+    match res {
+	Ok(stats)	=> {
+	    info!(parent: tracing::Span::none(),
+		  "conn#{}: {}, {}", id, &stats, SpeedInfo::new(instant, &stats));
+
+	    if let Some(cb) = on_session {
+		cb(&stats);
+	    }
+	},
+	Err(e)	=> error!("request failed: {:?}", e),
+    };
+}
+
+fn create_buckets(env: &Environment) -> Vec<Bucket> {
+    std::iter::once(env.max_connections)
+	.chain(env.vhosts.iter().map(|v| v.env.max_connections))
+	.map(Bucket::new)
+	.collect()
+}
+
+async fn run_tftpd_loop(env: std::sync::Arc<Environment>, sock: UdpSocket,
+			mut shutdown: tokio::sync::watch::Receiver<bool>,
+			buckets: Arc<Vec<Bucket>>,
+			on_session: Option<SessionCallback>) -> Result<()> {
+    let mut buf = vec![0u8; 1500];
+    let mut sessions = tokio::task::JoinSet::new();
+    let mut num = 0;
+
+    loop {
+	let info = tokio::select! {
+	    info = sock.recvmsg(&mut buf)	=> info?,
+	    Ok(_) = shutdown.wait_for(|v| *v)	=> break,
+	    Some(_) = sessions.join_next()	=> continue,
+	};
+
+	let request = Vec::from(&buf[..info.size]);
+
+	sessions.spawn(handle_request(env.clone(), num, info,
+				      request, buckets.clone(), on_session.clone()));
+
+	num += 1;
+    }
+
+    // let running transfers finish
+    while sessions.join_next().await.is_some() {}
+
+    Ok(())
+}
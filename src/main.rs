@@ -1,220 +1,12 @@
-#![allow(clippy::redundant_field_names)]
-//#![allow(dead_code)]
-//#![allow(unused_variables)]
-
-#[macro_use]
-extern crate tracing;
-
-mod tftp;
-pub mod errors;
-pub mod util;
-pub mod fetcher;
-
 use std::os::fd::{OwnedFd, FromRawFd};
-use std::sync::Arc;
 use std::time::Duration;
-use util::{ UdpSocket, UdpRecvInfo, SocketAddr, Bucket, ToFormatted };
-
-use tftp::{ Session, SessionStats };
-
-pub use errors::{ Error, Result };
-
-#[cfg(test)]
-mod test;
-
-pub struct Environment {
-    dir:		std::path::PathBuf,
-    cache_dir:		std::path::PathBuf,
-    fallback_uri:	Option<std::ffi::OsString>,
-    max_block_size:	u16,
-    max_window_size:	u16,
-    max_connections:	u32,
-    timeout:		Duration,
-    no_rfc2347:		bool,
-    wrq_devnull:	bool,
-
-    #[cfg(feature = "proxy")]
-    allow_uri:		bool,
-}
-
-impl Environment {
-    pub fn allow_uri(&self) -> bool {
-	#[cfg(feature = "proxy")]
-	return self.allow_uri;
-
-        #[cfg(not(feature = "proxy"))]
-	false
-    }
-}
-
-struct SpeedInfo<'a> {
-    duration:		Duration,
-    stats:		&'a SessionStats,
-}
-
-impl <'a> SpeedInfo<'a> {
-    pub fn new(now: std::time::Instant, stats: &'a SessionStats) -> Self {
-	Self {
-	    duration:	now.elapsed(),
-	    stats:	stats,
-	}
-    }
-}
-
-impl std::fmt::Display for SpeedInfo<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-	write!(f, "duration={} ms", self.duration.as_millis().to_formatted())?;
-
-	match self.stats.speed_bit_per_s(self.duration) {
-	    None			=> Ok(()),
-	    Some((speed_f, speed_n)) if speed_f == speed_n	=>
-		write!(f, " => total={} bytes/s",
-		       (speed_f as u64).to_formatted()),
-
-	    Some((_, speed_n)) if !self.stats.is_complete	=>
-		write!(f, " => net={} bytes/s", (speed_n as u64).to_formatted()),
-
-	    Some((speed_f, speed_n))	=>
-		write!(f, " => file={} bytes/s, net={} bytes/s",
-		       (speed_f as u64).to_formatted(),
-		       (speed_n as u64).to_formatted()),
-	}
-    }
-}
-
-async fn sigusr1_handler(mut stream: tokio::signal::unix::Signal)
-{
-    loop {
-	stream.recv().await;
-	debug!("got SIGUSR1");
-
-	#[cfg(feature = "proxy")]
-	fetcher::Cache::dump().await;
-    }
-}
-
-async fn sigusr2_handler(mut stream: tokio::signal::unix::Signal)
-{
-    loop {
-	stream.recv().await;
-	debug!("got SIGUSR2");
-
-	#[cfg(feature = "proxy")]
-	fetcher::Cache::clear().await;
-    }
-}
-
-fn init_sighandlers() -> Result<()>
-{
-    use tokio::signal::unix::{ signal, SignalKind };
-
-    let stream = signal(SignalKind::from_raw(nix::libc::SIGUSR1))?;
-    tokio::spawn(sigusr1_handler(stream));
-
-    let stream = signal(SignalKind::from_raw(nix::libc::SIGUSR2))?;
-    tokio::spawn(sigusr2_handler(stream));
-
-    Ok(())
-}
-
-use tracing::field::Empty;
-
-#[instrument(skip_all,
-	     fields(id = id,
-		    remote = Empty,
-		    local = Empty,
-		    filename = Empty,
-		    op = Empty))]
-async fn handle_request(env: std::sync::Arc<Environment>,
-			id: u64,
-			info: UdpRecvInfo,
-			req: Vec<u8>,
-			bucket: Arc<Bucket>)
-{
-    let instant = std::time::Instant::now();
-    let session = Session::new(&env, info.remote, info.local).await;
-
-    if let Err(e) = session {
-	warn!("failed to create tftp session: {:?}", e);
-	return;
-    }
-
-    let session = session.unwrap();
-
-    let b = bucket.acquire();
-
-    let res = match b.is_some() {
-	false	=> session.do_reject().await,
-	true	=> session.run(req).await
-    };
-
-    match res {
-	Ok(stats)	=> {
-	    info!(parent: tracing::Span::none(),
-		  "conn#{}: {}, {}", id, &stats, SpeedInfo::new(instant, &stats))
-	},
-	Err(e)	=> error!("request failed: {:?}", e),
-    };
-}
-
-async fn run_tftpd_loop(env: std::sync::Arc<Environment>, sock: UdpSocket) -> Result<()> {
-    let mut buf = vec![0u8; 1500];
-
-    let bucket = Arc::new(Bucket::new(env.max_connections));
-    let mut num = 0;
-
-    loop {
-	let info = sock.recvmsg(&mut buf).await?;
-	let request = Vec::from(&buf[..info.size]);
-
-	#[cfg(test)]
-	if &request[0..2] == b"QQ" {
-	    break Ok(());
-	}
-
-	tokio::task::spawn(handle_request(env.clone(), num, info,
-					  request, bucket.clone()));
-
-	num += 1;
-    }
-}
-
-enum Either<T: Sized, U: Sized> {
-    A(T),
-    B(U),
-}
-
-async fn run(env: Environment, info: Either<SocketAddr, OwnedFd>) -> Result<()> {
-    #[cfg(feature = "proxy")]
-    {
-	#[allow(clippy::identity_op)]
-	let gc_props = fetcher::CacheGcProperties {
-	    max_elements:	50,
-	    max_lifetime:	Duration::from_secs(1 * 3600),
-	    sleep:		Duration::from_secs(30),
-	};
-
-	fetcher::Cache::instanciate(&env.cache_dir, gc_props);
-    }
-
-
-    // UdpSocket creation must happen with active Tokio runtime
-    let mut sock = match info {
-	Either::A(addr)	=> UdpSocket::bind(&addr),
-	Either::B(fd)	=> UdpSocket::from_raw(fd),
-    }?;
-
-    sock.set_nonblocking()?;
-    sock.set_request_pktinfo()?;
 
-    init_sighandlers()?;
-
-    run_tftpd_loop(std::sync::Arc::new(env), sock).await
-}
+use r_tftpd::{ Config, Listen, Error, Result, AclRule, VHostSpec, fetcher, sandbox };
 
 #[tokio::main(flavor = "current_thread")]
-async fn tokio_main(env: Environment, info: Either<SocketAddr, OwnedFd>) -> Result<()> {
-    run(env, info).await
+async fn tokio_main(config: Config, listen: Listen) -> Result<()> {
+    config.start([listen]).await?
+	.wait().await
 }
 
 use clap::Parser;
@@ -245,9 +37,9 @@ struct CliOpts {
 	   value_name("NUM"), default_value("64"))]
     max_connections:	u32,
 
-    #[clap(short, long, value_parser, help("timeout in seconds during tftp transfers"),
-	   default_value("3"))]
-    timeout:		f32,
+    #[clap(short, long, value_parser = parse_secs, value_name("SECS"),
+	   help("timeout in seconds during tftp transfers"), default_value("3"))]
+    timeout:		Duration,
 
     #[clap(short, long, value_parser, value_name("URI"), help("fallback uri"))]
     fallback:		Option<String>,
@@ -268,9 +60,126 @@ struct CliOpts {
 	   value_parser)]
     wrq_devnull:	bool,
 
+    #[clap(long, help("serve only files which are readable by everybody"), value_parser)]
+    world_readable:	bool,
+
+    #[clap(long, help("allow access to files and directories starting with '.'"),
+	   value_parser)]
+    allow_dotfiles:	bool,
+
+    #[clap(short('i'), long, help("resolve filenames case-insensitively when no exact match exists"),
+	   value_parser)]
+    case_insensitive:	bool,
+
+    #[clap(long, value_parser = parse_pxe_search, value_name("PATH"),
+	   help("virtual name which is resolved to the first existing PXELINUX config (01-<mac>, hex ip, default) in its directory; can be given multiple times"))]
+    pxe_search:		Vec<std::path::PathBuf>,
+
+    #[clap(long, help("render 'NAME.tmpl' with client specific variables when 'NAME' does not exist"),
+	   value_parser)]
+    templates:		bool,
+
+    #[clap(long, help("serve 'NAME.zst', 'NAME.gz' or 'NAME.xz' decompressed when 'NAME' does not exist"),
+	   value_parser)]
+    decompress:		bool,
+
+    #[clap(long, help("serve files from a private copy (a reflink when supported) in the 'snapshots' subdirectory of the cache directory so that modifications during transfers are not seen"),
+	   value_parser)]
+    snapshot:		bool,
+
+    #[clap(long, value_parser, value_name("BYTES"), default_value("0"),
+	   help("keep recently served files up to this total size in memory; 0 disables the cache"))]
+    file_cache_size:	u64,
+
+    #[clap(long, value_parser, value_name("DIR"),
+	   help("directory with programs which can be referenced by 'exec://NAME' links"))]
+    exec_dir:		Option<std::path::PathBuf>,
+
+    #[clap(long, value_parser = parse_secs, value_name("SECS"), default_value("10"),
+	   help("time after which programs run by 'exec://' are killed"))]
+    exec_timeout:	Duration,
+
+    #[clap(long, value_parser, value_name("BYTES"), default_value("16777216"),
+	   help("maximum output size of programs run by 'exec://'"))]
+    exec_max_size:	u64,
+
+    #[clap(long, value_parser = parse_acl_rule, value_name("RULE"),
+	   help("access control rule 'allow|deny [client=CIDR] [local=CIDR] [iface=NAME] [path=GLOB]'; can be given multiple times, first match wins"))]
+    acl:		Vec<AclRule>,
+
+    #[clap(short('M'), long, value_parser, value_name("FILE"),
+	   help("filename rewrite rules in the format of tftp-hpa's --mapfile"))]
+    map_file:		Option<std::path::PathBuf>,
+
+    #[clap(long, help("log rewrites by --map-file but do not apply them"), value_parser)]
+    map_dry_run:	bool,
+
+    #[clap(long, value_parser = parse_vhost, value_name("SPEC"),
+	   help("virtual host 'local=IP|iface=NAME [dir=DIR] [fallback=URI] [block-size=N] [window-size=N] [timeout=SECS] [max-connections=N]'; can be given multiple times"))]
+    vhost:		Vec<VHostSpec>,
+
+    #[clap(short, long, value_parser, value_name("USER"),
+	   help("switch to this user after binding the socket"))]
+    user:		Option<String>,
+
+    #[clap(short, long, value_parser, value_name("GROUP"),
+	   help("switch to this group after binding the socket; defaults to the primary group of --user and is required for numeric users without passwd entry"))]
+    group:		Option<String>,
+
+    #[clap(long, value_parser, value_name("DIR"),
+	   help("chroot into this directory after binding the socket"))]
+    chroot:		Option<String>,
+
     #[cfg(feature = "proxy")]
     #[clap(long, help("disable proxy support"), value_parser)]
     disable_proxy:	bool,
+
+    #[cfg(feature = "proxy")]
+    #[clap(long, requires("cache_dir"), value_parser,
+	   help("keep downloaded files in the cache directory across restarts; the directory must not be used for other purposes"))]
+    persistent_cache:	bool,
+
+    #[cfg(feature = "proxy")]
+    #[clap(long, value_parser, value_name("BYTES"),
+	   help("maximum total size of the proxy cache; least recently used entries are removed"))]
+    cache_max_size:	Option<u64>,
+
+    #[cfg(feature = "proxy")]
+    #[clap(long, value_parser, value_name("BYTES"),
+	   help("larger resources are streamed without caching them"))]
+    cache_max_object_size:	Option<u64>,
+
+    #[cfg(feature = "sandbox")]
+    #[clap(long, help("restrict filesystem access and syscalls by landlock and seccomp"),
+	   value_parser)]
+    sandbox:		bool,
+}
+
+fn parse_acl_rule(s: &str) -> std::result::Result<AclRule, String> {
+    s.parse().map_err(|e: Error| e.to_string())
+}
+
+fn parse_pxe_search(s: &str) -> std::result::Result<std::path::PathBuf, String> {
+    fetcher::normalize_path(s.as_ref()).map_err(|e| e.to_string())
+}
+
+fn parse_vhost(s: &str) -> std::result::Result<VHostSpec, String> {
+    s.parse().map_err(|e: Error| e.to_string())
+}
+
+fn parse_secs(s: &str) -> std::result::Result<Duration, String> {
+    let secs: f32 = s.parse().map_err(|e: std::num::ParseFloatError| e.to_string())?;
+
+    Duration::try_from_secs_f32(secs).map_err(|e| e.to_string())
+}
+
+/// Reports an invalid option like clap does and exits
+fn invalid_arg(msg: impl std::fmt::Display) -> ! {
+    use clap::CommandFactory;
+
+    CliOpts::command()
+	.error(clap::error::ErrorKind::InvalidValue, msg)
+	.exit()
 }
 
 fn main() {
@@ -298,20 +207,75 @@ fn main() {
 	LogFormat::Default		=> unreachable!(),
     }
 
-    let env = Environment {
-	dir:			".".into(),
-	cache_dir:		args.cache_dir.map(|s| s.into()).unwrap_or_else(std::env::temp_dir),
-	fallback_uri:		args.fallback.map(|s| s.into()),
-	max_block_size:		1500,
-	max_window_size:	64,
-	max_connections:	args.max_connections,
-	timeout:		Duration::from_secs_f32(args.timeout),
-	no_rfc2347:		args.no_rfc2347,
-	wrq_devnull:		args.wrq_devnull,
-
-	#[cfg(feature = "proxy")]
-	allow_uri:		!args.disable_proxy,
-    };
+    let mut config = Config::new(".")
+	.max_connections(args.max_connections)
+	.timeout(args.timeout)
+	.no_rfc2347(args.no_rfc2347)
+	.wrq_devnull(args.wrq_devnull)
+	.world_readable(args.world_readable)
+	.allow_dotfiles(args.allow_dotfiles)
+	.case_insensitive(args.case_insensitive)
+	.templates(args.templates)
+	.decompress(args.decompress)
+	.snapshot(args.snapshot)
+	.exec_limits(fetcher::ExecLimits {
+	    timeout:		args.exec_timeout,
+	    max_size:		args.exec_max_size,
+	})
+	.privileges(sandbox::PrivDrop {
+	    user:		args.user,
+	    group:		args.group,
+	    chroot:		args.chroot.map(|s| s.into()),
+	    drop_caps:		true,
+	})
+	.signal_handlers(true);
+
+    #[cfg(feature = "proxy")]
+    {
+	config = config
+	    .proxy(!args.disable_proxy)
+	    .persistent_cache(args.persistent_cache)
+	    .cache_limits(args.cache_max_size, args.cache_max_object_size);
+    }
+
+    #[cfg(feature = "sandbox")]
+    {
+	config = config.sandbox(args.sandbox);
+    }
+
+    if let Some(dir) = args.cache_dir {
+	config = config.cache_dir(dir);
+    }
+
+    if let Some(fallback) = args.fallback {
+	config = config.fallback(fallback);
+    }
+
+    if args.file_cache_size > 0 {
+	config = config.file_cache(fetcher::FileCache::new(args.file_cache_size));
+    }
+
+    if let Some(dir) = args.exec_dir {
+	config = config.exec_dir(dir);
+    }
+
+    if let Some(f) = args.map_file {
+	config = config.map_file(&f, args.map_dry_run)
+	    .unwrap_or_else(|e| invalid_arg(format!("failed to load map file {f:?}: {e}")));
+    }
+
+    for p in args.pxe_search {
+	config = config.pxe_search(&p)
+	    .unwrap_or_else(|e| invalid_arg(format!("bad --pxe-search path {p:?}: {e}")));
+    }
+
+    for rule in args.acl {
+	config = config.acl_rule(rule);
+    }
+
+    for spec in args.vhost {
+	config = config.vhost(spec);
+    }
 
     let fd = match args.systemd {
 	true	=> listenfd::ListenFd::from_env()
@@ -321,10 +285,10 @@ fn main() {
 	false	=> None
     };
 
-    let info = match fd {
-	None		=> Either::A(SocketAddr::new(args.listen, args.port)),
-	Some(fd)	=> Either::B(fd),
+    let listen = match fd {
+	None		=> Listen::Addr(std::net::SocketAddr::new(args.listen, args.port)),
+	Some(fd)	=> Listen::Fd(fd),
     };
 
-    tokio_main(env, info).unwrap();
+    tokio_main(config, listen).unwrap();
 }
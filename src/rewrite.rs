@@ -0,0 +1,429 @@
+use std::ffi::{ OsStr, OsString };
+use std::net::IpAddr;
+use std::os::unix::ffi::{ OsStrExt, OsStringExt };
+use std::path::Path;
+
+use regex::bytes::{ Regex, RegexBuilder, Captures };
+
+use crate::tftp::SessionDirection as Direction;
+use crate::util::Client;
+use crate::{ Error, Result };
+
+/// Maximum number of rule applications; protects against endless loops
+/// caused by 's' rules
+const MAX_ITERATIONS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Flags {
+    /// 'r': replace the match by the replacement
+    replace:	bool,
+    /// 'g': replace all matches
+    global:	bool,
+    /// 'i': case insensitive match
+    icase:	bool,
+    /// 'e': stop processing rules when matching
+    stop:	bool,
+    /// 's': restart processing at the first rule when matching
+    restart:	bool,
+    /// 'a': refuse the request when matching
+    abort:	bool,
+    /// 'R': result is an uri which is requested directly; implies 'e'
+    redirect:	bool,
+    /// 'G': rule applies to read requests only
+    rrq_only:	bool,
+    /// 'P': rule applies to write requests only
+    wrq_only:	bool,
+}
+
+impl Flags {
+    fn parse(s: &str) -> Result<Self> {
+	let mut res = Self::default();
+
+	for c in s.chars() {
+	    match c {
+		'r'	=> res.replace  = true,
+		'g'	=> res.global   = true,
+		'i'	=> res.icase    = true,
+		'e'	=> res.stop     = true,
+		's'	=> res.restart  = true,
+		'a'	=> res.abort    = true,
+		'R'	=> res.redirect = true,
+		'G'	=> res.rrq_only = true,
+		'P'	=> res.wrq_only = true,
+		'-'	=> {},
+		c	=> return Err(Error::InvalidArgument(format!("unknown rewrite flag '{c}'").into())),
+	    }
+	}
+
+	Ok(res)
+    }
+
+    fn applies_to(&self, dir: Direction) -> bool {
+	match dir {
+	    Direction::Rrq	=> !self.wrq_only,
+	    Direction::Wrq	=> !self.rrq_only,
+	}
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    flags:	Flags,
+    re:		Regex,
+    replace:	Vec<u8>,
+}
+
+/// Result of rewriting a requested filename
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapped {
+    pub name:		OsString,
+    /// `name` is an uri which should be requested directly
+    pub redirect:	bool,
+}
+
+impl Mapped {
+    pub fn as_path(&self) -> &Path {
+	Path::new(&self.name)
+    }
+}
+
+/// Expands the replacement of a rule.
+///
+/// Recognized escapes are `\0` - `\9` (captures), `\i` (client ip), `\x`
+/// (client ip in uppercase hex), `\m` (client MAC address as
+/// `aa-bb-cc-dd-ee-ff`; empty when unknown) and `\\`.
+fn expand(tmpl: &[u8], caps: &Captures<'_>, client: &Client, dst: &mut Vec<u8>)
+{
+    let mut it = tmpl.iter();
+
+    while let Some(c) = it.next() {
+	match (c, it.as_slice().first()) {
+	    (b'\\', Some(d @ b'0'..=b'9'))	=> {
+		if let Some(m) = caps.get((d - b'0') as usize) {
+		    dst.extend_from_slice(m.as_bytes());
+		}
+		it.next();
+	    },
+	    (b'\\', Some(b'i'))			=> {
+		dst.extend_from_slice(client.remote.to_string().as_bytes());
+		it.next();
+	    },
+	    (b'\\', Some(b'x'))			=> {
+		let hex = match client.remote.to_canonical() {
+		    IpAddr::V4(ip)	=> format!("{:08X}", u32::from(ip)),
+		    IpAddr::V6(ip)	=> format!("{:032X}", u128::from(ip)),
+		};
+
+		dst.extend_from_slice(hex.as_bytes());
+		it.next();
+	    },
+	    (b'\\', Some(b'm'))			=> {
+		if let Some(mac) = &client.mac {
+		    dst.extend_from_slice(mac.format("-").as_bytes());
+		}
+		it.next();
+	    },
+	    (b'\\', Some(b'\\'))		=> {
+		dst.push(b'\\');
+		it.next();
+	    },
+	    (c, _)				=> dst.push(*c),
+	}
+    }
+}
+
+/// Removes a trailing comment; like in tftp-hpa, `#` starts a comment only
+/// at the beginning of a line or after whitespace so that it can be used
+/// in regular expressions and replacements
+fn strip_comment(line: &str) -> &str {
+    let pos = line.char_indices()
+	.find(|(pos, c)| *c == '#' && line[..*pos].chars().next_back().map_or(true, char::is_whitespace))
+	.map(|(pos, _)| pos);
+
+    match pos {
+	Some(pos)	=> &line[..pos],
+	None		=> line,
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Option<Self>> {
+	let line = strip_comment(line);
+
+	let mut tokens = line.split_whitespace();
+
+	let (flags, re) = match (tokens.next(), tokens.next()) {
+	    (None, _)		=> return Ok(None),
+	    (Some(f), Some(re))	=> (Flags::parse(f)?, re),
+	    (Some(_), None)	=> return Err(Error::InvalidArgument(
+		format!("missing regex in rewrite rule '{line}'").into())),
+	};
+
+	let replace = tokens.next();
+
+	if tokens.next().is_some() {
+	    return Err(Error::InvalidArgument(format!("trailing garbage in rewrite rule '{line}'").into()));
+	}
+
+	let replace = match replace {
+	    Some(r)				=> r.as_bytes().to_vec(),
+	    None if flags.replace || flags.redirect	=> return Err(Error::InvalidArgument(
+		format!("missing replacement in rewrite rule '{line}'").into())),
+	    None				=> Vec::new(),
+	};
+
+	let re = RegexBuilder::new(re)
+	    .case_insensitive(flags.icase)
+	    .unicode(false)
+	    .build()
+	    .map_err(|e| Error::InvalidArgument(e.to_string().into()))?;
+
+	Ok(Some(Self {
+	    flags:	flags,
+	    re:		re,
+	    replace:	replace,
+	}))
+    }
+
+    /// Applies the rule; returns `None` when it does not match
+    fn apply(&self, name: &[u8], client: &Client) -> Option<Vec<u8>> {
+	if !self.re.is_match(name) {
+	    return None;
+	}
+
+	if !self.flags.replace && !self.flags.redirect {
+	    return Some(name.to_vec());
+	}
+
+	let mut res = Vec::with_capacity(name.len() + self.replace.len());
+	let mut last = 0;
+
+	for caps in self.re.captures_iter(name) {
+	    let m = caps.get(0).unwrap();
+
+	    res.extend_from_slice(&name[last..m.start()]);
+	    expand(&self.replace, &caps, client, &mut res);
+	    last = m.end();
+
+	    if !self.flags.global {
+		break;
+	    }
+	}
+
+	res.extend_from_slice(&name[last..]);
+
+	Some(res)
+    }
+}
+
+/// Ordered table of filename rewrite rules in the format of tftp-hpa's
+/// `--mapfile`
+#[derive(Clone, Debug, Default)]
+pub struct Rewrite {
+    rules:	Vec<Rule>,
+    dry_run:	bool,
+}
+
+impl Rewrite {
+    pub fn parse(s: &str, dry_run: bool) -> Result<Self> {
+	let mut rules = Vec::new();
+
+	for (idx, line) in s.lines().enumerate() {
+	    match Rule::parse(line) {
+		Ok(Some(r))	=> rules.push(r),
+		Ok(None)	=> {},
+		Err(e)		=> return Err(Error::InvalidArgument(
+		    format!("line {}: {}", idx + 1, e).into())),
+	    }
+	}
+
+	Ok(Self {
+	    rules:	rules,
+	    dry_run:	dry_run,
+	})
+    }
+
+    pub fn load(path: &Path, dry_run: bool) -> Result<Self> {
+	let data = std::fs::read_to_string(path)?;
+
+	Self::parse(&data, dry_run)
+    }
+
+    fn map(&self, name: &OsStr, client: &Client, dir: Direction) -> Result<Mapped> {
+	let mut res = name.as_bytes().to_vec();
+	let mut idx = 0;
+	let mut cnt = 0;
+	let mut redirect = false;
+
+	while let Some(rule) = self.rules.get(idx) {
+	    idx += 1;
+
+	    if !rule.flags.applies_to(dir) {
+		continue;
+	    }
+
+	    let Some(tmp) = rule.apply(&res, client) else {
+		continue;
+	    };
+
+	    cnt += 1;
+
+	    if cnt > MAX_ITERATIONS {
+		return Err(Error::InvalidArgument("too many rewrite iterations".into()));
+	    }
+
+	    if rule.flags.abort {
+		return Err(Error::AccessViolation("request refused by rewrite rule"));
+	    }
+
+	    res = tmp;
+
+	    if rule.flags.redirect {
+		redirect = true;
+		break;
+	    }
+
+	    if rule.flags.stop {
+		break;
+	    }
+
+	    if rule.flags.restart {
+		idx = 0;
+	    }
+	}
+
+	Ok(Mapped {
+	    name:	OsString::from_vec(res),
+	    redirect:	redirect,
+	})
+    }
+
+    /// Rewrites the filename requested by `client`.  In dry-run mode, the
+    /// result is logged only and the original name is returned.
+    pub fn apply(&self, name: &OsStr, client: &Client, dir: Direction) -> Result<Mapped> {
+	let orig = Mapped {
+	    name:	name.into(),
+	    redirect:	false,
+	};
+
+	if self.rules.is_empty() {
+	    return Ok(orig);
+	}
+
+	let res = self.map(name, client, dir);
+
+	match (&res, self.dry_run) {
+	    (Ok(m), _) if *m == orig	=> {},
+	    (Ok(m), false)		=> debug!("rewrote {:?} to {:?}{}", name, m.name,
+						  if m.redirect { " (redirect)" } else { "" }),
+	    (Ok(m), true)		=> info!("dry-run: would rewrite {:?} to {:?}{}", name, m.name,
+						 if m.redirect { " (redirect)" } else { "" }),
+	    (Err(e), false)		=> debug!("rewriting {:?} failed: {}", name, e),
+	    (Err(e), true)		=> info!("dry-run: rewriting {:?} would fail: {}", name, e),
+	}
+
+	match self.dry_run {
+	    true	=> Ok(orig),
+	    false	=> res,
+	}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RULES: &str = r#"
+# windows paths
+rg	\\		/
+# strip absolute prefix
+r	^/tftpboot/	/
+i	^/?secret	# match only; denied below
+ia	^/?secret
+rG	^/?vendor-([a-z]+)\.bin$	/fw/\1/image.bin
+re	^/?hosts/(.*)	/hosts/\i/\1
+r	^/?pxe/ip	/pxe/\x
+r	^/?pxe/mac	/pxe/01-\m
+R	^/?remote/(.*)	http://example.org/\1
+"#;
+
+    fn client(ip: &str) -> Client {
+	Client {
+	    remote:	ip.parse().unwrap(),
+	    local:	"192.168.1.1".parse().unwrap(),
+	    mac:	Some("88:99:aa:bb:cc:dd".parse().unwrap()),
+	}
+    }
+
+    fn try_map(r: &Rewrite, name: &str) -> Result<Mapped> {
+	r.apply(OsStr::new(name), &client("192.168.1.10"), Direction::Rrq)
+    }
+
+    fn map(r: &Rewrite, name: &str) -> Mapped {
+	try_map(r, name).unwrap()
+    }
+
+    fn local(name: &str) -> Mapped {
+	Mapped { name: name.into(), redirect: false }
+    }
+
+    #[test]
+    fn test_rewrite() {
+	let r = Rewrite::parse(RULES, false).unwrap();
+
+	assert_eq!(map(&r, "foo"),                      local("foo"));
+	assert_eq!(map(&r, "\\boot\\pxelinux.0"),       local("/boot/pxelinux.0"));
+	assert_eq!(map(&r, "/tftpboot/pxelinux.0"),     local("/pxelinux.0"));
+	assert_eq!(map(&r, "/tftpboot/a/tftpboot/b"),   local("/a/tftpboot/b"));
+	assert_eq!(map(&r, "vendor-abc.bin"),           local("/fw/abc/image.bin"));
+	assert_eq!(map(&r, "hosts/cfg"),                local("/hosts/192.168.1.10/cfg"));
+	assert_eq!(map(&r, "pxe/ip"),                   local("/pxe/C0A8010A"));
+	assert_eq!(map(&r, "pxe/mac"),                  local("/pxe/01-88-99-aa-bb-cc-dd"));
+	assert_eq!(map(&r, "remote/a/b"),
+		   Mapped { name: "http://example.org/a/b".into(), redirect: true });
+
+	assert!(matches!(try_map(&r, "SECRET/x"), Err(Error::AccessViolation(_))));
+
+	// 'G' rules are not applied to write requests
+	assert_eq!(r.apply(OsStr::new("vendor-abc.bin"), &client("::1"), Direction::Wrq)
+		   .unwrap(), local("vendor-abc.bin"));
+    }
+
+    #[test]
+    fn test_restart() {
+	let r = Rewrite::parse("rs ^a b\nr ^b c\nrs ^c a\n", false).unwrap();
+
+	assert!(try_map(&r, "a").is_err());
+
+	let r = Rewrite::parse("rs ^a(.*) \\1\n", false).unwrap();
+
+	assert_eq!(map(&r, "aaab"), local("b"));
+    }
+
+    #[test]
+    fn test_dry_run() {
+	let r = Rewrite::parse(RULES, true).unwrap();
+
+	assert_eq!(map(&r, "/tftpboot/pxelinux.0"), local("/tftpboot/pxelinux.0"));
+	assert_eq!(map(&r, "secret"), local("secret"));
+    }
+
+    #[test]
+    fn test_comment() {
+	let r = Rewrite::parse("r ^a[#]b a#b # comment\n#r ^c d\nr ^c\\#d x\t# comment\n", false).unwrap();
+
+	assert_eq!(r.rules.len(), 2);
+	assert_eq!(map(&r, "a#b"), local("a#b"));
+	assert_eq!(map(&r, "c#d"), local("x"));
+	assert_eq!(map(&r, "c"),   local("c"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+	assert!(Rewrite::parse("x ^a b", false).is_err());
+	assert!(Rewrite::parse("r ^a", false).is_err());
+	assert!(Rewrite::parse("r ^(a b", false).is_err());
+	assert!(Rewrite::parse("r ^a b c", false).is_err());
+	assert!(Rewrite::parse("# comment only\n\n", false).unwrap().rules.is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use ::landlock::{ ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+		  RulesetStatus, path_beneath_rules };
+
+use crate::{ Error, Result };
+
+const ABI_VERSION: ABI = ABI::V3;
+
+fn to_error(e: ::landlock::RulesetError) -> Error {
+    Error::Sandbox(format!("landlock: {e}").into())
+}
+
+/// Restricts filesystem access to reading below `ro` and full access below
+/// `rw`.  Non-existing paths are ignored.
+pub fn restrict<A, B>(ro: &[A], rw: &[B]) -> Result<()>
+where
+    A: AsRef<Path>,
+    B: AsRef<Path>,
+{
+    let ro = ro.iter().map(|p| p.as_ref()).filter(|p| p.exists());
+    let rw = rw.iter().map(|p| p.as_ref()).filter(|p| p.exists());
+
+    let status = Ruleset::default()
+	.handle_access(AccessFs::from_all(ABI_VERSION)).map_err(to_error)?
+	.create().map_err(to_error)?
+	.add_rules(path_beneath_rules(ro, AccessFs::from_read(ABI_VERSION))).map_err(to_error)?
+	.add_rules(path_beneath_rules(rw, AccessFs::from_all(ABI_VERSION))).map_err(to_error)?
+	.restrict_self().map_err(to_error)?;
+
+    match status.ruleset {
+	RulesetStatus::FullyEnforced	=> info!("landlock restrictions enforced"),
+	RulesetStatus::PartiallyEnforced	=> warn!("landlock restrictions enforced partially only"),
+	RulesetStatus::NotEnforced	=> warn!("landlock not supported by the kernel"),
+    }
+
+    Ok(())
+}
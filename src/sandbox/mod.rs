@@ -0,0 +1,109 @@
+//
+
+mod privdrop;
+
+#[cfg(feature = "sandbox")]
+mod landlock;
+#[cfg(feature = "sandbox")]
+mod seccomp;
+
+pub use privdrop::{ PrivDrop, rebase_path };
+
+/// Fails unless the server runs on a `current_thread` tokio runtime.
+///
+/// Some restrictions (capabilities, Landlock) are applied to the calling
+/// thread only and are inherited by threads which are created afterwards.
+/// Worker threads of a multi-threaded runtime would stay unrestricted.
+fn check_current_thread(what: &str) -> crate::Result<()>
+{
+    use tokio::runtime::{ Handle, RuntimeFlavor };
+
+    match Handle::try_current().map(|h| h.runtime_flavor()) {
+	Ok(RuntimeFlavor::CurrentThread)	=> Ok(()),
+	_					=>
+	    Err(crate::Error::Sandbox(format!("{what} requires a current_thread tokio runtime").into())),
+    }
+}
+
+/// Restrictions which are applied to the running server.
+#[cfg(feature = "sandbox")]
+#[derive(Debug, Default)]
+pub struct Sandbox {
+    /// paths which can be read (e.g. the tftp root)
+    pub ro_paths:	Vec<std::path::PathBuf>,
+    /// paths which can be written (e.g. the cache directory)
+    pub rw_paths:	Vec<std::path::PathBuf>,
+    /// whether outgoing http connections are required
+    pub allow_http:	bool,
+    /// whether bodies and the index of the persistent cache are stored
+    pub persistent_cache:	bool,
+    /// whether programs are run by 'exec://'
+    pub allow_exec:	bool,
+    /// whether files are copied before serving them
+    pub snapshot:	bool,
+}
+
+/// System files which are needed for name resolution and TLS certificate
+/// verification in proxy mode.
+#[cfg(feature = "sandbox")]
+const HTTP_SYSTEM_PATHS: &[&str] = &[
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/host.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/ssl",
+    "/etc/pki",
+    "/usr/lib/ssl",
+    "/usr/share/ca-certificates",
+    // NSS modules are loaded on demand
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+];
+
+/// Files which are needed to run programs; these inherit the restrictions
+/// of the server.
+#[cfg(feature = "sandbox")]
+const EXEC_SYSTEM_PATHS: &[&str] = &[
+    "/bin",
+    "/sbin",
+    "/usr/bin",
+    "/usr/sbin",
+    "/usr/local/bin",
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/libexec",
+    "/etc/ld.so.cache",
+    "/dev/null",
+];
+
+#[cfg(feature = "sandbox")]
+impl Sandbox {
+    /// Applies the restrictions.  The seccomp filter is synchronized to all
+    /// threads but Landlock confines the calling thread only; hence, a
+    /// `current_thread` runtime whose blocking pool has not been started
+    /// yet is required.
+    pub fn apply(&self) -> crate::Result<()>
+    {
+	check_current_thread("landlock")?;
+
+	let mut ro_paths = self.ro_paths.clone();
+
+	if self.allow_http {
+	    ro_paths.extend(HTTP_SYSTEM_PATHS.iter().map(|p| p.into()));
+	}
+
+	if self.allow_exec {
+	    ro_paths.extend(EXEC_SYSTEM_PATHS.iter().map(|p| p.into()));
+	}
+
+	landlock::restrict(&ro_paths, &self.rw_paths)?;
+	seccomp::restrict(self)?;
+
+	Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+use std::path::{ Path, PathBuf };
+
+use nix::libc;
+use nix::unistd::{ self, Gid, Uid, Group, User };
+
+use crate::{ Error, Result };
+
+/// Privileges which are given up after the listening socket has been
+/// created.
+///
+/// Changing the root directory, user and group affects the whole process.
+/// Capabilities are dropped for the calling thread only; `drop_caps`
+/// therefore requires a `current_thread` tokio runtime whose blocking pool
+/// has not been started yet.  Threads created afterwards inherit the
+/// reduced capabilities.
+#[derive(Debug, Default)]
+pub struct PrivDrop {
+    pub user:		Option<String>,
+    pub group:		Option<String>,
+    pub chroot:		Option<PathBuf>,
+    pub drop_caps:	bool,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version:	u32,
+    pid:	libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective:	u32,
+    permitted:	u32,
+    inheritable:	u32,
+}
+
+/// Translates `p` into the path which refers to the same object after a
+/// `chroot(root)`.
+///
+/// Relative paths are interpreted relative to the current working directory.
+pub fn rebase_path(root: &Path, p: &Path) -> Result<PathBuf>
+{
+    let root = std::fs::canonicalize(root)?;
+    let abs = std::env::current_dir()?.join(p);
+    let abs = std::fs::canonicalize(&abs).unwrap_or(abs);
+
+    match abs.strip_prefix(&root) {
+	Ok(rel)	=> Ok(Path::new("/").join(rel)),
+	Err(_)	=> Err(Error::OutsideChroot(abs.into())),
+    }
+}
+
+fn parse_id<T: From<u32>>(s: &str) -> Option<T>
+{
+    s.parse::<u32>().ok().map(T::from)
+}
+
+impl PrivDrop {
+    /// Returns the uid and the primary gid of `--user`; the gid is unknown
+    /// for numeric ids without a passwd entry
+    fn lookup_user(&self) -> Result<Option<(Uid, Option<Gid>)>>
+    {
+	let user = match &self.user {
+	    None	=> return Ok(None),
+	    Some(u)	=> u,
+	};
+
+	match User::from_name(user)? {
+	    Some(u)	=> Ok(Some((u.uid, Some(u.gid)))),
+	    None	=> match parse_id::<libc::uid_t>(user) {
+		Some(uid)	=> {
+		    let uid = Uid::from_raw(uid);
+
+		    Ok(Some((uid, User::from_uid(uid)?.map(|u| u.gid))))
+		},
+		None		=> Err(Error::UnknownUser(user.as_str().into())),
+	    }
+	}
+    }
+
+    fn lookup_group(&self) -> Result<Option<Gid>>
+    {
+	let group = match &self.group {
+	    None	=> return Ok(None),
+	    Some(g)	=> g,
+	};
+
+	match Group::from_name(group)? {
+	    Some(g)	=> Ok(Some(g.gid)),
+	    None	=> match parse_id::<libc::gid_t>(group) {
+		Some(gid)	=> Ok(Some(Gid::from_raw(gid))),
+		None		=> Err(Error::UnknownGroup(group.as_str().into())),
+	    }
+	}
+    }
+
+    /// Resolves the user and group to switch to
+    fn resolve(&self) -> Result<(Option<Uid>, Option<Gid>)>
+    {
+	let user = self.lookup_user()?;
+
+	let gid = match (self.lookup_group()?, user) {
+	    (Some(gid), _)		=> Some(gid),
+	    (None, None)		=> None,
+	    (None, Some((_, Some(gid))))	=> Some(gid),
+	    (None, Some((_, None)))	=>
+		return Err(Error::InvalidArgument("--group is required for a --user without passwd entry".into())),
+	};
+
+	Ok((user.map(|(uid, _)| uid), gid))
+    }
+
+    /// Removes all capabilities from the bounding set.  Requires
+    /// `CAP_SETPCAP` so it must be called before switching the user.
+    fn drop_bounding_set()
+    {
+	for cap in 0.. {
+	    let rc = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) };
+
+	    if rc < 0 {
+		let err = std::io::Error::last_os_error();
+
+		match err.raw_os_error() {
+		    // end of the capability list
+		    Some(libc::EINVAL)	=> {},
+		    _			=> debug!("failed to drop cap #{} from bounding set: {}", cap, err),
+		}
+
+		break;
+	    }
+	}
+    }
+
+    fn drop_capabilities() -> Result<()>
+    {
+	let rc = unsafe {
+	    libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0)
+	};
+
+	if rc < 0 {
+	    // not supported by old kernels
+	    debug!("failed to clear ambient capabilities: {}", std::io::Error::last_os_error());
+	}
+
+	let mut hdr = CapHeader {
+	    version:	LINUX_CAPABILITY_VERSION_3,
+	    pid:	0,
+	};
+	let data = [CapData::default(); 2];
+
+	let rc = unsafe {
+	    libc::syscall(libc::SYS_capset, &mut hdr as *mut CapHeader, data.as_ptr())
+	};
+
+	if rc < 0 {
+	    return Err(std::io::Error::last_os_error().into());
+	}
+
+	Ok(())
+    }
+
+    /// Enters the chroot, switches user and group and drops capabilities.
+    pub fn apply(&self) -> Result<()>
+    {
+	if self.drop_caps {
+	    super::check_current_thread("dropping capabilities")?;
+	}
+
+	// names must be resolved before entering the chroot because the
+	// passwd database is usually not available there
+	let (uid, gid) = self.resolve()?;
+
+	if let Some(dir) = &self.chroot {
+	    unistd::chroot(dir)?;
+	    unistd::chdir("/")?;
+
+	    info!("changed root to {}", dir.display());
+	}
+
+	if self.drop_caps {
+	    Self::drop_bounding_set();
+	}
+
+	if let Some(gid) = gid {
+	    unistd::setgroups(&[gid])?;
+	    unistd::setgid(gid)?;
+	}
+
+	if let Some(uid) = uid {
+	    unistd::setuid(uid)?;
+	}
+
+	if self.drop_caps {
+	    Self::drop_capabilities()?;
+	}
+
+	if uid.is_some() || gid.is_some() {
+	    info!("running as uid={}, gid={}", unistd::getuid(), unistd::getgid());
+	}
+
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rebase() {
+	use tempfile::TempDir;
+
+	let tmp_dir = TempDir::new().unwrap();
+	let tmp_path = tmp_dir.path();
+
+	std::fs::create_dir(tmp_path.join("a")).unwrap();
+
+	assert_eq!(rebase_path(tmp_path, tmp_path).unwrap(),
+		   Path::new("/"));
+	assert_eq!(rebase_path(tmp_path, &tmp_path.join("a")).unwrap(),
+		   Path::new("/a"));
+	assert_eq!(rebase_path(tmp_path, &tmp_path.join("a/../a/")).unwrap(),
+		   Path::new("/a"));
+	assert_eq!(rebase_path(tmp_path, &tmp_path.join("missing/file")).unwrap(),
+		   Path::new("/missing/file"));
+
+	assert!(rebase_path(&tmp_path.join("a"), tmp_path).is_err());
+	assert!(rebase_path(tmp_path, Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+	let privdrop = |user: Option<&str>, group: Option<&str>| PrivDrop {
+	    user:	user.map(String::from),
+	    group:	group.map(String::from),
+	    ..Default::default()
+	}.resolve();
+
+	// an uid which is very unlikely to exist in the passwd database
+	let uid = "3999999999";
+
+	assert_eq!(privdrop(None, None).unwrap(), (None, None));
+	assert_eq!(privdrop(Some("0"), None).unwrap(), (Some(Uid::from_raw(0)), Some(Gid::from_raw(0))));
+	assert_eq!(privdrop(Some(uid), Some("123")).unwrap(),
+		   (Some(Uid::from_raw(3999999999)), Some(Gid::from_raw(123))));
+	assert!(matches!(privdrop(Some(uid), None), Err(Error::InvalidArgument(_))));
+	assert!(matches!(privdrop(Some("no-such-user-xyz"), None), Err(Error::UnknownUser(_))));
+    }
+
+    #[test]
+    fn test_drop_caps_runtime() {
+	let privdrop = PrivDrop {
+	    drop_caps:	true,
+	    ..Default::default()
+	};
+
+	// capabilities are per-thread; refused outside of a current_thread
+	// runtime
+	assert!(matches!(privdrop.apply(), Err(Error::Sandbox(_))));
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use nix::libc;
+use seccompiler::{ BpfProgram, SeccompAction, SeccompFilter, SeccompRule, TargetArch };
+
+use crate::{ Error, Result };
+
+use super::Sandbox;
+
+/// Syscalls required by the tokio runtime, the logging framework and the
+/// tftp server itself.
+const SYSCALLS_BASE: &[libc::c_long] = &[
+    // memory management
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+
+    // threads, signals and time
+    libc::SYS_futex,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_tgkill,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+
+    // event loop
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+
+    // file access
+    libc::SYS_openat,
+    libc::SYS_openat2,
+    libc::SYS_close,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_unlinkat,
+    libc::SYS_getcwd,
+
+    // udp sockets
+    libc::SYS_socket,
+    libc::SYS_bind,
+    libc::SYS_getsockname,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+];
+
+/// Legacy syscalls which are not available on all architectures
+#[cfg(target_arch = "x86_64")]
+const SYSCALLS_ARCH: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_readlink,
+    libc::SYS_poll,
+    libc::SYS_epoll_wait,
+    libc::SYS_arch_prctl,
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const SYSCALLS_ARCH: &[libc::c_long] = &[];
+
+/// Additional syscalls for outgoing http connections (proxy mode)
+const SYSCALLS_HTTP: &[libc::c_long] = &[
+    libc::SYS_connect,
+    libc::SYS_getpeername,
+    libc::SYS_shutdown,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_uname,
+];
+
+/// Additional syscalls for storing bodies and the index of the persistent
+/// cache
+const SYSCALLS_STORE: &[libc::c_long] = &[
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+];
+
+/// Additional syscalls for reserving the space of snapshots
+const SYSCALLS_SNAPSHOT: &[libc::c_long] = &[
+    libc::SYS_fallocate,
+];
+
+/// Additional syscalls for running programs by 'exec://'; the programs
+/// inherit the filter and need the basic syscalls of a shell too
+const SYSCALLS_EXEC: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_kill,
+    libc::SYS_pidfd_open,
+    libc::SYS_pidfd_send_signal,
+    libc::SYS_socketpair,
+    libc::SYS_close_range,
+    libc::SYS_chdir,
+    libc::SYS_fchdir,
+    libc::SYS_setpgid,
+    libc::SYS_getpgid,
+    libc::SYS_getppid,
+    libc::SYS_set_tid_address,
+    libc::SYS_prlimit64,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_uname,
+    libc::SYS_umask,
+    libc::SYS_sysinfo,
+    libc::SYS_rt_sigsuspend,
+];
+
+/// Legacy syscalls for running programs which are not available on all
+/// architectures
+#[cfg(target_arch = "x86_64")]
+const SYSCALLS_EXEC_ARCH: &[libc::c_long] = &[
+    libc::SYS_vfork,
+    libc::SYS_fork,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_access,
+    libc::SYS_getpgrp,
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const SYSCALLS_EXEC_ARCH: &[libc::c_long] = &[];
+
+fn to_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::Sandbox(format!("seccomp: {e}").into())
+}
+
+/// Installs a seccomp filter for all threads of the process.  Syscalls
+/// which are not in the allow list fail with `EPERM`.
+pub fn restrict(sandbox: &Sandbox) -> Result<()>
+{
+    let mut syscalls: Vec<libc::c_long> = Vec::new();
+
+    syscalls.extend(SYSCALLS_BASE);
+    syscalls.extend(SYSCALLS_ARCH);
+
+    if sandbox.allow_http {
+	syscalls.extend(SYSCALLS_HTTP);
+    }
+
+    if sandbox.persistent_cache {
+	syscalls.extend(SYSCALLS_STORE);
+    }
+
+    if sandbox.snapshot {
+	syscalls.extend(SYSCALLS_SNAPSHOT);
+    }
+
+    if sandbox.allow_exec {
+	syscalls.extend(SYSCALLS_EXEC);
+	syscalls.extend(SYSCALLS_EXEC_ARCH);
+    }
+
+    let rules: BTreeMap<i64, Vec<SeccompRule>> = syscalls
+	.into_iter()
+	.map(|nr| (nr, Vec::new()))
+	.collect();
+
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(to_error)?;
+
+    let filter = SeccompFilter::new(rules,
+				    SeccompAction::Errno(libc::EPERM as u32),
+				    SeccompAction::Allow,
+				    arch).map_err(to_error)?;
+
+    let prog: BpfProgram = filter.try_into().map_err(to_error)?;
+
+    seccompiler::apply_filter_all_threads(&prog).map_err(to_error)?;
+
+    info!("seccomp filter installed");
+
+    Ok(())
+}
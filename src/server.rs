@@ -0,0 +1,487 @@
+use std::ffi::OsString;
+use std::os::fd::OwnedFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::{ Environment, Error, Result, SessionStats };
+use crate::{ acl, fetcher, rewrite, sandbox, vhost };
+use crate::util::{ SocketAddr, UdpSocket };
+
+/// Callback which is invoked with the statistics of every finished session
+pub type SessionCallback = Arc<dyn Fn(&SessionStats) + Send + Sync>;
+
+/// Socket on which the server listens
+#[derive(Debug)]
+pub enum Listen {
+    /// address which is bound by the server (port 0 selects a free port)
+    Addr(std::net::SocketAddr),
+    /// already bound udp socket (e.g. from systemd)
+    Fd(OwnedFd),
+}
+
+impl From<std::net::SocketAddr> for Listen {
+    fn from(addr: std::net::SocketAddr) -> Self {
+	Self::Addr(addr)
+    }
+}
+
+impl From<std::net::UdpSocket> for Listen {
+    fn from(sock: std::net::UdpSocket) -> Self {
+	Self::Fd(sock.into())
+    }
+}
+
+impl From<OwnedFd> for Listen {
+    fn from(fd: OwnedFd) -> Self {
+	Self::Fd(fd)
+    }
+}
+
+/// Configuration of an embedded server.
+///
+/// Defaults match the command line program except that signal handlers
+/// are not installed and capabilities are not dropped.
+pub struct Config {
+    env:		Environment,
+    acl:		Vec<acl::Rule>,
+    vhosts:		Vec<vhost::Spec>,
+    signal_handlers:	bool,
+    on_session:		Option<SessionCallback>,
+    // the persistent cache must not use the default temp directory
+    has_cache_dir:	bool,
+}
+
+impl Config {
+    /// Creates a configuration which serves files from `dir`
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+	let env = Environment {
+	    dir:		dir.as_ref().into(),
+	    cache_dir:		std::env::temp_dir(),
+	    fallback_uri:	None,
+	    max_block_size:	1500,
+	    max_window_size:	64,
+	    max_connections:	64,
+	    timeout:		Duration::from_secs(3),
+	    no_rfc2347:		false,
+	    wrq_devnull:	false,
+	    world_readable:	false,
+	    allow_dotfiles:	false,
+	    case_insensitive:	false,
+	    pxe_search:		Vec::new(),
+	    templates:		false,
+	    decompress:		false,
+	    exec_dir:		None,
+	    exec_limits:	fetcher::ExecLimits {
+		timeout:	Duration::from_secs(10),
+		max_size:	16 * 1024 * 1024,
+	    },
+	    backends:		Default::default(),
+	    store:		Default::default(),
+	    file_cache:		None,
+	    snapshot:		false,
+	    acl:		Default::default(),
+	    rewrite:		Default::default(),
+	    vhosts:		Vec::new(),
+	    privileges:		Default::default(),
+
+	    #[cfg(feature = "proxy")]
+	    allow_uri:		true,
+	    #[cfg(feature = "proxy")]
+	    persistent_cache:	false,
+	    #[cfg(feature = "proxy")]
+	    #[allow(clippy::identity_op)]
+	    cache_gc:		fetcher::CacheGcProperties {
+		max_elements:		50,
+		max_lifetime:		Duration::from_secs(1 * 3600),
+		sleep:			Duration::from_secs(30),
+		max_size:		None,
+		max_object_size:	None,
+	    },
+
+	    #[cfg(feature = "sandbox")]
+	    sandbox:		false,
+	};
+
+	Self {
+	    env:		env,
+	    acl:		Vec::new(),
+	    vhosts:		Vec::new(),
+	    signal_handlers:	false,
+	    on_session:		None,
+	    has_cache_dir:	false,
+	}
+    }
+
+    #[cfg(test)]
+    pub(crate) fn env(&self) -> &Environment {
+	&self.env
+    }
+
+    /// Sets the directory for cache files of the proxy
+    pub fn cache_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+	self.env.cache_dir = dir.as_ref().into();
+	self.has_cache_dir = true;
+	self
+    }
+
+    /// Sets the uri or path prefix which is used for missing files
+    pub fn fallback<S: Into<OsString>>(mut self, fallback: S) -> Self {
+	self.env.fallback_uri = Some(fallback.into());
+	self
+    }
+
+    pub fn max_block_size(mut self, sz: u16) -> Self {
+	self.env.max_block_size = sz;
+	self
+    }
+
+    pub fn max_window_size(mut self, sz: u16) -> Self {
+	self.env.max_window_size = sz;
+	self
+    }
+
+    pub fn max_connections(mut self, num: u32) -> Self {
+	self.env.max_connections = num;
+	self
+    }
+
+    /// Sets the timeout during tftp transfers
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+	self.env.timeout = timeout;
+	self
+    }
+
+    /// Disables RFC 2347 (OACK) support
+    pub fn no_rfc2347(mut self, ena: bool) -> Self {
+	self.env.no_rfc2347 = ena;
+	self
+    }
+
+    /// Accepts write requests but throws the data away
+    pub fn wrq_devnull(mut self, ena: bool) -> Self {
+	self.env.wrq_devnull = ena;
+	self
+    }
+
+    /// Serves only files which are readable by everybody
+    pub fn world_readable(mut self, ena: bool) -> Self {
+	self.env.world_readable = ena;
+	self
+    }
+
+    /// Allows access to files and directories starting with '.'
+    pub fn allow_dotfiles(mut self, ena: bool) -> Self {
+	self.env.allow_dotfiles = ena;
+	self
+    }
+
+    /// Resolves filenames case-insensitively when no exact match exists
+    pub fn case_insensitive(mut self, ena: bool) -> Self {
+	self.env.case_insensitive = ena;
+	self
+    }
+
+    /// Adds a virtual name which is resolved to the first existing
+    /// PXELINUX config
+    pub fn pxe_search<P: AsRef<Path>>(mut self, path: P) -> Result<Self> {
+	self.env.pxe_search.push(fetcher::normalize_path(path.as_ref())?);
+	Ok(self)
+    }
+
+    /// Renders `NAME.tmpl` when `NAME` does not exist
+    pub fn templates(mut self, ena: bool) -> Self {
+	self.env.templates = ena;
+	self
+    }
+
+    /// Serves `NAME.zst`, `NAME.gz` or `NAME.xz` decompressed when `NAME`
+    /// does not exist
+    pub fn decompress(mut self, ena: bool) -> Self {
+	self.env.decompress = ena;
+	self
+    }
+
+    /// Sets the directory with programs which can be referenced by
+    /// `exec://` links
+    pub fn exec_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+	self.env.exec_dir = Some(dir.as_ref().into());
+	self
+    }
+
+    pub fn exec_limits(mut self, limits: fetcher::ExecLimits) -> Self {
+	self.env.exec_limits = limits;
+	self
+    }
+
+    /// Sets the backends which are tried before the builtin ones
+    pub fn backends(mut self, backends: fetcher::Backends) -> Self {
+	self.env.backends = backends;
+	self
+    }
+
+    /// Sets the store with in-memory files which are served before files
+    /// from the filesystem; it can be modified while the server is running
+    pub fn memory_store(mut self, store: fetcher::MemoryStore) -> Self {
+	self.env.store = store;
+	self
+    }
+
+    /// Keeps recently served local files in `cache` so that concurrent
+    /// sessions share one copy
+    pub fn file_cache(mut self, cache: fetcher::FileCache) -> Self {
+	self.env.file_cache = Some(cache);
+	self
+    }
+
+    /// Serves local files from a private copy (a reflink when supported)
+    /// in the `snapshots` subdirectory of the cache directory so that
+    /// modifications during transfers are not seen; otherwise such
+    /// transfers are aborted
+    pub fn snapshot(mut self, ena: bool) -> Self {
+	self.env.snapshot = ena;
+	self
+    }
+
+    /// Adds an access control rule; the first matching rule wins
+    pub fn acl_rule(mut self, rule: acl::Rule) -> Self {
+	self.acl.push(rule);
+	self
+    }
+
+    /// Loads filename rewrite rules in the format of tftp-hpa's --mapfile
+    pub fn map_file<P: AsRef<Path>>(mut self, path: P, dry_run: bool) -> Result<Self> {
+	self.env.rewrite = rewrite::Rewrite::load(path.as_ref(), dry_run)?;
+	Ok(self)
+    }
+
+    /// Adds a virtual host; settings which are not given by `spec` are
+    /// inherited from this configuration when the server is started
+    pub fn vhost(mut self, spec: vhost::Spec) -> Self {
+	self.vhosts.push(spec);
+	self
+    }
+
+    /// Sets the user, group and chroot directory which are applied after
+    /// binding the sockets; dropping capabilities requires a
+    /// `current_thread` runtime (see [`sandbox::PrivDrop`])
+    pub fn privileges(mut self, privileges: sandbox::PrivDrop) -> Self {
+	self.env.privileges = privileges;
+	self
+    }
+
+    /// Installs handlers for SIGUSR1 (dump cache) and SIGUSR2 (clear cache)
+    pub fn signal_handlers(mut self, ena: bool) -> Self {
+	self.signal_handlers = ena;
+	self
+    }
+
+    /// Sets a callback which is invoked for every finished session
+    pub fn on_session<F>(mut self, cb: F) -> Self
+    where
+	F: Fn(&SessionStats) + Send + Sync + 'static,
+    {
+	self.on_session = Some(Arc::new(cb));
+	self
+    }
+
+    #[cfg(feature = "proxy")]
+    pub fn proxy(mut self, ena: bool) -> Self {
+	self.env.allow_uri = ena;
+	self
+    }
+
+    /// Limits the total size of the proxy cache and the size of single
+    /// objects; larger objects are not cached
+    #[cfg(feature = "proxy")]
+    pub fn cache_limits(mut self, max_size: Option<u64>, max_object_size: Option<u64>) -> Self {
+	self.env.cache_gc.max_size = max_size;
+	self.env.cache_gc.max_object_size = max_object_size;
+	self
+    }
+
+    /// Keeps downloaded files in the cache directory so that they survive
+    /// restarts; the directory must be set by `cache_dir()` and should not
+    /// be used for other purposes
+    #[cfg(feature = "proxy")]
+    pub fn persistent_cache(mut self, ena: bool) -> Self {
+	self.env.persistent_cache = ena;
+	self
+    }
+
+    /// Restricts filesystem access and syscalls by landlock and seccomp;
+    /// this affects the whole process and requires a `current_thread`
+    /// runtime (see [`sandbox::Sandbox::apply()`])
+    #[cfg(feature = "sandbox")]
+    pub fn sandbox(mut self, ena: bool) -> Self {
+	self.env.sandbox = ena;
+	self
+    }
+
+    /// Starts the server on the given sockets; must be called within a
+    /// tokio runtime.  All servers of a process share the proxy cache;
+    /// starting one with a different cache directory or cache properties
+    /// than a running one fails.
+    pub async fn start<I>(self, listen: I) -> Result<Server>
+    where
+	I: IntoIterator<Item = Listen>,
+    {
+	let mut env = self.env;
+
+	#[cfg(feature = "proxy")]
+	if env.persistent_cache && !self.has_cache_dir {
+	    return Err(Error::InvalidArgument("persistent cache requires a cache directory".into()));
+	}
+
+	env.acl = acl::Acl::new(self.acl);
+	env.vhosts = self.vhosts.into_iter()
+	    .map(|spec| env.new_vhost(spec))
+	    .collect();
+
+	Server::start_env(env, listen.into_iter().collect(), self.signal_handlers,
+			  self.on_session).await
+    }
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	f.debug_struct("Config")
+	    .field("dir", &self.env.dir)
+	    .field("vhosts", &self.vhosts)
+	    .finish_non_exhaustive()
+    }
+}
+
+/// Handle of a running server
+pub struct Server {
+    local_addrs:	Vec<std::net::SocketAddr>,
+    shutdown:		watch::Sender<bool>,
+    task:		tokio::task::JoinHandle<Result<()>>,
+}
+
+impl Server {
+    pub(crate) async fn start_env(mut env: Environment, listen: Vec<Listen>, signal_handlers: bool,
+				  on_session: Option<SessionCallback>) -> Result<Self> {
+	if listen.is_empty() {
+	    return Err(Error::Internal("no socket given"));
+	}
+
+	// UdpSocket creation must happen with active Tokio runtime
+	let mut socks = listen.into_iter()
+	    .map(|l| match l {
+		Listen::Addr(addr)	=> UdpSocket::bind(&SocketAddr::new(addr.ip(), addr.port())),
+		Listen::Fd(fd)		=> UdpSocket::from_raw(fd),
+	    })
+	    .collect::<Result<Vec<_>>>()?;
+
+	if let Some(root) = env.privileges.chroot.clone() {
+	    env.rebase(&root)?;
+	}
+
+	// the cache must be instanciated before dropping privileges because the
+	// http client reads system configuration (e.g. certificates)
+	#[cfg(feature = "proxy")]
+	fetcher::Cache::instanciate(&env.cache_dir, env.cache_gc.clone(), env.persistent_cache)?;
+
+	let res = Self::setup(&mut env, &mut socks, signal_handlers);
+
+	#[cfg(feature = "proxy")]
+	if res.is_err() {
+	    fetcher::Cache::close().await;
+	}
+
+	res?;
+
+	let local_addrs = socks.iter()
+	    .map(|s| s.local_addr().map(|a| a.to_std()))
+	    .collect::<Result<Vec<_>>>()?;
+
+	let (tx, rx) = watch::channel(false);
+	let task = tokio::task::spawn(run_loops(Arc::new(env), socks, rx, on_session));
+
+	Ok(Self {
+	    local_addrs:	local_addrs,
+	    shutdown:		tx,
+	    task:		task,
+	})
+    }
+
+    fn setup(env: &mut Environment, socks: &mut [UdpSocket], signal_handlers: bool) -> Result<()> {
+	env.privileges.apply()?;
+
+	// 'cache_dir' has been rebased to the chroot
+	#[cfg(feature = "proxy")]
+	if env.persistent_cache {
+	    fetcher::Cache::open_store(&env.cache_dir);
+	}
+
+	env.create_snapshot_dirs()?;
+
+	for sock in socks {
+	    sock.set_nonblocking()?;
+	    sock.set_request_pktinfo()?;
+	}
+
+	if signal_handlers {
+	    crate::init_sighandlers(env.file_cache.clone())?;
+	}
+
+	#[cfg(feature = "sandbox")]
+	if env.sandbox {
+	    env.get_sandbox().apply()?;
+	}
+
+	Ok(())
+    }
+
+    /// Returns the addresses of the sockets in the order they were given
+    pub fn local_addrs(&self) -> &[std::net::SocketAddr] {
+	&self.local_addrs
+    }
+
+    /// Stops accepting new requests; running transfers are finished.
+    /// Dropping the handle does not stop the server.
+    pub fn shutdown(&self) {
+	let _ = self.shutdown.send(true);
+    }
+
+    /// Waits until the server terminates after `shutdown()` or an error
+    pub async fn wait(self) -> Result<()> {
+	self.task.await
+	    .map_err(|_| Error::Internal("server task failed"))?
+    }
+}
+
+/// Runs the request loops on all sockets until `shutdown` is set or a loop
+/// fails
+async fn run_loops(env: Arc<Environment>, socks: Vec<UdpSocket>,
+		   shutdown: watch::Receiver<bool>,
+		   on_session: Option<SessionCallback>) -> Result<()> {
+    let buckets = Arc::new(crate::create_buckets(&env));
+    let mut loops = JoinSet::new();
+
+    for sock in socks {
+	loops.spawn(crate::run_tftpd_loop(env.clone(), sock, shutdown.clone(),
+					  buckets.clone(), on_session.clone()));
+    }
+
+    let mut res = Ok(());
+
+    while let Some(r) = loops.join_next().await {
+	let r = r.map_err(|_| Error::Internal("request loop failed")).and_then(|r| r);
+
+	if r.is_err() && res.is_ok() {
+	    // stop the other loops too
+	    loops.abort_all();
+	    res = r;
+	}
+    }
+
+    #[cfg(feature = "proxy")]
+    fetcher::Cache::close().await;
+
+    res
+}
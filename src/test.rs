@@ -23,15 +23,6 @@ fn create_file(p: &std::path::Path, fname: &str, mut sz: usize) -> std::io::Resu
     Ok(())
 }
 
-fn abort_server(addr: std::net::SocketAddr)
-{
-    let mut l_addr = addr;
-    l_addr.set_port(0);
-
-    let sock = std::net::UdpSocket::bind(l_addr).unwrap();
-    sock.send_to(b"QQ", addr).unwrap();
-}
-
 #[derive(Debug)]
 enum FileSpec {
     Content(&'static str, usize),
@@ -166,9 +157,41 @@ async fn run_test(ip: std::net::IpAddr)
 	timeout:		Duration::from_secs(3),
 	no_rfc2347:		false,
 	wrq_devnull:		true,
+	world_readable:		false,
+	allow_dotfiles:		false,
+	case_insensitive:	false,
+	pxe_search:		Vec::new(),
+	templates:		false,
+	decompress:		false,
+	exec_dir:		None,
+	exec_limits:		fetcher::ExecLimits {
+	    timeout:		Duration::from_secs(10),
+	    max_size:		1024 * 1024,
+	},
+	backends:		Default::default(),
+	store:			Default::default(),
+	file_cache:		None,
+	snapshot:		false,
+	acl:			Default::default(),
+	rewrite:		Default::default(),
+	vhosts:			Vec::new(),
+	privileges:		Default::default(),
 
 	#[cfg(feature = "proxy")]
 	allow_uri:		true,
+	#[cfg(feature = "proxy")]
+	persistent_cache:	false,
+	#[cfg(feature = "proxy")]
+	cache_gc:		fetcher::CacheGcProperties {
+	    max_elements:	50,
+	    max_lifetime:	Duration::from_secs(3600),
+	    sleep:		Duration::from_secs(30),
+	    max_size:		None,
+	    max_object_size:	None,
+	},
+
+	#[cfg(feature = "sandbox")]
+	sandbox:		false,
     };
 
     let addr = std::net::SocketAddr::new(ip, 0);
@@ -180,8 +203,7 @@ async fn run_test(ip: std::net::IpAddr)
 	http_server.as_mut().map(|s| s.wait_for_ready());
     }
 
-    let h_server = tokio::task::spawn(timeout(Duration::from_secs(5),
-					      run(env, Either::B(listen.into()))));
+    let server = Server::start_env(env, vec![listen.into()], true, None).await.unwrap();
     let mut instance = 0;
     let mut do_abort = false;
 
@@ -231,7 +253,6 @@ async fn run_test(ip: std::net::IpAddr)
 	}
 
 	if do_abort {
-	    abort_server(addr);
 	    break;
 	}
 
@@ -240,13 +261,11 @@ async fn run_test(ip: std::net::IpAddr)
 	instance += 1
     }
 
-    h_server.await
-	.expect("tftp server timed out")
-	.expect("tftp server failed")
-	.unwrap();
+    server.shutdown();
 
-    #[cfg(feature = "proxy")]
-    crate::fetcher::Cache::close().await;
+    timeout(Duration::from_secs(5), server.wait()).await
+	.expect("tftp server timed out")
+	.expect("tftp server failed");
 }
 
 // switching tokio runtime between tests breaks the Cache singleton
@@ -269,6 +288,87 @@ pub fn init_logging() {
     }
 }
 
+#[tokio::test]
+async fn test_embedded() {
+    use std::sync::Mutex;
+    use crate::codec::{ Datagram, Mode, Request, SequenceId };
+
+    let _g = TEST_LOCK.lock().await;
+
+    init_logging();
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let stats = Arc::new(Mutex::new(Vec::new()));
+    let stats_cb = stats.clone();
+    let store = fetcher::MemoryStore::new();
+    let file_cache = fetcher::FileCache::new(1024);
+
+    std::fs::write(dir.path().join("hello"), [23u8; 100]).unwrap();
+
+    let server = Config::new(dir.path())
+	.memory_store(store.clone())
+	.file_cache(file_cache.clone())
+	.on_session(move |s| stats_cb.lock().unwrap().push((s.filename.clone(), s.filesize)))
+	.start([Listen::Addr("127.0.0.1:0".parse().unwrap())]).await
+	.unwrap();
+
+    let addr = server.local_addrs()[0];
+
+    assert_ne!(addr.port(), 0);
+
+    let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    // fetches a file which fits into a single block
+    let fetch = |name: &'static str| {
+	let sock = &sock;
+
+	async move {
+	    let mut buf = [0u8; 1024];
+	    let rrq = Datagram::Read(Request::new(name.as_bytes(), Mode::Octet));
+
+	    sock.send_to(&rrq.to_vec(), addr).await.unwrap();
+
+	    let (sz, peer) = sock.recv_from(&mut buf).await.unwrap();
+
+	    let res = match Datagram::try_from(&buf[..sz]).unwrap() {
+		Datagram::Data(id, data) if id.as_u16() == 1	=> data.to_vec(),
+		d						=> panic!("unexpected {d}"),
+	    };
+
+	    sock.send_to(&Datagram::Ack(SequenceId::new(1)).to_vec(), peer).await.unwrap();
+
+	    res
+	}
+    };
+
+    assert_eq!(fetch("hello").await, [23u8; 100]);
+
+    // the store can be modified while the server is running
+    store.insert("hello", b"from memory".as_slice(), None).unwrap();
+    assert_eq!(fetch("hello").await, b"from memory");
+
+    store.remove("hello");
+    assert_eq!(fetch("hello").await, [23u8; 100]);
+
+    // second read of the file was served from the cache
+    let cache_stats = file_cache.stats();
+
+    assert_eq!((cache_stats.hits, cache_stats.misses, cache_stats.size), (1, 1, 100));
+
+    server.shutdown();
+
+    tokio::time::timeout(Duration::from_secs(5), server.wait()).await
+	.expect("tftp server timed out")
+	.expect("tftp server failed");
+
+    let mut stats = stats.lock().unwrap().clone();
+
+    stats.sort();
+    assert_eq!(stats, [("hello".to_string(), 11),
+		       ("hello".to_string(), 100),
+		       ("hello".to_string(), 100)]);
+}
+
 #[tokio::test]
 async fn test_ipv4() {
     let _g = TEST_LOCK.lock().await;
@@ -286,3 +386,306 @@ async fn test_ipv6() {
 
     run_test(std::net::Ipv6Addr::LOCALHOST.into()).await;
 }
+
+#[cfg(feature = "proxy")]
+#[tokio::test]
+async fn test_cache_conflict() {
+    use std::net::Ipv4Addr;
+
+    let _g = TEST_LOCK.lock().await;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let other_dir = tempfile::TempDir::new().unwrap();
+    let listen = || [Listen::Addr((Ipv4Addr::LOCALHOST, 0).into())];
+
+    let server = Config::new(dir.path())
+	.cache_dir(dir.path())
+	.start(listen()).await
+	.unwrap();
+
+    // the proxy cache is shared; its configuration must match
+    let res = Config::new(dir.path())
+	.cache_dir(other_dir.path())
+	.start(listen()).await;
+
+    assert!(matches!(res, Err(Error::Proxy(r_tftpd_proxy::Error::CacheConflict))));
+
+    let res = Config::new(dir.path())
+	.cache_dir(dir.path())
+	.cache_limits(Some(1000), None)
+	.start(listen()).await;
+
+    assert!(matches!(res, Err(Error::Proxy(r_tftpd_proxy::Error::CacheConflict))));
+
+    let other = Config::new(dir.path())
+	.cache_dir(dir.path())
+	.start(listen()).await
+	.unwrap();
+
+    for s in [server, other] {
+	s.shutdown();
+	s.wait().await.unwrap();
+    }
+}
+
+/// Environment variable which passes the test directory to a child process
+/// started by `run_isolated()`
+#[cfg(any(feature = "proxy", feature = "sandbox"))]
+const ISOLATED_DIR: &str = "R_TFTPD_TEST_ISOLATED";
+
+/// Returns the test directory when running in a child process started by
+/// `run_isolated()`
+#[cfg(any(feature = "proxy", feature = "sandbox"))]
+fn isolated_dir() -> Option<std::path::PathBuf> {
+    std::env::var_os(ISOLATED_DIR).map(Into::into)
+}
+
+/// Runs the test `name` again in a child process which gets `dir`.  This is
+/// required for tests which chroot or sandbox the process because these
+/// restrictions can not be undone.
+#[cfg(any(feature = "proxy", feature = "sandbox"))]
+fn run_isolated(name: &str, dir: &Path) {
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+	.args(["--exact", &format!("test::{name}"), "--test-threads=1", "--nocapture"])
+	.env(ISOLATED_DIR, dir)
+	.stdin(std::process::Stdio::null())
+	.status()
+	.unwrap();
+
+    assert!(status.success(), "isolated test {name} failed: {status}");
+}
+
+#[cfg(feature = "proxy")]
+#[tokio::test]
+async fn test_persistent_cache_dir() {
+    use std::net::Ipv4Addr;
+
+    let dir = tempfile::TempDir::new().unwrap();
+
+    // must not clean up the default temp directory
+    let res = Config::new(dir.path())
+	.persistent_cache(true)
+	.start([Listen::Addr((Ipv4Addr::LOCALHOST, 0).into())]).await;
+
+    assert!(matches!(res, Err(Error::InvalidArgument(_))));
+}
+
+#[cfg(feature = "proxy")]
+#[tokio::test]
+async fn test_chroot_persistent_cache() {
+    use std::net::Ipv4Addr;
+
+    let Some(root) = isolated_dir() else {
+	if !nix::unistd::geteuid().is_root() {
+	    println!("test_chroot_persistent_cache skipped; requires root");
+	    return;
+	}
+
+	let dir = tempfile::TempDir::new().unwrap();
+	let cache_dir = dir.path().join("cache");
+
+	std::fs::create_dir(&cache_dir).unwrap();
+	std::fs::write(cache_dir.join("tmp-orphan"), b"").unwrap();
+
+	run_isolated("test_chroot_persistent_cache", dir.path());
+
+	// the store has been opened within the chroot
+	assert!(!cache_dir.join("tmp-orphan").exists());
+
+	return;
+    };
+
+    init_logging();
+
+    let server = Config::new(&root)
+	.cache_dir(root.join("cache"))
+	.persistent_cache(true)
+	.privileges(sandbox::PrivDrop {
+	    chroot:	Some(root.clone()),
+	    ..Default::default()
+	})
+	.start([Listen::Addr((Ipv4Addr::LOCALHOST, 0).into())]).await
+	.unwrap();
+
+    server.shutdown();
+
+    tokio::time::timeout(Duration::from_secs(5), server.wait()).await
+	.expect("tftp server timed out")
+	.expect("tftp server failed");
+}
+
+/// Fetches `name` from the tftp server at `addr`; returns the error message
+/// when the request fails
+#[cfg(feature = "sandbox")]
+async fn fetch_file(addr: std::net::SocketAddr, name: &str) -> std::result::Result<Vec<u8>, String> {
+    use crate::codec::{ Datagram, Mode, Request };
+
+    let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let rrq = Datagram::Read(Request::new(name.as_bytes(), Mode::Octet));
+    let mut buf = [0u8; 1024];
+    let mut res = Vec::new();
+    let mut next = 1;
+
+    sock.send_to(&rrq.to_vec(), addr).await.unwrap();
+
+    loop {
+	let (sz, peer) = tokio::time::timeout(Duration::from_secs(5), sock.recv_from(&mut buf)).await
+	    .expect("tftp server timed out")
+	    .unwrap();
+
+	match Datagram::try_from(&buf[..sz]).unwrap() {
+	    // ignore retransmissions
+	    Datagram::Data(id, _) if id.as_u16() != next	=> {},
+	    Datagram::Data(id, data)	=> {
+		let is_last = data.len() < 512;
+
+		res.extend_from_slice(data);
+		sock.send_to(&Datagram::Ack(id).to_vec(), peer).await.unwrap();
+
+		if is_last {
+		    return Ok(res);
+		}
+
+		next += 1;
+	    },
+	    Datagram::Error(_, msg)	=> return Err(String::from_utf8_lossy(msg).into()),
+	    d				=> panic!("unexpected {d}"),
+	}
+    }
+}
+
+/// Starts a sandboxed server with `config` and fetches `names`
+#[cfg(feature = "sandbox")]
+async fn fetch_sandboxed(config: Config, names: &[&str]) -> Vec<std::result::Result<Vec<u8>, String>> {
+    use std::net::Ipv4Addr;
+
+    init_logging();
+
+    let server = config
+	.sandbox(true)
+	.start([Listen::Addr((Ipv4Addr::LOCALHOST, 0).into())]).await
+	.unwrap();
+
+    let addr = server.local_addrs()[0];
+    let mut res = Vec::new();
+
+    for name in names {
+	res.push(fetch_file(addr, name).await);
+    }
+
+    server.shutdown();
+
+    tokio::time::timeout(Duration::from_secs(5), server.wait()).await
+	.expect("tftp server timed out")
+	.expect("tftp server failed");
+
+    res
+}
+
+#[cfg(feature = "sandbox")]
+#[tokio::test]
+async fn test_sandbox_files() {
+    let Some(root) = isolated_dir() else {
+	let dir = tempfile::TempDir::new().unwrap();
+
+	std::fs::create_dir(dir.path().join("pxelinux.cfg")).unwrap();
+	std::fs::write(dir.path().join("pxelinux.cfg/default"), b"default menu").unwrap();
+	std::fs::write(dir.path().join("hello"), [23u8; 100]).unwrap();
+	std::fs::write(dir.path().join("big"), [42u8; 2000]).unwrap();
+	std::fs::write(dir.path().join("info.tmpl"), b"ip={{ remote_ip }}").unwrap();
+
+	run_isolated("test_sandbox_files", dir.path());
+
+	return;
+    };
+
+    let config = Config::new(&root)
+	.file_cache(fetcher::FileCache::new(1024))
+	.cache_dir(root.join("cache"))
+	.snapshot(true)
+	.templates(true)
+	.pxe_search("pxelinux.cfg/auto").unwrap();
+
+    let res = fetch_sandboxed(config, &["hello", "hello", "big", "info", "pxelinux.cfg/auto",
+					"missing"]).await;
+
+    assert_eq!(res[0], Ok(vec![23u8; 100]));
+    assert_eq!(res[1], Ok(vec![23u8; 100]));
+    assert_eq!(res[2], Ok(vec![42u8; 2000]));
+    assert_eq!(res[3], Ok(b"ip=127.0.0.1".to_vec()));
+    assert_eq!(res[4], Ok(b"default menu".to_vec()));
+    assert!(res[5].is_err());
+}
+
+#[cfg(feature = "sandbox")]
+#[tokio::test]
+async fn test_sandbox_exec() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Some(root) = isolated_dir() else {
+	let dir = tempfile::TempDir::new().unwrap();
+	let exec_dir = dir.path().join("exec");
+	let tftp_dir = dir.path().join("tftp");
+	let prog = exec_dir.join("hello");
+
+	std::fs::create_dir(&exec_dir).unwrap();
+	std::fs::create_dir(&tftp_dir).unwrap();
+	std::fs::write(exec_dir.join("greeting"), b"hello").unwrap();
+	// runs a subshell and an external program
+	std::fs::write(&prog, b"#!/bin/sh\necho \"$(cat greeting) $TFTP_FILENAME\"\n").unwrap();
+	std::fs::set_permissions(&prog, std::fs::Permissions::from_mode(0o755)).unwrap();
+	std::os::unix::fs::symlink("exec://hello", tftp_dir.join("gen")).unwrap();
+
+	run_isolated("test_sandbox_exec", dir.path());
+
+	return;
+    };
+
+    let config = Config::new(root.join("tftp"))
+	.exec_dir(root.join("exec"));
+
+    let res = fetch_sandboxed(config, &["gen/test"]).await;
+
+    assert_eq!(res[0], Ok(b"hello gen/test\n".to_vec()));
+}
+
+#[cfg(all(feature = "sandbox", feature = "proxy"))]
+#[tokio::test]
+async fn test_sandbox_proxy() {
+    let Some(root) = isolated_dir() else {
+	let dir = tempfile::TempDir::new().unwrap();
+	let http_dir = dir.path().join("http");
+
+	std::fs::create_dir(&http_dir).unwrap();
+	std::fs::create_dir(dir.path().join("tftp")).unwrap();
+	std::fs::create_dir(dir.path().join("cache")).unwrap();
+	std::fs::write(http_dir.join("remote"), [5u8; 1000]).unwrap();
+
+	let mut http_server = httpd::Server::create(&http_dir).unwrap();
+
+	http_server.wait_for_ready();
+
+	std::fs::write(dir.path().join("host"), http_server.get_host().unwrap()).unwrap();
+
+	run_isolated("test_sandbox_proxy", dir.path());
+
+	// the persistent cache has stored the body
+	let index = std::fs::read_to_string(dir.path().join("cache/index")).unwrap();
+
+	assert!(index.contains("/remote\tbody-"), "{index}");
+
+	return;
+    };
+
+    let host = std::fs::read_to_string(root.join("host")).unwrap();
+    let config = Config::new(root.join("tftp"))
+	.cache_dir(root.join("cache"))
+	.persistent_cache(true)
+	.fallback(format!("http://{host}/"));
+
+    let res = fetch_sandboxed(config, &["remote", "remote"]).await;
+
+    assert_eq!(res[0], Ok(vec![5u8; 1000]));
+    assert_eq!(res[1], Ok(vec![5u8; 1000]));
+
+}
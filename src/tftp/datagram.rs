@@ -1,18 +1,16 @@
-use std::mem::MaybeUninit;
-use std::time::Duration;
-
 use crate::{ Error, Result };
-use crate::util::{ AsInit as _, SocketAddr, UdpSocket };
-use super::{ Request, RequestError as E, RequestResult, SequenceId };
+use super::{ Request, RequestError as E, RequestResult, SequenceId, Oack };
 
-#[derive(Debug)]
+/// A TFTP packet; decoded by `Datagram::try_from(&[u8])` and encoded by
+/// `encode_into()`
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Datagram<'a> {
     Read(Request<'a>),
     Write(Request<'a>),
     Data(SequenceId, &'a[u8]),
     Ack(SequenceId),
     Error(u16, &'a[u8]),
-    OAck
+    OAck(Oack),
 }
 
 impl std::fmt::Display for Datagram<'_> {
@@ -21,9 +19,9 @@ impl std::fmt::Display for Datagram<'_> {
 	    Self::Read(r)	=> write!(f, "RRQ({r:?})"),
 	    Self::Write(r)	=> write!(f, "WRQ({r:?})"),
 	    Self::Data(id, d)	=> write!(f, "DATA({}, ..{})", id, d.len()),
-	    Self::Ack(id)	=> write!(f, "ACK({id}"),
+	    Self::Ack(id)	=> write!(f, "ACK({id})"),
 	    Self::Error(err, s)	=> write!(f, "ERROR({}, \"{}\")", err, String::from_utf8_lossy(s).trim()),
-	    Self::OAck		=> write!(f, "OACK"),
+	    Self::OAck(o)	=> write!(f, "OACK({o:?})"),
 	}
     }
 }
@@ -97,50 +95,53 @@ impl <'a> TryFrom<&'a[u8]> for Datagram<'a> {
 	    5	=> Err(E::MissingZero)?,
 
 	    // OACK (received on client side only)
-	    6	=> Datagram::OAck,
+	    6	=> Datagram::OAck(Oack::from_slice(&v[2..])?),
 	    _	=> Err(E::BadOpCode(op))?,
 	})
     }
 }
 
-impl <'a> Datagram<'a> {
-    async fn recv_inner(sock: &UdpSocket,
-			buf: &'a mut [MaybeUninit<u8>], exp_addr: &SocketAddr) -> Result<Self>
-    {
-	loop {
-	    let (data, addr) = sock.recvfrom(buf).await?;
+impl Datagram<'_> {
+    pub fn op_code(&self) -> u16 {
+	match self {
+	    Self::Read(_)	=> 1,
+	    Self::Write(_)	=> 2,
+	    Self::Data(..)	=> 3,
+	    Self::Ack(_)	=> 4,
+	    Self::Error(..)	=> 5,
+	    Self::OAck(_)	=> 6,
+	}
+    }
 
-	    if &addr != exp_addr {
-		error!("unexpected address: {} vs {}", addr, exp_addr);
-		// TODO: audit this event?
-		continue;
-	    }
+    /// Appends the encoded packet to `msg`
+    pub fn encode_into(&self, msg: &mut Vec<u8>) {
+	msg.extend(self.op_code().to_be_bytes());
 
-            // TODO: this should not be needed but recent borrow checker triggers a bogus
-            //
-            // | error[E0499]: cannot borrow `*buf` as mutable more than once at a time
-            //
-            // else. Revisit after polonius.
-            let data = {
-                let len = data.len();
-                unsafe { buf[..len].assume_init() }
-            };
-
-	    break Self::try_from(data)
+	match self {
+	    Self::Read(r) |
+	    Self::Write(r)		=> r.encode_into(msg),
+	    Self::Data(id, data)	=> {
+		msg.extend(id.as_slice());
+		msg.extend(*data);
+	    },
+	    Self::Ack(id)		=> msg.extend(id.as_slice()),
+	    Self::Error(code, info)	=> {
+		msg.extend(code.to_be_bytes());
+		msg.extend(*info);
+		msg.push(0);
+	    },
+	    Self::OAck(o)		=> o.encode_into(msg),
 	}
     }
 
-    pub async fn recv(sock: &UdpSocket,
-		      buf: &'a mut [MaybeUninit<u8>], exp_addr: &SocketAddr, to: Duration) -> Result<Self>
-    {
-	use tokio::time::timeout;
+    pub fn to_vec(&self) -> Vec<u8> {
+	let mut res = Vec::new();
+
+	self.encode_into(&mut res);
 
-	timeout(to, Self::recv_inner(sock, buf, exp_addr)).await
-	    .map_err(|_| Error::Timeout)
-	    .and_then(|v| v)
+	res
     }
 
-    #[allow(dead_code)]
     pub fn is_ack(&self) -> bool {
 	matches!(self, Self::Ack(_))
     }
@@ -253,11 +254,57 @@ mod test {
 	assert_datagram!(b"\x00\x05\x01\x02error", err => RE::MissingZero);
 
 	// OACK
-	assert_datagram!(b"\x00\x06...", Datagram::OAck);
+	assert_datagram!(b"\x00\x06", Datagram::OAck(o), o == Oack::default());
+	assert_datagram!(b"\x00\x06blksize\x001428\x00tsize\x0012345678\x00", Datagram::OAck(o),
+			 o.block_size == Some(1428) && o.tsize == Some(12345678) &&
+			 o.window_size.is_none() && o.timeout.is_none());
+	assert_datagram!(b"\x00\x06blksize\x001428", err => RE::MissingZero);
 
 	// misc errors
 	assert_datagram!(b"\x00\x07", err => RE::BadOpCode(c), c == 7);
 	assert_datagram!(b"\x00",     err => RE::TooShort);
 	assert_datagram!(b"",         err => RE::TooShort);
     }
+
+    #[test]
+    fn test_encode() {
+	use super::super::Mode;
+
+	let mut rrq = Request::new(b"pxelinux.0", Mode::Octet);
+
+	rrq.block_size = Some(1468);
+	rrq.tsize = Some(0);
+	rrq.window_size = Some(16);
+	rrq.timeout = Some(std::time::Duration::from_secs(2));
+
+	let oack = Oack {
+	    block_size:		Some(1468),
+	    tsize:		Some(42),
+	    ..Default::default()
+	};
+
+	let packets = [
+	    Datagram::Read(rrq.clone()),
+	    Datagram::Read(Request::new(b"file", Mode::NetAscii)),
+	    Datagram::Write(Request::new(b"upload", Mode::Octet)),
+	    Datagram::Data(SequenceId::new(0x1234), b"data"),
+	    Datagram::Data(SequenceId::new(1), b""),
+	    Datagram::Ack(SequenceId::new(0xfffe)),
+	    Datagram::Error(1, b"file not found"),
+	    Datagram::OAck(oack),
+	    Datagram::OAck(Oack::default()),
+	];
+
+	for p in packets {
+	    let buf = p.to_vec();
+
+	    assert_eq!(Datagram::try_from(buf.as_slice()).unwrap(), p);
+	}
+
+	assert_eq!(Datagram::Read(rrq).to_vec(),
+		   b"\x00\x01pxelinux.0\x00octet\x00blksize\x001468\x00timeout\x002\x00\
+		     tsize\x000\x00windowsize\x0016\x00");
+	assert_eq!(Datagram::Ack(SequenceId::new(0x0102)).to_vec(), b"\x00\x04\x01\x02");
+	assert_eq!(Datagram::Error(1, b"missing").to_vec(), b"\x00\x05\x00\x01missing\x00");
+    }
 }
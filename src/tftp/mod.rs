@@ -9,9 +9,10 @@ mod xfer;
 mod sequence_id;
 
 pub use datagram::Datagram;
-use request::Request;
-use mode::Mode;
-use oack::Oack;
+pub use request::Request;
+pub use request::Dir as RequestDir;
+pub use mode::Mode;
+pub use oack::Oack;
 use xfer::Xfer;
 
 pub use errors::{ RequestError, RequestResult };
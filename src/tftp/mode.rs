@@ -27,4 +27,12 @@ impl Mode {
     pub fn is_octet(&self) -> bool {
 	*self == Self::Octet
     }
+
+    pub fn as_str(&self) -> &'static str {
+	match self {
+	    Self::NetAscii	=> "netascii",
+	    Self::Octet		=> "octet",
+	    Self::Mail		=> "mail",
+	}
+    }
 }
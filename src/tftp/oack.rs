@@ -1,7 +1,9 @@
 use std::time::Duration;
 
-use super::Request;
+use super::{ Request, RequestError as E, RequestResult };
+use super::request::try_ranged_from;
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Oack {
     pub block_size:	Option<u16>,
     pub timeout:	Option<Duration>,
@@ -9,7 +11,7 @@ pub struct Oack {
     pub tsize:		Option<u64>,
 }
 
-fn append_option<V: Into<u64>>(msg: &mut Vec<u8>, id: &[u8], value: V)
+pub(super) fn append_option<V: Into<u64>>(msg: &mut Vec<u8>, id: &[u8], value: V)
 {
     msg.extend(id);
     msg.push(0);
@@ -27,6 +29,38 @@ impl Oack {
 	}
     }
 
+    /// Parses the options of an OACK (without the op code)
+    pub fn from_slice(data: &[u8]) -> RequestResult<Self> {
+	let mut res = Self::default();
+
+	if data.is_empty() {
+	    return Ok(res);
+	}
+
+	if data[data.len() - 1] != b'\0' {
+	    return Err(E::MissingZero);
+	}
+
+	let mut iter = data[..data.len() - 1].split(|c| *c == b'\0');
+
+	while let Some(v) = iter.next() {
+	    use crate::util::ToLower;
+
+	    let name = v.to_lower();
+	    let arg = iter.next().ok_or(E::MissingArgument)?;
+
+	    match name.as_slice() {
+		b"blksize"	=> res.block_size = Some(try_ranged_from::<u16, 8, 65464>(arg)?),
+		b"timeout"	=> res.timeout = Some(Duration::from_secs(try_ranged_from::<u64, 0, 65536>(arg)?)),
+		b"tsize"	=> res.tsize = Some(try_ranged_from::<u64, 0, { u64::MAX }>(arg)?),
+		b"windowsize"	=> res.window_size = Some(try_ranged_from::<u16, 1, 65535>(arg)?),
+		_		=> warn!("unsupported {:?}={:?} option", name, arg),
+	    }
+	}
+
+	Ok(res)
+    }
+
     pub fn update_block_size<F>(&mut self, max_val: u16, update_fn: F)
     where
 	F: FnOnce(u16)
@@ -66,11 +100,10 @@ impl Oack {
 	}
     }
 
+    /// Appends the options to `msg`
     #[allow(clippy::option_map_unit_fn)]
-    pub fn fill_buf(self, msg: &mut Vec::<u8>)
+    pub fn encode_into(&self, msg: &mut Vec::<u8>)
     {
-	msg.extend([0, 6]);
-
 	self.block_size.map(|sz|  append_option(msg, b"blksize", sz));
 	self.window_size.map(|sz| append_option(msg, b"windowsize", sz));
 	self.tsize.map(|sz|       append_option(msg, b"tsize", sz));
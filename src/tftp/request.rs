@@ -2,7 +2,7 @@ use std::time::Duration;
 
 use super::{ RequestError as E, RequestResult, Mode };
 
-fn try_ranged_from<T, const MIN: u64, const MAX: u64>(s: &[u8]) -> RequestResult<T>
+pub(super) fn try_ranged_from<T, const MIN: u64, const MAX: u64>(s: &[u8]) -> RequestResult<T>
 where
     T: TryFrom<u64>,
 {
@@ -27,7 +27,7 @@ where
     tmp.try_into().map_err(|_| E::NumberOutOfRange)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Request<'a> {
     filename:		&'a[u8],
     pub mode:		Mode,
@@ -37,12 +37,26 @@ pub struct Request<'a> {
     pub tsize:		Option<u64>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Dir {
     Read,
     Write,
 }
 
 impl <'a> Request<'a> {
+    /// Creates a request without options
+    pub fn new(filename: &'a [u8], mode: Mode) -> Self {
+	Self {
+	    filename:		filename,
+	    mode:		mode,
+
+	    block_size:		None,
+	    timeout:		None,
+	    window_size:	None,
+	    tsize:		None,
+	}
+    }
+
     pub fn has_options(&self) -> bool {
 	self.block_size.is_some() ||
 	    self.timeout.is_some() ||
@@ -69,15 +83,7 @@ impl <'a> Request<'a> {
 	let mode = iter.next().ok_or(E::MissingMode)?;
 	let mode = Mode::try_from(mode)?;
 
-	let mut res = Self {
-	    filename:		filename,
-	    mode:		mode,
-
-	    block_size:		None,
-	    timeout:		None,
-	    window_size:	None,
-	    tsize:		None,
-	};
+	let mut res = Self::new(filename, mode);
 
 	while let Some(v) = iter.next() {
 	    use crate::util::ToLower;
@@ -100,6 +106,26 @@ impl <'a> Request<'a> {
 	Ok(res)
     }
 
+    /// Appends the request without the op code to `msg`
+    #[allow(clippy::option_map_unit_fn)]
+    pub fn encode_into(&self, msg: &mut Vec<u8>) {
+	use super::oack::append_option;
+
+	msg.extend(self.filename);
+	msg.push(0);
+	msg.extend(self.mode.as_str().as_bytes());
+	msg.push(0);
+
+	self.block_size.map(|sz|  append_option(msg, b"blksize", sz));
+	self.timeout.map(|to|     append_option(msg, b"timeout", to.as_secs()));
+	self.tsize.map(|sz|       append_option(msg, b"tsize", sz));
+	self.window_size.map(|sz| append_option(msg, b"windowsize", sz));
+    }
+
+    pub fn filename(&self) -> &'a [u8] {
+	self.filename
+    }
+
     pub fn get_filename(&self) -> std::path::PathBuf {
 	use std::os::unix::ffi::OsStrExt;
 
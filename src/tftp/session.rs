@@ -5,7 +5,8 @@ const RETRY_CNT: u32 = 5;
 const GENERIC_PKT_SZ: usize = 512;
 
 use crate::{ Error, Result };
-use crate::util::{ SocketAddr, UdpSocket };
+use crate::util::{ AsInit as _, SocketAddr, UdpSocket, UdpRecvInfo, Client };
+use crate::rewrite::Mapped;
 
 use super::{ Request, RequestError, Datagram, Oack, Xfer, SequenceId,
 	     SessionStats as Stats, SessionDirection as Direction };
@@ -26,6 +27,7 @@ impl TftpErrorCode for Error {
             Self::TooMuchClients |
             Self::RequestError(_)	=> Some([0, 4]),
             Self::FileMissing(_)	=> Some([0, 1]),
+            Self::AccessViolation(_)	=> Some([0, 2]),
             _				=> Some([0, 0]),
         }
     }
@@ -33,6 +35,8 @@ impl TftpErrorCode for Error {
 
 pub struct Session<'a> {
     remote:	SocketAddr,
+    info:	UdpRecvInfo,
+    client:	Client,
     sock:	UdpSocket,
     env:	&'a crate::Environment,
 
@@ -43,16 +47,24 @@ pub struct Session<'a> {
 
 impl <'a> Session<'a> {
     pub async fn new(env: &'a crate::Environment,
-		     remote: SocketAddr,
-		     local: std::net::IpAddr) -> Result<Session<'a>> {
-	let local_addr = SocketAddr::new(local, 0);
+		     info: &UdpRecvInfo) -> Result<Session<'a>> {
+	let remote = info.remote.clone();
+	let local_addr = SocketAddr::new(info.local, 0);
 	let sock = UdpSocket::bind(&local_addr)?;
 
 	tracing::Span::current().record("remote", remote.to_string());
 	tracing::Span::current().record("local",  sock.local_addr().unwrap().to_string());
 
+	let mut client = Client::from_info(info);
+
+	// always resolved so that it is logged and reported in the session
+	// statistics; lookups are cached and bounded by a short timeout
+	client.resolve_mac(info).await;
+
 	Ok(Self {
 	    remote:		remote,
+	    info:		info.clone(),
+	    client:		client,
 	    sock:		sock,
 	    env:		env,
 
@@ -62,6 +74,42 @@ impl <'a> Session<'a> {
 	})
     }
 
+    async fn recv_inner<'b>(sock: &UdpSocket, buf: &'b mut [MaybeUninit<u8>],
+			    exp_addr: &SocketAddr) -> Result<Datagram<'b>>
+    {
+	loop {
+	    let (data, addr) = sock.recvfrom(buf).await?;
+
+	    if &addr != exp_addr {
+		error!("unexpected address: {} vs {}", addr, exp_addr);
+		// TODO: audit this event?
+		continue;
+	    }
+
+	    // TODO: this should not be needed but recent borrow checker triggers a bogus
+	    //
+	    // | error[E0499]: cannot borrow `*buf` as mutable more than once at a time
+	    //
+	    // else. Revisit after polonius.
+	    let data = {
+		let len = data.len();
+		unsafe { buf[..len].assume_init() }
+	    };
+
+	    break Datagram::try_from(data)
+	}
+    }
+
+    /// Receives the next datagram from the remote site
+    async fn recv<'b>(&self, buf: &'b mut [MaybeUninit<u8>]) -> Result<Datagram<'b>>
+    {
+	use tokio::time::timeout;
+
+	timeout(self.timeout, Self::recv_inner(&self.sock, buf, &self.remote)).await
+	    .map_err(|_| Error::Timeout)
+	    .and_then(|v| v)
+    }
+
     async fn send(&self, msg: &[u8]) -> Result<()>
     {
 	self.sock.sendto(msg, &self.remote).await
@@ -96,34 +144,28 @@ impl <'a> Session<'a> {
 
 	warn!("error: {}", e);
 
-	msg.extend([0, 5]);
+	let info = e.to_string();
 
-        match e.tftp_error_code() {
-            Some(code)	=> {
-                msg.extend(code);
-                msg.extend(e.to_string().as_bytes());
-            }
-
-            None	=> msg.extend([0, 0]),
-        }
+	let err = match e.tftp_error_code() {
+	    Some(code)	=> Datagram::Error(u16::from_be_bytes(code), info.as_bytes()),
+	    None	=> Datagram::Error(0, b""),
+	};
 
-	msg.push(0);            // terminates error message
+	err.encode_into(&mut msg);
 
 	self.send(&msg).await
     }
 
     async fn send_ack(&self, id: SequenceId) -> Result<()>
     {
-	let msg: [u8; 4] = [ 0, 4, id.as_u8_hi(), id.as_u8_lo() ];
-
-	self.send(&msg).await
+	self.send(&Datagram::Ack(id).to_vec()).await
     }
 
     async fn send_oack(&self, oack: Oack) -> Result<()>
     {
 	let mut msg = Vec::<u8>::with_capacity(GENERIC_PKT_SZ);
 
-	oack.fill_buf(&mut msg);
+	Datagram::OAck(oack).encode_into(&mut msg);
 
 	self.send(&msg).await
     }
@@ -136,6 +178,37 @@ impl <'a> Session<'a> {
 	debug!("request={:?}", req);
     }
 
+    /// Checks the access control list for `path`; this is the requested
+    /// filename after applying the rewrite rules
+    fn check_acl(&self, path: &std::path::Path) -> Result<()>
+    {
+	use crate::acl::Action;
+
+	match self.env.acl.lookup(&self.info, path, self.env.case_insensitive) {
+	    Some((idx, rule)) if rule.action() == Action::Deny	=> {
+		warn!(rule = idx, "access denied by acl rule '{}'", rule);
+		Err(Error::AccessViolation("access denied"))
+	    },
+	    Some((idx, rule))	=> {
+		trace!(rule = idx, "access granted by acl rule '{}'", rule);
+		Ok(())
+	    },
+	    None		=> Ok(()),
+	}
+    }
+
+    /// Applies the rewrite rules to the requested filename and checks
+    /// access to the result
+    fn map_filename(&self, req: &Request<'_>, dir: Direction) -> Result<Mapped>
+    {
+	let name = self.env.rewrite.apply(req.get_filename().as_os_str(),
+					  &self.client, dir)?;
+
+	self.check_acl(name.as_path())?;
+
+	Ok(name)
+    }
+
     async fn wrq_oack(&mut self, mut oack: Oack) -> Result<()>
     {
 	oack.update_block_size(self.env.max_block_size, |v| self.block_size = v);
@@ -157,9 +230,15 @@ impl <'a> Session<'a> {
 	    filename:	req.get_filename().to_string_lossy().into_owned(),
 	    remote_ip:	self.remote.to_string(),
 	    local_ip:	self.sock.local_addr().unwrap().to_string(),
+	    mac:	self.client.mac,
 	    ..Default::default()
 	};
 
+	if let Err(e) = self.map_filename(&req, Direction::Wrq) {
+	    self.send_err(e.clone()).await?;
+	    return Err(e);
+	}
+
 	if !self.env.no_rfc2347 && req.has_options() {
 	    self.wrq_oack(Oack::from_request(&req)).await?;
 	} else {
@@ -177,7 +256,7 @@ impl <'a> Session<'a> {
 	let mut retry_cnt = RETRY_CNT;
 
 	loop {
-	    let resp = Datagram::recv(&self.sock, buf.spare_capacity_mut(), &self.remote, self.timeout).await;
+	    let resp = self.recv(buf.spare_capacity_mut()).await;
 
 	    match resp {
 		Ok(Datagram::Data(id, ..)) if id != seq	=> {
@@ -246,7 +325,7 @@ impl <'a> Session<'a> {
 
 	let mut buf = vec![MaybeUninit::uninit(); GENERIC_PKT_SZ];
 
-	let resp = Datagram::recv(&self.sock, &mut buf, &self.remote, self.timeout).await?;
+	let resp = self.recv(&mut buf).await?;
 
 	match resp {
 	    Datagram::Ack(id) if id.as_u16() == 0	=> {},
@@ -274,10 +353,27 @@ impl <'a> Session<'a> {
 	    filename:	req.get_filename().to_string_lossy().into_owned(),
 	    remote_ip:	self.remote.to_string(),
 	    local_ip:	self.sock.local_addr().unwrap().to_string(),
+	    mac:	self.client.mac,
 	    ..Default::default()
 	};
 
-	let mut fetcher = Builder::new(self.env).instanciate(&req.get_filename())?;
+	let builder = Builder::new(self.env)
+	    .client(&self.client)
+	    .request(&req);
+
+	let fetcher = match self.map_filename(&req, Direction::Rrq) {
+	    Ok(name) if name.redirect	=> builder.instanciate_uri(&name.name),
+	    Ok(name)			=> builder.instanciate(name.as_path()).await,
+	    Err(e)			=> Err(e),
+	};
+
+	let mut fetcher = match fetcher {
+	    Ok(f)	=> f,
+	    Err(e)	=> {
+		self.send_err(e.clone()).await?;
+		return Err(e);
+	    }
+	};
 
 	if let Err(e) = fetcher.open().await {
 	    self.send_err(e.clone()).await?;
@@ -304,9 +400,20 @@ impl <'a> Session<'a> {
 	let mut buf = Vec::<u8>::with_capacity(GENERIC_PKT_SZ);
 
 	loop {
-	    match tokio::time::timeout(FILL_TIMEOUT, xfer.fill_window(seq, &mut fetcher)).await?? {
-		0	=> {},
-		v	=> {
+	    let filled = match tokio::time::timeout(FILL_TIMEOUT, xfer.fill_window(seq, &mut fetcher)).await {
+		Ok(r)	=> r,
+		Err(e)	=> Err(e.into()),
+	    };
+
+	    match filled {
+		// e.g. failed programs; tell the client instead of letting it
+		// run into a timeout
+		Err(e)	=> {
+		    self.send_err(e.clone()).await?;
+		    return Err(e);
+		},
+		Ok(0)	=> {},
+		Ok(v)	=> {
 		    debug!("retransmitting {:?}+", seq);
 
 		    stats.retries += 1;
@@ -329,7 +436,7 @@ impl <'a> Session<'a> {
 
             debug_assert_eq!(buf.spare_capacity_mut().len(), GENERIC_PKT_SZ);
 
-	    let resp = Datagram::recv(&self.sock, buf.spare_capacity_mut(), &self.remote, self.timeout).await;
+	    let resp = self.recv(buf.spare_capacity_mut()).await;
 
 	    match resp {
 		Err(Error::Timeout) if retry > 0    => {
@@ -1,4 +1,4 @@
-use crate::util::ToFormatted;
+use crate::util::{ ToFormatted, MacAddr };
 
 #[derive(Copy, Clone, Default, Debug)]
 pub enum Direction {
@@ -29,6 +29,7 @@ pub struct Stats {
     pub filename:	String,
     pub remote_ip:	String,
     pub local_ip:	String,
+    pub mac:		Option<MacAddr>,
     pub is_complete:	bool,
 }
 
@@ -52,9 +53,14 @@ impl Stats {
 
 impl std::fmt::Display for Stats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-	write!(f, "\"{}\" ({} {} {}, {}x {})", self.filename,
-	       self.local_ip, self.direction.as_arrow(), self.remote_ip,
-	       self.window_size, self.block_size)?;
+	write!(f, "\"{}\" ({} {} {}", self.filename,
+	       self.local_ip, self.direction.as_arrow(), self.remote_ip)?;
+
+	if let Some(mac) = &self.mac {
+	    write!(f, " [{}]", mac)?;
+	}
+
+	write!(f, ", {}x {})", self.window_size, self.block_size)?;
 
 	match self.direction {
 	    Direction::Rrq	=> {
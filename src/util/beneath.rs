@@ -0,0 +1,115 @@
+use std::ffi::OsString;
+use std::os::fd::OwnedFd;
+use std::path::{ Path, PathBuf };
+
+use nix::errno::Errno;
+use nix::fcntl::{ self, OFlag, OpenHow, ResolveFlag };
+use nix::sys::stat::{ self, SFlag };
+
+/// A directory which confines the resolution of relative paths below
+/// itself.
+///
+/// Paths are resolved by `openat2(RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS)`;
+/// symlinks are followed as long as they do not leave the directory.
+/// Escaping paths fail with `EXDEV`.
+#[derive(Debug)]
+pub struct Beneath {
+    root:	PathBuf,
+    fd:		OwnedFd,
+}
+
+fn non_empty(p: &Path) -> &Path {
+    match p.as_os_str().is_empty() {
+	true	=> Path::new("."),
+	false	=> p,
+    }
+}
+
+impl Beneath {
+    pub fn open<P: AsRef<Path>>(root: P) -> std::io::Result<Self> {
+	let root = root.as_ref();
+	let fd = fcntl::open(root, OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+			     stat::Mode::empty())?;
+
+	Ok(Self {
+	    root:	root.into(),
+	    fd:		fd,
+	})
+    }
+
+    pub fn root(&self) -> &Path {
+	&self.root
+    }
+
+    /// Fallback for kernels without `openat2()` (< 5.6); it is racy but
+    /// rejects obvious escapes
+    fn open_legacy(&self, p: &Path, flags: OFlag) -> std::io::Result<OwnedFd> {
+	let root = std::fs::canonicalize(&self.root)?;
+	let path = std::fs::canonicalize(self.root.join(p))?;
+
+	if !path.starts_with(&root) {
+	    return Err(Errno::EXDEV.into());
+	}
+
+	Ok(fcntl::open(&path, flags | OFlag::O_CLOEXEC, stat::Mode::empty())?)
+    }
+
+    pub fn open_at(&self, p: &Path, flags: OFlag) -> std::io::Result<OwnedFd> {
+	let p = non_empty(p);
+	let how = OpenHow::new()
+	    .flags(flags | OFlag::O_CLOEXEC)
+	    .resolve(ResolveFlag::RESOLVE_BENEATH | ResolveFlag::RESOLVE_NO_MAGICLINKS);
+
+	match fcntl::openat2(&self.fd, p, how) {
+	    Err(Errno::ENOSYS)	=> {
+		debug!("openat2() not supported; using legacy method");
+		self.open_legacy(p, flags)
+	    },
+	    r			=> Ok(r?),
+	}
+    }
+
+    /// Opens `p` for reading.
+    ///
+    /// The file is opened with `O_NONBLOCK` so that FIFOs do not block;
+    /// callers must check the file type before using it.
+    pub fn open_file(&self, p: &Path) -> std::io::Result<std::fs::File> {
+	self.open_at(p, OFlag::O_RDONLY | OFlag::O_NOCTTY | OFlag::O_NONBLOCK)
+	    .map(std::fs::File::from)
+    }
+
+    /// Opens the directory containing `p` and returns it together with the
+    /// last path component
+    fn open_parent<'a>(&self, p: &'a Path) -> std::io::Result<(OwnedFd, &'a std::ffi::OsStr)> {
+	let name = p.file_name().ok_or(Errno::EINVAL)?;
+	let parent = p.parent().unwrap_or(Path::new(""));
+
+	Ok((self.open_at(parent, OFlag::O_PATH | OFlag::O_DIRECTORY)?, name))
+    }
+
+    /// Returns whether `p` is a symlink; the last component is not followed.
+    pub fn is_symlink(&self, p: &Path) -> std::io::Result<bool> {
+	let (dir, name) = self.open_parent(p)?;
+	let st = stat::fstatat(&dir, name, fcntl::AtFlags::AT_SYMLINK_NOFOLLOW)?;
+
+	Ok(SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK)
+    }
+
+    pub fn read_link(&self, p: &Path) -> std::io::Result<OsString> {
+	let (dir, name) = self.open_parent(p)?;
+
+	Ok(fcntl::readlinkat(&dir, name)?)
+    }
+
+    pub fn exists(&self, p: &Path) -> bool {
+	self.open_at(p, OFlag::O_PATH).is_ok()
+    }
+
+    /// Returns whether `p` is a regular file (after following symlinks)
+    pub fn is_file(&self, p: &Path) -> bool {
+	self.open_at(p, OFlag::O_PATH)
+	    .and_then(|fd| Ok(stat::fstat(&fd)?))
+	    .map(|st| SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFREG)
+	    .unwrap_or(false)
+    }
+}
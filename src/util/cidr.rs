@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::{ Error, Result };
+
+/// An ip network given as address and prefix length (e.g. `10.0.0.0/8`).
+///
+/// IPv4 networks match IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr:	IpAddr,
+    prefix:	u8,
+}
+
+fn mask_u128(v: u128, prefix: u8) -> u128 {
+    match prefix {
+	0	=> 0,
+	p	=> v & (!0u128 << (128 - p as u32)),
+    }
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+	let max = match addr {
+	    IpAddr::V4(_)	=> 32,
+	    IpAddr::V6(_)	=> 128,
+	};
+
+	if prefix > max {
+	    return Err(Error::InvalidArgument(format!("bad prefix length {prefix} for {addr}").into()));
+	}
+
+	Ok(Self {
+	    addr:	addr,
+	    prefix:	prefix,
+	})
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+	let ip = ip.to_canonical();
+
+	match (self.addr, ip) {
+	    (IpAddr::V4(net), IpAddr::V4(ip))	=>
+		mask_u128((u32::from(net) as u128) << 96, self.prefix) ==
+		mask_u128((u32::from(ip) as u128) << 96, self.prefix),
+
+	    (IpAddr::V6(net), IpAddr::V6(ip))	=>
+		mask_u128(net.into(), self.prefix) == mask_u128(ip.into(), self.prefix),
+
+	    _					=> false,
+	}
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+	let (addr, prefix) = match s.split_once('/') {
+	    Some((a, p))	=> (a, Some(p)),
+	    None		=> (s, None),
+	};
+
+	let addr = IpAddr::from_str(addr)
+	    .map_err(|_| Error::InvalidArgument(format!("bad ip address '{addr}'").into()))?;
+
+	let prefix = match (prefix, addr) {
+	    (Some(p), _)		=> p.parse::<u8>()
+		.map_err(|_| Error::InvalidArgument(format!("bad prefix length '{p}'").into()))?,
+	    (None, IpAddr::V4(_))	=> 32,
+	    (None, IpAddr::V6(_))	=> 128,
+	};
+
+	Self::new(addr, prefix)
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+	s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+	let net: Cidr = "10.1.0.0/16".parse().unwrap();
+
+	assert!(net.contains(&ip("10.1.0.0")));
+	assert!(net.contains(&ip("10.1.255.255")));
+	assert!(net.contains(&ip("::ffff:10.1.2.3")));
+	assert!(!net.contains(&ip("10.2.0.1")));
+	assert!(!net.contains(&ip("::1")));
+
+	let net: Cidr = "fd00::/8".parse().unwrap();
+
+	assert!(net.contains(&ip("fd12::1")));
+	assert!(!net.contains(&ip("fe80::1")));
+	assert!(!net.contains(&ip("10.1.2.3")));
+
+	let host: Cidr = "192.168.1.1".parse().unwrap();
+
+	assert!(host.contains(&ip("192.168.1.1")));
+	assert!(!host.contains(&ip("192.168.1.2")));
+
+	assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&ip("1.2.3.4")));
+	assert!("::/0".parse::<Cidr>().unwrap().contains(&ip("::1")));
+
+	assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+	assert!("10.0.0/8".parse::<Cidr>().is_err());
+	assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,35 @@
+use std::net::IpAddr;
+
+use super::{ MacAddr, UdpRecvInfo };
+
+/// Identity of the client of a session
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub remote:	IpAddr,
+    pub local:	IpAddr,
+    pub mac:	Option<MacAddr>,
+}
+
+impl Client {
+    /// Creates the client information for a request; the MAC address is
+    /// unknown until [`Self::resolve_mac()`] is called
+    pub fn from_info(info: &UdpRecvInfo) -> Self {
+	let remote = info.remote.ip().to_canonical();
+
+	Self {
+	    remote:	remote,
+	    local:	info.local.to_canonical(),
+	    mac:	None,
+	}
+    }
+
+    /// Looks up the MAC address of the client in the neighbour table of
+    /// the kernel
+    pub async fn resolve_mac(&mut self, info: &UdpRecvInfo) {
+	self.mac = super::lookup_mac(&self.remote, info.if_idx).await;
+
+	if let Some(mac) = &self.mac {
+	    tracing::Span::current().record("mac", mac.to_string());
+	}
+    }
+}
@@ -1,11 +1,21 @@
 mod udpsocket;
 mod bucket;
 mod socketaddr;
+mod beneath;
+mod cidr;
+mod netif;
+mod neigh;
+mod client;
 
 pub use socketaddr::SocketAddr;
 pub use udpsocket::{ UdpSocket,
 		     RecvInfo as UdpRecvInfo };
 pub use bucket::Bucket;
+pub use beneath::Beneath;
+pub use cidr::Cidr;
+pub use netif::if_name;
+pub use neigh::{ MacAddr, lookup_mac };
+pub use client::Client;
 
 mod uninit;
 pub use uninit::*;
@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use nix::libc;
+
+use crate::Error;
+
+/// An ethernet hardware address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub fn is_zero(&self) -> bool {
+	self.0 == [0; 6]
+    }
+
+    /// Formats the address with `sep` between the octets in lowercase hex
+    pub fn format(&self, sep: &str) -> String {
+	self.0.iter()
+	    .map(|b| format!("{b:02x}"))
+	    .collect::<Vec<_>>()
+	    .join(sep)
+    }
+}
+
+impl std::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+	f.write_str(&self.format(":"))
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+	let mut res = [0u8; 6];
+	let mut parts = s.split([':', '-']);
+
+	for b in res.iter_mut() {
+	    *b = parts.next()
+		.filter(|p| p.len() == 2)
+		.and_then(|p| u8::from_str_radix(p, 16).ok())
+		.ok_or_else(|| Error::InvalidArgument(format!("bad mac address '{s}'").into()))?;
+	}
+
+	if parts.next().is_some() {
+	    return Err(Error::InvalidArgument(format!("bad mac address '{s}'").into()));
+	}
+
+	Ok(Self(res))
+    }
+}
+
+/// Parses the content of `/proc/net/arp`
+fn parse_proc_arp(data: &str, ip: &std::net::Ipv4Addr, iface: Option<&str>) -> Option<MacAddr> {
+    // completed entry (ATF_COM)
+    const ATF_COM: u32 = 0x02;
+
+    data.lines()
+	.skip(1)
+	.filter_map(|l| {
+	    let mut fields = l.split_whitespace();
+
+	    let addr = fields.next()?.parse::<std::net::Ipv4Addr>().ok()?;
+	    let _hwtype = fields.next()?;
+	    let flags = u32::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+	    let mac = fields.next()?.parse::<MacAddr>().ok()?;
+	    let _mask = fields.next()?;
+	    let dev = fields.next()?;
+
+	    Some((addr, flags, mac, dev))
+	})
+	.find(|(addr, flags, mac, dev)| {
+	    addr == ip && flags & ATF_COM != 0 && !mac.is_zero() &&
+		iface.map(|i| i == *dev).unwrap_or(true)
+	})
+	.map(|(_, _, mac, _)| mac)
+}
+
+const NLMSG_HDRLEN: usize = 16;
+const NDMSG_LEN: usize = 12;
+
+const fn nl_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn get_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_ne_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn get_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_ne_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+/// Builds a `RTM_GETNEIGH` request for the neighbour entry of `ip` on
+/// interface `if_idx`
+fn build_neigh_request(ip: &IpAddr, if_idx: libc::c_int, seq: u32) -> Vec<u8> {
+    let (af, dst) = match ip {
+	IpAddr::V4(ip)	=> (libc::AF_INET, ip.octets().to_vec()),
+	IpAddr::V6(ip)	=> (libc::AF_INET6, ip.octets().to_vec()),
+    };
+
+    let mut res = vec![0u8; NLMSG_HDRLEN + NDMSG_LEN];
+    let flags = libc::NLM_F_REQUEST as u16;
+
+    // struct nlmsghdr
+    res[4..6].copy_from_slice(&libc::RTM_GETNEIGH.to_ne_bytes());
+    res[6..8].copy_from_slice(&flags.to_ne_bytes());
+    res[8..12].copy_from_slice(&seq.to_ne_bytes());
+
+    // struct ndmsg
+    res[NLMSG_HDRLEN] = af as u8;
+    res[NLMSG_HDRLEN + 4..NLMSG_HDRLEN + 8].copy_from_slice(&if_idx.to_ne_bytes());
+
+    // NDA_DST attribute
+    res.extend(((4 + dst.len()) as u16).to_ne_bytes());
+    res.extend(libc::NDA_DST.to_ne_bytes());
+    res.extend(dst);
+    res.resize(nl_align(res.len()), 0);
+
+    let len = res.len() as u32;
+    res[0..4].copy_from_slice(&len.to_ne_bytes());
+
+    res
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum NeighResult {
+    Found(MacAddr),
+    Done,
+    Error(i32),
+    Continue,
+}
+
+/// Parses a single `struct ndmsg` with its attributes
+fn parse_ndmsg(msg: &[u8], ip: &IpAddr, if_idx: libc::c_int) -> Option<MacAddr> {
+    if msg.len() < NDMSG_LEN {
+	return None;
+    }
+
+    let ifindex = get_u32(msg, 4) as libc::c_int;
+    let state = get_u16(msg, 8);
+
+    if if_idx > 0 && ifindex != if_idx {
+	return None;
+    }
+
+    if state & (libc::NUD_INCOMPLETE | libc::NUD_FAILED) != 0 {
+	return None;
+    }
+
+    let mut pos = NDMSG_LEN;
+    let mut dst = None;
+    let mut lladdr = None;
+
+    while pos + 4 <= msg.len() {
+	let len = get_u16(msg, pos) as usize;
+	let tp = get_u16(msg, pos + 2);
+
+	if len < 4 || pos + len > msg.len() {
+	    break;
+	}
+
+	let data = &msg[pos + 4..pos + len];
+
+	match (tp, data.len()) {
+	    (libc::NDA_DST, 4)		=> dst = Some(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+	    (libc::NDA_DST, 16)		=> dst = Some(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+	    (libc::NDA_LLADDR, 6)	=> lladdr = Some(MacAddr(data.try_into().unwrap())),
+	    _				=> {},
+	}
+
+	pos += nl_align(len);
+    }
+
+    match (dst, lladdr) {
+	(Some(dst), Some(mac)) if dst == *ip && !mac.is_zero()	=> Some(mac),
+	_							=> None,
+    }
+}
+
+/// Parses a buffer with netlink messages as received from the kernel
+fn parse_neigh_msgs(buf: &[u8], ip: &IpAddr, if_idx: libc::c_int, seq: u32) -> NeighResult {
+    let mut pos = 0;
+
+    while pos + NLMSG_HDRLEN <= buf.len() {
+	let len = get_u32(buf, pos) as usize;
+	let tp = get_u16(buf, pos + 4) as libc::c_int;
+	let msg_seq = get_u32(buf, pos + 8);
+
+	if len < NLMSG_HDRLEN || pos + len > buf.len() {
+	    return NeighResult::Done;
+	}
+
+	if msg_seq == seq {
+	    match tp {
+		libc::NLMSG_DONE			=> return NeighResult::Done,
+		libc::NLMSG_ERROR if len >= NLMSG_HDRLEN + 4	=> {
+		    // struct nlmsgerr; 'error' is a negative errno or 0
+		    // for an ack
+		    return match -(get_u32(buf, pos + NLMSG_HDRLEN) as i32) {
+			0	=> NeighResult::Done,
+			e	=> NeighResult::Error(e),
+		    };
+		},
+		libc::NLMSG_ERROR			=> return NeighResult::Done,
+		tp if tp == libc::RTM_NEWNEIGH as libc::c_int	=> {
+		    return match parse_ndmsg(&buf[pos + NLMSG_HDRLEN..pos + len], ip, if_idx) {
+			Some(mac)	=> NeighResult::Found(mac),
+			None		=> NeighResult::Done,
+		    };
+		},
+		_					=> {},
+	    }
+	}
+
+	pos += nl_align(len);
+    }
+
+    NeighResult::Continue
+}
+
+/// Queries the neighbour entry of `ip` by netlink
+fn lookup_netlink(ip: &IpAddr, if_idx: libc::c_int) -> crate::Result<Option<MacAddr>> {
+    use nix::sys::socket::{ self, AddressFamily, SockType, SockFlag, SockProtocol, MsgFlags };
+    use nix::sys::time::TimeVal;
+    use std::os::fd::AsRawFd;
+
+    static SEQ: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+
+    let seq = SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let fd = socket::socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC,
+			    SockProtocol::NetlinkRoute)?;
+
+    socket::setsockopt(&fd, socket::sockopt::ReceiveTimeout,
+		       &TimeVal::new(0, LOOKUP_TIMEOUT.as_micros() as _))?;
+    socket::send(fd.as_raw_fd(), &build_neigh_request(ip, if_idx, seq), MsgFlags::empty())?;
+
+    let mut buf = vec![0u8; 8192];
+
+    loop {
+	let len = socket::recv(fd.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+
+	if len == 0 {
+	    break Ok(None);
+	}
+
+	match parse_neigh_msgs(&buf[..len], ip, if_idx, seq) {
+	    NeighResult::Found(mac)		=> break Ok(Some(mac)),
+	    NeighResult::Done			=> break Ok(None),
+	    NeighResult::Error(libc::ENOENT)	=> break Ok(None),
+	    NeighResult::Error(e)		=> break Err(nix::Error::from_raw(e).into()),
+	    NeighResult::Continue		=> {},
+	}
+    }
+}
+
+fn lookup_mac_blocking(ip: IpAddr, if_idx: libc::c_int) -> Option<MacAddr> {
+    // the kernel requires the interface for a single neighbour lookup
+    if if_idx > 0 {
+	match lookup_netlink(&ip, if_idx) {
+	    Ok(res)	=> return res,
+	    Err(e)	=> debug!("netlink neighbour lookup failed: {e}"),
+	}
+    }
+
+    match ip {
+	IpAddr::V4(ip)	=> {
+	    let iface = super::if_name(if_idx);
+	    let data = std::fs::read_to_string("/proc/net/arp")
+		.inspect_err(|e| debug!("failed to read arp table: {e}"))
+		.ok()?;
+
+	    parse_proc_arp(&data, &ip, iface.as_deref())
+	},
+	IpAddr::V6(_)	=> None,
+    }
+}
+
+/// Upper bound for the time spent in a neighbour lookup
+const LOOKUP_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Time for which the result of a lookup is reused; a booting client
+/// sends several requests in a short time
+const CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Number of cached results; expired ones are removed when it is reached
+const CACHE_SIZE: usize = 1024;
+
+/// Recent results of neighbour lookups, including failed ones
+#[derive(Default)]
+struct NeighCache {
+    entries:	HashMap<(IpAddr, libc::c_int), (Instant, Option<MacAddr>)>,
+}
+
+impl NeighCache {
+    fn get(&self, key: &(IpAddr, libc::c_int), now: Instant) -> Option<Option<MacAddr>> {
+	self.entries.get(key)
+	    .filter(|(tm, _)| now.saturating_duration_since(*tm) < CACHE_TTL)
+	    .map(|(_, mac)| *mac)
+    }
+
+    fn insert(&mut self, key: (IpAddr, libc::c_int), mac: Option<MacAddr>, now: Instant) {
+	if self.entries.len() >= CACHE_SIZE {
+	    self.entries.retain(|_, (tm, _)| now.saturating_duration_since(*tm) < CACHE_TTL);
+	}
+
+	if self.entries.len() >= CACHE_SIZE {
+	    self.entries.clear();
+	}
+
+	self.entries.insert(key, (now, mac));
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref NEIGH_CACHE: Mutex<NeighCache> = Mutex::new(NeighCache::default());
+}
+
+/// Looks up the hardware address of `ip` in the neighbour table of the
+/// kernel.  `if_idx` is the index of the interface on which the client
+/// was seen.
+///
+/// The entry is queried by netlink; for IPv4, `/proc/net/arp` is used
+/// as fallback.  The lookup runs on the blocking thread pool and gives
+/// up after 250 ms.  Results are reused for some seconds.
+pub async fn lookup_mac(ip: &IpAddr, if_idx: libc::c_int) -> Option<MacAddr> {
+    let ip = ip.to_canonical();
+    let key = (ip, if_idx);
+
+    if let Some(res) = NEIGH_CACHE.lock().unwrap().get(&key, Instant::now()) {
+	return res;
+    }
+
+    let task = tokio::task::spawn_blocking(move || lookup_mac_blocking(ip, if_idx));
+
+    let res = match tokio::time::timeout(LOOKUP_TIMEOUT, task).await {
+	Ok(Ok(res))	=> res,
+	Ok(Err(e))	=> {
+	    warn!("neighbour lookup failed: {e}");
+	    None
+	},
+	Err(_)		=> {
+	    debug!("neighbour lookup for {ip} timed out");
+	    None
+	},
+    };
+
+    NEIGH_CACHE.lock().unwrap().insert(key, res, Instant::now());
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mac() {
+	let mac: MacAddr = "88:99:AA:bb:cc:0d".parse().unwrap();
+
+	assert_eq!(mac, MacAddr([0x88, 0x99, 0xaa, 0xbb, 0xcc, 0x0d]));
+	assert_eq!(mac.to_string(), "88:99:aa:bb:cc:0d");
+	assert_eq!(mac.format("-"), "88-99-aa-bb-cc-0d");
+	assert_eq!("88-99-aa-bb-cc-0d".parse::<MacAddr>().unwrap(), mac);
+
+	assert!("88:99:aa:bb:cc".parse::<MacAddr>().is_err());
+	assert!("88:99:aa:bb:cc:0d:00".parse::<MacAddr>().is_err());
+	assert!("88:99:aa:bb:cc:d".parse::<MacAddr>().is_err());
+	assert!("88:99:aa:bb:cc:xx".parse::<MacAddr>().is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+	let mut cache = NeighCache::default();
+	let now = Instant::now();
+	let ip: IpAddr = "192.0.2.1".parse().unwrap();
+	let mac = MacAddr([2, 0, 0, 0, 0, 1]);
+
+	assert_eq!(cache.get(&(ip, 1), now), None);
+
+	cache.insert((ip, 1), Some(mac), now);
+	cache.insert((ip, 2), None, now);
+
+	assert_eq!(cache.get(&(ip, 1), now), Some(Some(mac)));
+	assert_eq!(cache.get(&(ip, 2), now), Some(None));
+	assert_eq!(cache.get(&(ip, 1), now + CACHE_TTL), None);
+
+	// expired entries are removed when the cache is full
+	for i in 0..CACHE_SIZE {
+	    cache.insert((ip, i as libc::c_int + 10), None, now + CACHE_TTL);
+	}
+
+	assert_eq!(cache.entries.len(), CACHE_SIZE);
+	assert_eq!(cache.get(&(ip, 1), now), None);
+    }
+
+    fn build_neigh(seq: u32, ifindex: u32, state: u16, dst: &[u8], lladdr: &[u8]) -> Vec<u8> {
+	let mut res = vec![0u8; NLMSG_HDRLEN + NDMSG_LEN];
+
+	res[4..6].copy_from_slice(&libc::RTM_NEWNEIGH.to_ne_bytes());
+	res[8..12].copy_from_slice(&seq.to_ne_bytes());
+	res[NLMSG_HDRLEN + 4..NLMSG_HDRLEN + 8].copy_from_slice(&ifindex.to_ne_bytes());
+	res[NLMSG_HDRLEN + 8..NLMSG_HDRLEN + 10].copy_from_slice(&state.to_ne_bytes());
+
+	for (tp, data) in [(libc::NDA_DST, dst), (libc::NDA_LLADDR, lladdr)] {
+	    res.extend(((4 + data.len()) as u16).to_ne_bytes());
+	    res.extend(tp.to_ne_bytes());
+	    res.extend(data);
+	    res.resize(nl_align(res.len()), 0);
+	}
+
+	let len = res.len() as u32;
+	res[0..4].copy_from_slice(&len.to_ne_bytes());
+
+	res
+    }
+
+    #[test]
+    fn test_netlink() {
+	let v4: IpAddr = "192.0.2.1".parse().unwrap();
+	let v6: IpAddr = "fe80::1".parse().unwrap();
+	let mac = MacAddr([2, 0xfc, 0, 0, 0, 5]);
+
+	let req = build_neigh_request(&v6, 3, 23);
+
+	assert_eq!(get_u32(&req, 0) as usize, req.len());
+	assert_eq!(get_u16(&req, 4), libc::RTM_GETNEIGH);
+	assert_eq!(get_u16(&req, 6), libc::NLM_F_REQUEST as u16);
+	assert_eq!(req[NLMSG_HDRLEN], libc::AF_INET6 as u8);
+	assert_eq!(get_u32(&req, NLMSG_HDRLEN + 4), 3);
+	assert_eq!(get_u16(&req, NLMSG_HDRLEN + NDMSG_LEN), 20);
+	assert_eq!(get_u16(&req, NLMSG_HDRLEN + NDMSG_LEN + 2), libc::NDA_DST);
+	assert_eq!(&req[NLMSG_HDRLEN + NDMSG_LEN + 4..], &[0xfe, 0x80, 0, 0, 0, 0, 0, 0,
+							     0, 0, 0, 0, 0, 0, 0, 1]);
+
+	let req = build_neigh_request(&v4, 2, 24);
+
+	assert_eq!(req.len(), NLMSG_HDRLEN + NDMSG_LEN + 8);
+	assert_eq!(&req[NLMSG_HDRLEN + NDMSG_LEN + 4..], &[192, 0, 2, 1]);
+
+	let msg_v4 = build_neigh(7, 2, libc::NUD_REACHABLE, &[192, 0, 2, 1], &mac.0);
+	let msg_v6 = build_neigh(7, 3, libc::NUD_STALE,
+				 &"fe80::1".parse::<std::net::Ipv6Addr>().unwrap().octets(), &mac.0);
+	let msg_fail = build_neigh(7, 2, libc::NUD_FAILED, &[192, 0, 2, 1], &mac.0);
+
+	assert_eq!(parse_neigh_msgs(&msg_v4, &v4, 2, 7), NeighResult::Found(mac));
+	assert_eq!(parse_neigh_msgs(&msg_v4, &v4, 0, 7), NeighResult::Found(mac));
+	assert_eq!(parse_neigh_msgs(&msg_v4, &v4, 3, 7), NeighResult::Done);
+	assert_eq!(parse_neigh_msgs(&msg_v4, &v4, 2, 8), NeighResult::Continue);
+	assert_eq!(parse_neigh_msgs(&msg_v4, &v6, 2, 7), NeighResult::Done);
+	assert_eq!(parse_neigh_msgs(&msg_fail, &v4, 2, 7), NeighResult::Done);
+	assert_eq!(parse_neigh_msgs(&msg_v6, &v6, 3, 7), NeighResult::Found(mac));
+
+	let nlmsg = |tp: libc::c_int, payload: &[u8]| {
+	    let mut res = vec![0u8; NLMSG_HDRLEN];
+	    res[0..4].copy_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+	    res[4..6].copy_from_slice(&(tp as u16).to_ne_bytes());
+	    res[8..12].copy_from_slice(&7u32.to_ne_bytes());
+	    res.extend(payload);
+	    res
+	};
+
+	assert_eq!(parse_neigh_msgs(&nlmsg(libc::NLMSG_DONE, &[0; 4]), &v4, 2, 7),
+		   NeighResult::Done);
+	assert_eq!(parse_neigh_msgs(&nlmsg(libc::NLMSG_ERROR, &(-libc::ENOENT).to_ne_bytes()), &v4, 2, 7),
+		   NeighResult::Error(libc::ENOENT));
+	assert_eq!(parse_neigh_msgs(&nlmsg(libc::NLMSG_ERROR, &0i32.to_ne_bytes()), &v4, 2, 7),
+		   NeighResult::Done);
+    }
+
+    #[test]
+    fn test_proc_arp() {
+	const ARP: &str = r#"IP address       HW type     Flags       HW address            Mask     Device
+192.0.2.1        0x1         0x2         02:fc:00:00:00:05     *        eth0
+192.0.2.2        0x1         0x0         00:00:00:00:00:00     *        eth0
+192.0.2.3        0x1         0x2         02:fc:00:00:00:07     *        eth1
+192.0.2.3        0x1         0x2         02:fc:00:00:00:08     *        eth0
+"#;
+
+	let ip = |s: &str| s.parse::<std::net::Ipv4Addr>().unwrap();
+	let mac = |s: &str| Some(s.parse::<MacAddr>().unwrap());
+
+	assert_eq!(parse_proc_arp(ARP, &ip("192.0.2.1"), Some("eth0")), mac("02:fc:00:00:00:05"));
+	assert_eq!(parse_proc_arp(ARP, &ip("192.0.2.1"), None),         mac("02:fc:00:00:00:05"));
+	assert_eq!(parse_proc_arp(ARP, &ip("192.0.2.1"), Some("eth1")), None);
+	assert_eq!(parse_proc_arp(ARP, &ip("192.0.2.2"), None),         None);
+	assert_eq!(parse_proc_arp(ARP, &ip("192.0.2.3"), Some("eth0")), mac("02:fc:00:00:00:08"));
+	assert_eq!(parse_proc_arp(ARP, &ip("192.0.2.4"), None),         None);
+    }
+}
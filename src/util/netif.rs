@@ -0,0 +1,37 @@
+use nix::libc;
+
+/// Returns the name of the network interface with index `idx`
+pub fn if_name(idx: libc::c_int) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+
+    if idx <= 0 {
+	return None;
+    }
+
+    // SAFETY: 'buf' has the size required by if_indextoname()
+    let res = unsafe { libc::if_indextoname(idx as libc::c_uint, buf.as_mut_ptr()) };
+
+    if res.is_null() {
+	return None;
+    }
+
+    // SAFETY: if_indextoname() wrote a nul terminated string into 'buf'
+    let name = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_if_name() {
+	assert_eq!(if_name(0), None);
+	assert_eq!(if_name(-1), None);
+
+	let idx = nix::net::if_::if_nametoindex("lo").unwrap();
+
+	assert_eq!(if_name(idx as libc::c_int).as_deref(), Some("lo"));
+    }
+}
@@ -67,4 +67,23 @@ impl SocketAddr {
     {
 	&self.0
     }
+
+    pub fn ip(&self) -> IpAddr
+    {
+	match (self.0.as_sockaddr_in(), self.0.as_sockaddr_in6()) {
+	    (Some(a), _)	=> a.ip().into(),
+	    (_, Some(a))	=> a.ip().into(),
+	    // other families are rejected by TryFrom<SockaddrStorage>
+	    _			=> unreachable!(),
+	}
+    }
+
+    pub fn to_std(&self) -> std::net::SocketAddr
+    {
+	match (self.0.as_sockaddr_in(), self.0.as_sockaddr_in6()) {
+	    (Some(a), _)	=> std::net::SocketAddrV4::from(*a).into(),
+	    (_, Some(a))	=> std::net::SocketAddrV6::from(*a).into(),
+	    _			=> unreachable!(),
+	}
+    }
 }
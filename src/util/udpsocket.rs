@@ -56,10 +56,10 @@ impl RecvInfoOpt {
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct RecvInfo {
     pub size:	usize,
-    #[allow(dead_code)]
-    if_idx:	libc::c_int,
+    pub if_idx:	libc::c_int,
     pub local:	IpAddr,
     pub remote:	SocketAddr,
 }
@@ -245,6 +245,7 @@ impl UdpSocket {
 	let af = addr.get_af();
 
 	match socket::bind(fd.as_raw_fd(), addr.as_nix()) {
+	    #[allow(deprecated)]
 	    Ok(_)	=> Ok(Self {
 		fd:		AsyncFd::new(fd)?,
 		af:		af,
@@ -258,6 +259,7 @@ impl UdpSocket {
     pub fn from_raw(fd: OwnedFd) -> Result<Self> {
 	let addr = SocketAddr::from_fd(fd.as_fd())?;
 
+	#[allow(deprecated)]
 	Ok(Self {
 	    fd:		AsyncFd::new(fd)?,
 	    af:		addr.get_af(),
@@ -0,0 +1,152 @@
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{ Error, Result, Environment };
+
+/// Settings for a virtual host as given on the command line.
+///
+/// Format is `local=IP|iface=NAME [dir=DIR] [fallback=URI] [block-size=N]
+/// [window-size=N] [timeout=SECS] [max-connections=N]`; unset values are
+/// inherited from the global configuration.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Spec {
+    pub local:			Option<IpAddr>,
+    pub iface:			Option<String>,
+    pub dir:			Option<PathBuf>,
+    pub fallback_uri:		Option<OsString>,
+    pub max_block_size:		Option<u16>,
+    pub max_window_size:	Option<u16>,
+    pub timeout:		Option<Duration>,
+    pub max_connections:	Option<u32>,
+}
+
+fn parse_value<T: FromStr>(key: &str, v: &str) -> Result<T>
+{
+    v.parse().map_err(|_| Error::InvalidArgument(format!("bad value '{v}' for '{key}'").into()))
+}
+
+/// Parses a block size; valid values are given by RFC 2348
+fn parse_block_size(key: &str, v: &str) -> Result<u16>
+{
+    Some(parse_value(key, v)?)
+	.filter(|sz| (8..=65464).contains(sz))
+	.ok_or_else(|| Error::InvalidArgument(format!("block size '{v}' for '{key}' not in 8..=65464").into()))
+}
+
+fn parse_duration(key: &str, v: &str) -> Result<Duration>
+{
+    Duration::try_from_secs_f32(parse_value(key, v)?)
+	.map_err(|_| Error::InvalidArgument(format!("bad duration '{v}' for '{key}'").into()))
+}
+
+impl FromStr for Spec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+	let mut res = Self::default();
+
+	for t in s.split_whitespace() {
+	    match t.split_once('=') {
+		Some(("local", v))		=> res.local = Some(parse_value("local", v)?),
+		Some(("iface", v))		=> res.iface = Some(v.to_string()),
+		Some(("dir", v))		=> res.dir = Some(v.into()),
+		Some(("fallback", v))		=> res.fallback_uri = Some(v.into()),
+		Some(("block-size", v))		=> res.max_block_size = Some(parse_block_size("block-size", v)?),
+		Some(("window-size", v))	=> res.max_window_size = Some(parse_value("window-size", v)?),
+		Some(("timeout", v))		=> res.timeout = Some(parse_duration("timeout", v)?),
+		Some(("max-connections", v))	=> res.max_connections = Some(parse_value("max-connections", v)?),
+		_				=> return Err(Error::InvalidArgument(
+		    format!("bad token '{t}' in vhost '{s}'").into())),
+	    }
+	}
+
+	if res.local.is_none() && res.iface.is_none() {
+	    return Err(Error::InvalidArgument(
+		format!("vhost '{s}' requires 'local' or 'iface'").into()));
+	}
+
+	Ok(res)
+    }
+}
+
+/// A virtual host; requests received on the given local address and/or
+/// interface are served from its own environment.
+pub struct VHost {
+    pub local:		Option<IpAddr>,
+    pub iface:		Option<String>,
+    pub env:		Environment,
+}
+
+impl VHost {
+    /// Checks whether request matches; `iface` returns the (lazily resolved)
+    /// name of the receiving interface
+    pub fn matches<F>(&self, local: &IpAddr, mut iface: F) -> bool
+    where
+	F: FnMut() -> Option<String>,
+    {
+	if let Some(l) = &self.local {
+	    if l.to_canonical() != local.to_canonical() {
+		return false;
+	    }
+	}
+
+	if let Some(name) = &self.iface {
+	    if iface().as_ref() != Some(name) {
+		return false;
+	    }
+	}
+
+	true
+    }
+
+    pub fn get_name(&self) -> String {
+	match (&self.local, &self.iface) {
+	    (Some(l), Some(i))	=> format!("{l}%{i}"),
+	    (Some(l), None)	=> l.to_string(),
+	    (None, Some(i))	=> format!("%{i}"),
+	    (None, None)	=> "*".to_string(),
+	}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+	assert_eq!("local=10.0.0.1 dir=/srv/lab fallback=http://example.org/ block-size=1428 window-size=8 timeout=1.5 max-connections=4"
+		   .parse::<Spec>().unwrap(),
+		   Spec {
+		       local:		Some("10.0.0.1".parse().unwrap()),
+		       iface:		None,
+		       dir:		Some("/srv/lab".into()),
+		       fallback_uri:	Some("http://example.org/".into()),
+		       max_block_size:	Some(1428),
+		       max_window_size:	Some(8),
+		       timeout:		Some(Duration::from_secs_f32(1.5)),
+		       max_connections:	Some(4),
+		   });
+
+	assert_eq!("iface=eth1".parse::<Spec>().unwrap(),
+		   Spec {
+		       iface:		Some("eth1".into()),
+		       ..Default::default()
+		   });
+
+	assert!("dir=/srv/lab".parse::<Spec>().is_err());
+	assert!("local=10.0.0".parse::<Spec>().is_err());
+	assert!("iface=eth1 block-size=100000".parse::<Spec>().is_err());
+	assert!("iface=eth1 block-size=7".parse::<Spec>().is_err());
+	assert!("iface=eth1 block-size=65465".parse::<Spec>().is_err());
+	assert!("iface=eth1 block-size=8".parse::<Spec>().is_ok());
+	assert!("iface=eth1 block-size=65464".parse::<Spec>().is_ok());
+	assert!("iface=eth1 foo".parse::<Spec>().is_err());
+	assert!("iface=eth1 timeout=-1".parse::<Spec>().is_err());
+	assert!("iface=eth1 timeout=NaN".parse::<Spec>().is_err());
+	assert!("iface=eth1 timeout=1e30".parse::<Spec>().is_err());
+    }
+}